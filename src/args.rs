@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+/// A command line flag, each part of the program has a list of the ones it understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flag {
+    pub name: &'static str,
+    pub takes_value: bool,
+}
+
+impl Flag {
+    /// A flag followed by a value, like `--zoom 12`.
+    pub const fn value(name: &'static str) -> Self {
+        Self { name, takes_value: true }
+    }
+
+    /// A flag on its own, like `--no-attribution`.
+    pub const fn switch(name: &'static str) -> Self {
+        Self { name, takes_value: false }
+    }
+}
+
+/// The command line, split into flags and their values once so everything else can just look them up.
+/// Flags can be given more than once, the last one wins unless all of them are asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Args {
+    flags: Vec<(&'static str, Option<String>)>,
+}
+
+impl Args {
+    /// Anything which isn't one of the `known` flags is an error, so typos don't get silently ignored.
+    pub fn parse(args: &[String], known: &[&[Flag]]) -> Result<Self, String> {
+        let mut flags = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = known.iter().flat_map(|flags| flags.iter()).find(|flag| flag.name == arg)
+                .ok_or_else(|| format!("Unknown argument {}", arg))?;
+            let value = match flag.takes_value {
+                true => Some(args.next().ok_or(format!("{} needs a value", arg))?.clone()),
+                false => None,
+            };
+            flags.push((flag.name, value));
+        }
+        Ok(Self { flags })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.flags.iter().rev().find(|(flag, _)| *flag == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.flags.iter().filter(move |(flag, _)| *flag == name).filter_map(|(_, value)| value.as_deref())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.flags.iter().any(|(flag, _)| *flag == name)
+    }
+
+    /// The flag's value parsed into a `T`, `error` is given back if it doesn't parse.
+    pub fn parse_value<T: FromStr>(&self, name: &str, error: &str) -> Result<Option<T>, String> {
+        self.get(name).map(|value| value.parse().map_err(|_| error.to_string())).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: &[Flag] = &[Flag::value("--zoom"), Flag::value("--marker"), Flag::switch("--no-attribution")];
+
+    fn parse(args: &str) -> Result<Args, String> {
+        Args::parse(&args.split_whitespace().map(|arg| arg.to_string()).collect::<Vec<_>>(), &[FLAGS])
+    }
+
+    #[test]
+    fn flags_are_looked_up_by_name() {
+        let args = parse("--zoom 12 --marker 1,2 --no-attribution --marker 3,4 --zoom 13").unwrap();
+        assert_eq!(args.get("--zoom"), Some("13"));
+        assert_eq!(args.get_all("--marker").collect::<Vec<_>>(), vec!["1,2", "3,4"]);
+        assert!(args.has("--no-attribution"));
+        assert_eq!(args.parse_value::<u32>("--zoom", "--zoom needs a number"), Ok(Some(13)));
+        assert_eq!(args.parse_value::<u32>("--size", "--size needs a number"), Ok(None));
+    }
+
+    #[test]
+    fn unknown_flags_and_missing_values_are_errors() {
+        assert_eq!(parse("--zom 12"), Err("Unknown argument --zom".to_string()));
+        assert_eq!(parse("12"), Err("Unknown argument 12".to_string()));
+        assert_eq!(parse("--marker 1,2 --zoom"), Err("--zoom needs a value".to_string()));
+        assert_eq!(parse("--zoom twelve").unwrap().parse_value::<u32>("--zoom", "--zoom needs a number"), Err("--zoom needs a number".to_string()));
+    }
+}
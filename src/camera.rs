@@ -1,11 +1,15 @@
 use bevy::{prelude::*, core_pipeline::bloom::Bloom};
 use bevy_pancam::{DirectionKeys, PanCam};
 
-use crate::{tile::{world_mercator_to_lat_lon, Coord}, STARTING_DISPLACEMENT, STARTING_LONG_LAT, TILE_QUALITY};
+use crate::{tile::{world_mercator_to_lat_lon, LatLon}, tile_source::ActiveTileSource, STARTING_DISPLACEMENT, STARTING_LONG_LAT, TILE_QUALITY, WORLD_ZOOM};
 
+// How many levels past the source's max zoom you can keep zooming in, the most detailed tiles just get bigger
+const OVERZOOM_LEVELS: i32 = 3;
+// How many levels out from WORLD_ZOOM you can zoom, the tiles stop getting less detailed at zoom 3
+const UNDERZOOM_LEVELS: i32 = 12;
 
-pub fn setup_camera(mut commands: Commands) {
-    let starting = STARTING_DISPLACEMENT.to_game_coords(STARTING_LONG_LAT, 14, TILE_QUALITY.into());
+pub fn setup_camera(mut commands: Commands, tile_source: Res<ActiveTileSource>) {
+    let starting = STARTING_DISPLACEMENT.to_game_coords(STARTING_LONG_LAT, WORLD_ZOOM, TILE_QUALITY.into());
     commands.spawn((
         Camera2d,
         Camera {
@@ -27,8 +31,9 @@ pub fn setup_camera(mut commands: Commands) {
             speed: 400., // the speed for the keyboard movement
             enabled: true, // when false, controls are disabled. See toggle example.
             zoom_to_cursor: false, // whether to zoom towards the mouse or the center of the screen
-            min_scale: 0.25, // prevent the camera from zooming too far in
-            max_scale: f32::INFINITY, // prevent the camera from zooming too far out
+            // The scale halves with every level zoomed in past WORLD_ZOOM
+            min_scale: 2f32.powi(WORLD_ZOOM as i32 - tile_source.max_zoom() as i32 - OVERZOOM_LEVELS), // prevent the camera from zooming too far in
+            max_scale: 2f32.powi(UNDERZOOM_LEVELS), // prevent the camera from zooming too far out
             min_x: f32::NEG_INFINITY, // minimum x position of the camera window
             max_x: f32::INFINITY, // maximum x position of the camera window
             min_y: f32::NEG_INFINITY, // minimum y position of the camera window
@@ -44,8 +49,8 @@ pub fn camera_space_to_lat_long_rect(
     projection: OrthographicProjection,
     zoom: u32,
     quality: f32,
-    reference: Coord,
-) -> Option<geo::Rect<f32>> {
+    reference: LatLon,
+) -> Option<geo::Rect<f64>> {
     // Get the window size
     let window_width = window.width(); 
     let window_height = window.height();
@@ -60,9 +65,9 @@ pub fn camera_space_to_lat_long_rect(
     let bottom = camera_translation.y + ((window_height * projection.scale) / 2.0);
     let top = camera_translation.y;
     
-    Some(geo::Rect::<f32>::new(
-        world_mercator_to_lat_lon(left.into(), bottom.into(), reference, zoom, quality).to_tuple(),
-        world_mercator_to_lat_lon(right.into(), top.into(), reference, zoom, quality).to_tuple(),
+    Some(geo::Rect::<f64>::new(
+        world_mercator_to_lat_lon(Vec2::new(left, bottom).into(), reference, zoom, quality.into()).to_xy().to_array(),
+        world_mercator_to_lat_lon(Vec2::new(right, top).into(), reference, zoom, quality.into()).to_xy().to_array(),
     ))
 }
 
@@ -70,8 +75,8 @@ pub fn camera_middle_to_lat_long(
     transform: &GlobalTransform,
     zoom: u32,
     quality: f32,
-    reference: Coord,
-) -> Coord {
+    reference: LatLon,
+) -> LatLon {
     let camera_translation = transform.translation();
-    world_mercator_to_lat_lon(camera_translation.x.into(), camera_translation.y.into(), reference, zoom, quality)
+    world_mercator_to_lat_lon(camera_translation.xy().into(), reference, zoom, quality.into())
 }
@@ -0,0 +1,274 @@
+use std::{collections::hash_map::RandomState, fmt, hash::{BuildHasher, Hasher}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
+
+use bevy::log::warn;
+
+use crate::{args::{Args, Flag}, tile_cache::{parse_http_date, unix_now}};
+
+/// How we talk to tile servers.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub timeout: Duration,
+    /// How many times a request is tried again after the first go fails
+    pub max_retries: u32,
+    /// The wait before the first retry, it doubles after each retry
+    pub backoff: Duration,
+    /// The longest we'll wait between tries, even if the server asks for longer
+    pub max_backoff: Duration,
+    /// The OSM tile usage policy requires an identifying user agent
+    pub user_agent: String,
+    pub referer: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(15),
+            max_retries: 4,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            user_agent: format!("bevy-ofm-viewer/{} (+https://github.com/SO9010/bevy-ofm-viewer)", env!("CARGO_PKG_VERSION")),
+            referer: None,
+        }
+    }
+}
+
+/// `--timeout <seconds>`, `--max-retries <count>`, `--user-agent <text>` and `--referer <url>`.
+pub const HTTP_FLAGS: &[Flag] = &[Flag::value("--timeout"), Flag::value("--max-retries"), Flag::value("--user-agent"), Flag::value("--referer")];
+
+pub fn http_config_from_args(args: &Args) -> Result<HttpConfig, String> {
+    let mut config = HttpConfig::default();
+    if let Some(timeout) = args.get("--timeout") {
+        config.timeout = Duration::from_secs_f64(timeout.parse().ok().filter(|timeout: &f64| *timeout > 0.0).ok_or("--timeout needs a number of seconds")?);
+    }
+    if let Some(max_retries) = args.parse_value("--max-retries", "--max-retries needs a number")? {
+        config.max_retries = max_retries;
+    }
+    if let Some(user_agent) = args.get("--user-agent") {
+        config.user_agent = user_agent.to_string();
+    }
+    if let Some(referer) = args.get("--referer") {
+        config.referer = Some(referer.to_string());
+    }
+    Ok(config)
+}
+
+/// Why a tile couldn't be fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchError {
+    /// The server answered with an error, after retrying if it was worth retrying
+    Status(u16),
+    /// We couldn't get an answer at all, the connection failed or timed out
+    Transport(String),
+    /// Reading the tile off the disk failed
+    Io(String),
+    /// Nobody wanted the tile any more, so we stopped
+    Cancelled,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Status(status) => write!(f, "server answered {}", status),
+            FetchError::Transport(e) => write!(f, "request failed: {}", e),
+            FetchError::Io(e) => write!(f, "read failed: {}", e),
+            FetchError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Tells a fetch which is still going that it can give up, it's checked between tries and while waiting to try again.
+#[derive(Debug, Clone, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How one go at a request turned out.
+enum Attempt<T> {
+    Done(Result<T, FetchError>),
+    /// Worth trying again, after the wait the server asked for if it did
+    Retry(FetchError, Option<Duration>),
+}
+
+#[derive(Debug)]
+pub struct HttpClient {
+    agent: ureq::Agent,
+    config: HttpConfig,
+}
+
+impl HttpClient {
+    pub fn new(config: HttpConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(config.timeout)
+            .timeout_read(config.timeout)
+            .user_agent(&config.user_agent)
+            .build();
+        Self { agent, config }
+    }
+
+    /// GETs the url, trying again with backoff when the server is busy or we can't reach it.
+    /// Anything which isn't an error status comes back, so a `304 Not Modified` is fine. Stops trying once `cancel` is set.
+    pub fn get(&self, url: &str, headers: &[(&str, &str)], cancel: &CancelFlag) -> Result<ureq::Response, FetchError> {
+        retry(&self.config, cancel, |wait| sleep_unless_cancelled(wait, cancel), || {
+            let mut request = self.agent.get(url);
+            if let Some(referer) = &self.config.referer {
+                request = request.set("Referer", referer);
+            }
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+
+            match request.call() {
+                Ok(response) => Attempt::Done(Ok(response)),
+                Err(ureq::Error::Status(status, response)) if is_retryable(status) => {
+                    Attempt::Retry(FetchError::Status(status), response.header("Retry-After").and_then(parse_retry_after))
+                }
+                Err(ureq::Error::Status(status, _)) => Attempt::Done(Err(FetchError::Status(status))),
+                Err(ureq::Error::Transport(e)) => Attempt::Retry(FetchError::Transport(e.to_string()), None),
+            }
+        })
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(HttpConfig::default())
+    }
+}
+
+// Rate limiting and the server having a bad moment, anything else won't get better by asking again
+fn is_retryable(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+fn retry<T>(config: &HttpConfig, cancel: &CancelFlag, mut sleep: impl FnMut(Duration), mut attempt: impl FnMut() -> Attempt<T>) -> Result<T, FetchError> {
+    let mut tries = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(FetchError::Cancelled);
+        }
+        match attempt() {
+            Attempt::Done(result) => return result,
+            Attempt::Retry(error, _) if tries >= config.max_retries => return Err(error),
+            Attempt::Retry(error, retry_after) => {
+                let wait = retry_after.unwrap_or_else(|| backoff(config, tries, jitter())).min(config.max_backoff);
+                warn!("{}, trying again in {:.1}s", error, wait.as_secs_f32());
+                sleep(wait);
+                tries += 1;
+            }
+        }
+    }
+}
+
+// Waits in small steps so a cancelled fetch doesn't hang about for the whole backoff
+fn sleep_unless_cancelled(wait: Duration, cancel: &CancelFlag) {
+    let until = Instant::now() + wait;
+    while !cancel.is_cancelled() {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        thread::sleep(left.min(Duration::from_millis(20)));
+    }
+}
+
+/// Exponential backoff with jitter, so lots of tiles failing at once don't all retry at once.
+/// `jitter` goes from 0 to 1 and picks somewhere between half and all of the full wait.
+fn backoff(config: &HttpConfig, tries: u32, jitter: f64) -> Duration {
+    let full = config.backoff.saturating_mul(1 << tries.min(16)).min(config.max_backoff);
+    full.mul_f64(0.5 + jitter / 2.0)
+}
+
+// Good enough randomness without pulling in a crate for it, every RandomState is seeded differently
+fn jitter() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+/// `Retry-After` is either a number of seconds or a date.
+fn parse_retry_after(retry_after: &str) -> Option<Duration> {
+    match retry_after.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => parse_http_date(retry_after).map(|date| Duration::from_secs(date.saturating_sub(unix_now()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let config = HttpConfig { backoff: Duration::from_secs(1), max_backoff: Duration::from_secs(10), ..Default::default() };
+        assert_eq!(backoff(&config, 0, 1.0), Duration::from_secs(1));
+        assert_eq!(backoff(&config, 2, 1.0), Duration::from_secs(4));
+        assert_eq!(backoff(&config, 2, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff(&config, 30, 1.0), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn retry_after_is_honoured() {
+        let config = HttpConfig::default();
+        let mut waits = Vec::new();
+        let mut tries = 0;
+        let result = retry(&config, &CancelFlag::default(), |wait| waits.push(wait), || {
+            tries += 1;
+            if tries < 3 {
+                Attempt::Retry(FetchError::Status(429), parse_retry_after("7"))
+            } else {
+                Attempt::Done(Ok("tile"))
+            }
+        });
+        assert_eq!(result, Ok("tile"));
+        assert_eq!(waits, vec![Duration::from_secs(7); 2]);
+    }
+
+    #[test]
+    fn gives_up_after_the_max_retries() {
+        let config = HttpConfig { max_retries: 2, ..Default::default() };
+        let mut tries = 0;
+        let result: Result<(), _> = retry(&config, &CancelFlag::default(), |_| {}, || {
+            tries += 1;
+            Attempt::Retry(FetchError::Transport("connection refused".to_string()), None)
+        });
+        assert_eq!(result, Err(FetchError::Transport("connection refused".to_string())));
+        assert_eq!(tries, 3);
+    }
+
+    #[test]
+    fn cancelling_stops_the_retries() {
+        let config = HttpConfig { max_retries: 10, ..Default::default() };
+        let cancel = CancelFlag::default();
+        let mut tries = 0;
+        let result: Result<(), _> = retry(&config, &cancel, |_| cancel.cancel(), || {
+            tries += 1;
+            Attempt::Retry(FetchError::Status(503), None)
+        });
+        assert_eq!(result, Err(FetchError::Cancelled));
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn cancelling_cuts_the_wait_short() {
+        let cancel = CancelFlag::default();
+        cancel.cancel();
+        let start = Instant::now();
+        sleep_unless_cancelled(Duration::from_secs(10), &cancel);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn errors_which_wont_go_away_are_not_retried() {
+        assert!(is_retryable(503));
+        assert!(!is_retryable(404));
+        assert!(!is_retryable(403));
+    }
+}
@@ -0,0 +1,519 @@
+use std::{collections::HashMap, fs, sync::{Arc, Mutex}};
+
+use ab_glyph::{Font, FontArc, GlyphId, OutlineCurve};
+use bevy::prelude::*;
+use raqote::{AntialiasMode, DrawOptions, DrawTarget, LineCap, LineJoin, PathBuilder, SolidSource, Source, StrokeStyle};
+use rstar::{RTree, RTreeObject, AABB};
+
+use crate::{args::{Args, Flag}, tile::Tile};
+
+/// Used unless another font is given with `--font`.
+pub const DEFAULT_FONT: &[u8] = include_bytes!("../assets/fonts/BagnardSans.otf");
+
+/// The font labels are drawn with, and the labels which have already been put on the map.
+#[derive(Resource, Clone, Deref)]
+pub struct MapLabels(pub Arc<LabelRenderer>);
+
+pub struct LabelRenderer {
+    pub font: FontArc,
+    index: Mutex<LabelIndex>,
+}
+
+/// `--font <path>`
+pub const LABEL_FLAGS: &[Flag] = &[Flag::value("--font")];
+
+/// Loads the font given with `--font`, or the bundled font.
+pub fn labels_from_args(args: &Args) -> Result<LabelRenderer, String> {
+    let font = match args.get("--font") {
+        Some(path) => {
+            let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            FontArc::try_from_vec(data).map_err(|e| format!("Failed to load {}: {}", path, e))?
+        }
+        None => FontArc::try_from_slice(DEFAULT_FONT).map_err(|e| e.to_string())?,
+    };
+    Ok(LabelRenderer::new(font))
+}
+
+/// A label which has been placed, the bounds are in global pixels, so the pixel in the tile plus the tile index times the tile size.
+/// This means labels from neighbouring tiles can be checked against each other.
+struct PlacedLabel {
+    text: String,
+    bounds: AABB<[f64; 2]>,
+    /// The tiles which have drawn it, more than one if it goes over the edge of a tile
+    tiles: Vec<Tile>,
+}
+
+impl RTreeObject for PlacedLabel {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        self.bounds
+    }
+}
+
+#[derive(Default)]
+struct LabelIndex {
+    /// Labels only get in the way of labels on the same zoom level, so each level has its own tree
+    labels: HashMap<u32, RTree<PlacedLabel>>,
+    /// Where each tile's labels are, so they can be taken out again once the tile is gone
+    by_tile: HashMap<Tile, Vec<AABB<[f64; 2]>>>,
+}
+
+pub struct LaidOutGlyph {
+    pub id: GlyphId,
+    /// Pixels from the start of the text
+    pub x: f32,
+    pub advance: f32,
+}
+
+/// Where the glyphs of a line of text go.
+pub struct TextLayout {
+    pub glyphs: Vec<LaidOutGlyph>,
+    pub width: f32,
+    pub ascent: f32,
+    pub descent: f32,
+    pub scale: f32,
+}
+
+impl LabelRenderer {
+    pub fn new(font: FontArc) -> Self {
+        Self {
+            font,
+            index: Mutex::new(LabelIndex::default()),
+        }
+    }
+
+    /// Tries to claim the space for the label. If a neighbouring tile has already placed the same label
+    /// this returns true too, so each tile draws its part of a label which goes over the edge.
+    pub fn place(&self, tile: Tile, text: &str, bounds: AABB<[f64; 2]>, padding: f64) -> bool {
+        let mut index = self.index.lock().unwrap();
+        let index = &mut *index;
+        let labels = index.labels.entry(tile.zoom).or_default();
+
+        let padded = AABB::from_corners(
+            [bounds.lower()[0] - padding, bounds.lower()[1] - padding],
+            [bounds.upper()[0] + padding, bounds.upper()[1] + padding],
+        );
+        let (is_free, fits) = {
+            let mut overlapping = labels.locate_in_envelope_intersecting(&padded).peekable();
+            let is_free = overlapping.peek().is_none();
+            (is_free, overlapping.all(|placed| placed.text == text && same_bounds(&placed.bounds, &bounds)))
+        };
+        if is_free {
+            labels.insert(PlacedLabel { text: text.to_string(), bounds, tiles: vec![tile] });
+            index.by_tile.entry(tile).or_default().push(bounds);
+        } else if fits {
+            // This tile draws it too, so it has to stay until both tiles have gone
+            for placed in labels.locate_in_envelope_intersecting_mut(&padded) {
+                if !placed.tiles.contains(&tile) {
+                    placed.tiles.push(tile);
+                    index.by_tile.entry(tile).or_default().push(placed.bounds);
+                }
+            }
+        }
+        fits
+    }
+
+    /// Takes out the labels a tile placed, once nothing is showing it any more and it won't be shown again
+    /// without being drawn again.
+    pub fn forget_tile(&self, tile: &Tile) {
+        let mut index = self.index.lock().unwrap();
+        let index = &mut *index;
+        let (Some(placed), Some(labels)) = (index.by_tile.remove(tile), index.labels.get_mut(&tile.zoom)) else {
+            return;
+        };
+        for bounds in placed {
+            // Nothing else can be inside a placed label, so this only takes out the label itself
+            let drained: Vec<PlacedLabel> = labels.drain_in_envelope(bounds).collect();
+            for mut label in drained {
+                label.tiles.retain(|drawn_by| drawn_by != tile);
+                if !label.tiles.is_empty() {
+                    labels.insert(label);
+                }
+            }
+        }
+        if labels.size() == 0 {
+            index.labels.remove(&tile.zoom);
+        }
+    }
+
+    pub fn layout(&self, text: &str, size: f32) -> TextLayout {
+        let scale = size / self.font.units_per_em().unwrap_or(self.font.height_unscaled());
+        let mut glyphs = Vec::new();
+        let mut x = 0.0;
+        let mut last = None;
+        for c in text.chars() {
+            let glyph = self.font.glyph_id(c);
+            if let Some(last) = last {
+                x += self.font.kern_unscaled(last, glyph) * scale;
+            }
+            let advance = self.font.h_advance_unscaled(glyph) * scale;
+            glyphs.push(LaidOutGlyph { id: glyph, x, advance });
+            x += advance;
+            last = Some(glyph);
+        }
+        TextLayout {
+            glyphs,
+            width: x,
+            ascent: self.font.ascent_unscaled() * scale,
+            descent: self.font.descent_unscaled() * scale,
+            scale,
+        }
+    }
+
+    /// Adds the outline of the glyph to the path, `position` takes a point of the glyph in pixels
+    /// (x to the right and y up from the baseline) and gives back where it goes in the tile.
+    pub fn trace_glyph(&self, pb: &mut PathBuilder, glyph: GlyphId, scale: f32, position: impl Fn(f32, f32) -> (f32, f32)) {
+        let Some(outline) = self.font.outline(glyph) else {
+            return;
+        };
+        let point = |p: ab_glyph::Point| position(p.x * scale, p.y * scale);
+
+        let mut last_end = None;
+        for curve in &outline.curves {
+            let (start, end) = match curve {
+                OutlineCurve::Line(start, end) | OutlineCurve::Quad(start, _, end) | OutlineCurve::Cubic(start, _, _, end) => (*start, *end),
+            };
+            // A new contour starts whenever a curve doesn't carry on from the last one
+            if last_end != Some(start) {
+                if last_end.is_some() {
+                    pb.close();
+                }
+                let (x, y) = point(start);
+                pb.move_to(x, y);
+            }
+            match curve {
+                OutlineCurve::Line(_, end) => {
+                    let (x, y) = point(*end);
+                    pb.line_to(x, y);
+                }
+                OutlineCurve::Quad(_, control, end) => {
+                    let ((cx, cy), (x, y)) = (point(*control), point(*end));
+                    pb.quad_to(cx, cy, x, y);
+                }
+                OutlineCurve::Cubic(_, control_1, control_2, end) => {
+                    let ((c1x, c1y), (c2x, c2y), (x, y)) = (point(*control_1), point(*control_2), point(*end));
+                    pb.cubic_to(c1x, c1y, c2x, c2y, x, y);
+                }
+            }
+            last_end = Some(end);
+        }
+        if last_end.is_some() {
+            pb.close();
+        }
+    }
+
+    /// Draws a line of text with its centre at `x`, `y`.
+    pub fn draw_text(&self, dt: &mut DrawTarget, layout: &TextLayout, x: f32, y: f32, paint: &TextPaint) {
+        let left = x - layout.width / 2.0;
+        let baseline = y + (layout.ascent + layout.descent) / 2.0;
+        let mut pb = PathBuilder::new();
+        for glyph in &layout.glyphs {
+            self.trace_glyph(&mut pb, glyph.id, layout.scale, |gx, gy| (left + glyph.x + gx, baseline - gy));
+        }
+        paint.draw(dt, &pb.finish());
+    }
+}
+
+/// The colours text gets drawn with, the halo is a stroke around the glyphs which makes them readable over the map.
+pub struct TextPaint {
+    pub color: SolidSource,
+    pub halo_color: SolidSource,
+    pub halo_width: f32,
+}
+
+impl TextPaint {
+    pub fn draw(&self, dt: &mut DrawTarget, path: &raqote::Path) {
+        let options = DrawOptions {
+            antialias: AntialiasMode::Gray,
+            ..Default::default()
+        };
+        if self.halo_width > 0.0 {
+            let stroke_style = StrokeStyle {
+                width: self.halo_width * 2.0,
+                cap: LineCap::Round,
+                join: LineJoin::Round,
+                ..Default::default()
+            };
+            dt.stroke(path, &Source::Solid(self.halo_color), &stroke_style, &options);
+        }
+        dt.fill(path, &Source::Solid(self.color), &options);
+    }
+}
+
+fn same_bounds(a: &AABB<[f64; 2]>, b: &AABB<[f64; 2]>) -> bool {
+    let close = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).abs() < 1.0 && (a[1] - b[1]).abs() < 1.0;
+    close(a.lower(), b.lower()) && close(a.upper(), b.upper())
+}
+
+/// Where a label wants to go, in pixels in the tile.
+pub enum LabelAnchor {
+    Point(f32, f32),
+    /// The label follows the line, and is repeated about every `spacing` pixels along it.
+    /// It's left off parts of the line which bend by more than `max_angle` (radians) between two letters.
+    Line {
+        points: Vec<(f32, f32)>,
+        spacing: f32,
+        max_angle: f32,
+    },
+}
+
+/// A label which a tile wants to draw, labels are placed after all the other layers have been drawn
+/// in order of priority, so the most important labels get first pick of the space.
+pub struct LabelCandidate {
+    pub text: String,
+    pub anchor: LabelAnchor,
+    pub size: f32,
+    pub padding: f32,
+    pub paint: TextPaint,
+    /// The `symbol-sort-key`, the index of the style layer and the `rank` of the feature
+    pub priority: (f32, usize, f32),
+}
+
+impl LabelRenderer {
+    /// Places and draws the labels of a tile, which takes up all of `dt`.
+    pub fn draw_labels(&self, dt: &mut DrawTarget, mut candidates: Vec<LabelCandidate>, tile: Tile) {
+        // Where the tile is in global pixels
+        let tile_origin = (tile.x as f64 * dt.width() as f64, tile.y as f64 * dt.height() as f64);
+        // Lower sort keys go first, then labels from layers higher up the style, then the lower ranked (more important) features
+        candidates.sort_by(|a, b| {
+            a.priority.0.total_cmp(&b.priority.0)
+                .then(b.priority.1.cmp(&a.priority.1))
+                .then(a.priority.2.total_cmp(&b.priority.2))
+        });
+
+        for candidate in candidates {
+            let layout = self.layout(&candidate.text, candidate.size);
+            match &candidate.anchor {
+                LabelAnchor::Point(x, y) => {
+                    let half_width = layout.width as f64 / 2.0;
+                    let half_height = (layout.ascent - layout.descent) as f64 / 2.0;
+                    let (global_x, global_y) = (tile_origin.0 + *x as f64, tile_origin.1 + *y as f64);
+                    let bounds = AABB::from_corners([global_x - half_width, global_y - half_height], [global_x + half_width, global_y + half_height]);
+                    if self.place(tile, &candidate.text, bounds, candidate.padding as f64) {
+                        self.draw_text(dt, &layout, *x, *y, &candidate.paint);
+                    }
+                }
+                LabelAnchor::Line { points, spacing, max_angle } => {
+                    let line = Polyline::new(points);
+                    if line.length < layout.width {
+                        continue;
+                    }
+                    // Spread the labels out evenly along the line
+                    let count = (line.length / spacing).floor().max(1.0) as usize;
+                    for i in 0..count {
+                        let centre = line.length * (i as f32 + 0.5) / count as f32;
+                        let Some(glyphs) = line.place_glyphs(centre - layout.width / 2.0, &layout, *max_angle) else {
+                            continue;
+                        };
+                        let Some(bounds) = glyph_bounds(&glyphs, &layout, dt.width() as f32, dt.height() as f32) else {
+                            continue;
+                        };
+                        let bounds = AABB::from_corners(
+                            [tile_origin.0 + bounds.0 as f64, tile_origin.1 + bounds.1 as f64],
+                            [tile_origin.0 + bounds.2 as f64, tile_origin.1 + bounds.3 as f64],
+                        );
+                        if self.place(tile, &candidate.text, bounds, candidate.padding as f64) {
+                            self.draw_text_along(dt, &layout, &glyphs, &candidate.paint);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws each glyph centred on its spot on the line, turned to follow it.
+    fn draw_text_along(&self, dt: &mut DrawTarget, layout: &TextLayout, glyphs: &[GlyphPlacement], paint: &TextPaint) {
+        let middle = (layout.ascent + layout.descent) / 2.0;
+        let mut pb = PathBuilder::new();
+        for (glyph, placement) in layout.glyphs.iter().zip(glyphs) {
+            let (sin, cos) = placement.angle.sin_cos();
+            self.trace_glyph(&mut pb, glyph.id, layout.scale, |gx, gy| {
+                // Along the line and up from it, with the middle of the glyph on the line
+                let along = gx - glyph.advance / 2.0;
+                let up = gy - middle;
+                (placement.x + along * cos + up * sin, placement.y + along * sin - up * cos)
+            });
+        }
+        paint.draw(dt, &pb.finish());
+    }
+}
+
+/// Where the middle of a glyph which follows a line goes, and the direction of the line there.
+struct GlyphPlacement {
+    x: f32,
+    y: f32,
+    angle: f32,
+}
+
+struct Polyline<'a> {
+    points: &'a [(f32, f32)],
+    /// How far along the line each point is
+    distances: Vec<f32>,
+    length: f32,
+}
+
+impl<'a> Polyline<'a> {
+    fn new(points: &'a [(f32, f32)]) -> Self {
+        let mut distances = Vec::with_capacity(points.len());
+        let mut length = 0.0;
+        for (i, point) in points.iter().enumerate() {
+            if i > 0 {
+                let last = points[i - 1];
+                length += (point.0 - last.0).hypot(point.1 - last.1);
+            }
+            distances.push(length);
+        }
+        Self { points, distances, length }
+    }
+
+    /// The point and direction (radians) at that distance along the line.
+    fn at(&self, distance: f32) -> Option<(f32, f32, f32)> {
+        let i = self.distances.partition_point(|d| *d < distance).clamp(1, self.points.len().checked_sub(1)?);
+        let (start, end) = (self.points[i - 1], self.points[i]);
+        let segment = self.distances[i] - self.distances[i - 1];
+        let t = if segment > 0.0 { (distance - self.distances[i - 1]) / segment } else { 0.0 };
+        Some((
+            start.0 + (end.0 - start.0) * t,
+            start.1 + (end.1 - start.1) * t,
+            (end.1 - start.1).atan2(end.0 - start.0),
+        ))
+    }
+
+    /// Works out where the glyphs go for text starting `start` pixels along the line. The text is flipped so
+    /// it runs the other way along the line if it would otherwise be upside down.
+    fn place_glyphs(&self, start: f32, layout: &TextLayout, max_angle: f32) -> Option<Vec<GlyphPlacement>> {
+        if start < 0.0 || start + layout.width > self.length {
+            return None;
+        }
+        let (start_x, _, _) = self.at(start)?;
+        let (end_x, _, _) = self.at(start + layout.width)?;
+        let flipped = end_x < start_x;
+
+        let mut placements: Vec<GlyphPlacement> = Vec::with_capacity(layout.glyphs.len());
+        for glyph in &layout.glyphs {
+            let middle = glyph.x + glyph.advance / 2.0;
+            let (x, y, angle) = if flipped {
+                // Same stretch of line, but the first glyph goes at the far end of it
+                let (x, y, angle) = self.at(start + layout.width - middle)?;
+                (x, y, angle + std::f32::consts::PI)
+            } else {
+                self.at(start + middle)?
+            };
+            if let Some(last) = placements.last() {
+                let bend = (angle - last.angle + std::f32::consts::PI).rem_euclid(2.0 * std::f32::consts::PI) - std::f32::consts::PI;
+                if bend.abs() > max_angle {
+                    return None;
+                }
+            }
+            placements.push(GlyphPlacement { x, y, angle });
+        }
+        Some(placements)
+    }
+}
+
+/// The box around all of the glyphs, as (min x, min y, max x, max y). Labels which would go over the edge
+/// of the tile are left out, as the neighbouring tile has a different piece of the line and wouldn't draw the rest.
+fn glyph_bounds(glyphs: &[GlyphPlacement], layout: &TextLayout, width: f32, height: f32) -> Option<(f32, f32, f32, f32)> {
+    let radius = (layout.ascent - layout.descent) / 2.0 + layout.glyphs.iter().map(|glyph| glyph.advance).fold(0.0, f32::max) / 2.0;
+    let mut bounds = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+    for glyph in glyphs {
+        bounds = (
+            bounds.0.min(glyph.x - radius),
+            bounds.1.min(glyph.y - radius),
+            bounds.2.max(glyph.x + radius),
+            bounds.3.max(glyph.y + radius),
+        );
+    }
+    (bounds.0 >= 0.0 && bounds.1 >= 0.0 && bounds.2 <= width && bounds.3 <= height).then_some(bounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(x: f64, y: f64) -> AABB<[f64; 2]> {
+        AABB::from_corners([x, y], [x + 40.0, y + 10.0])
+    }
+
+    const TILE: Tile = Tile { x: 0, y: 0, zoom: 14 };
+
+    #[test]
+    fn overlapping_labels_are_not_placed() {
+        let labels = labels_from_args(&Args::default()).unwrap();
+        assert!(labels.place(TILE, "Cambridge", bounds(0.0, 0.0), 2.0));
+        assert!(!labels.place(TILE, "Chesterton", bounds(20.0, 5.0), 2.0));
+        assert!(labels.place(TILE, "Chesterton", bounds(100.0, 5.0), 2.0));
+    }
+
+    #[test]
+    fn neighbouring_tile_can_draw_the_same_label() {
+        let labels = labels_from_args(&Args::default()).unwrap();
+        // The label goes over the edge between two tiles, so both of them draw it
+        assert!(labels.place(TILE, "Mill Road", bounds(240.0, 100.0), 2.0));
+        assert!(labels.place(Tile::new(1, 0, 14), "Mill Road", bounds(240.2, 100.1), 2.0));
+    }
+
+    #[test]
+    fn labels_on_other_zooms_dont_get_in_the_way() {
+        let labels = labels_from_args(&Args::default()).unwrap();
+        assert!(labels.place(TILE, "Cambridge", bounds(0.0, 0.0), 2.0));
+        assert!(labels.place(Tile::new(0, 0, 15), "Chesterton", bounds(0.0, 0.0), 2.0));
+        assert!(!labels.place(Tile::new(1, 0, 14), "Chesterton", bounds(0.0, 0.0), 2.0));
+    }
+
+    #[test]
+    fn forgotten_tiles_make_room_for_other_labels() {
+        let labels = labels_from_args(&Args::default()).unwrap();
+        let neighbour = Tile::new(1, 0, 14);
+        assert!(labels.place(TILE, "Cambridge", bounds(0.0, 0.0), 2.0));
+        assert!(labels.place(TILE, "Mill Road", bounds(240.0, 100.0), 2.0));
+        assert!(labels.place(neighbour, "Mill Road", bounds(240.2, 100.1), 2.0));
+
+        labels.forget_tile(&TILE);
+        assert!(labels.place(neighbour, "Chesterton", bounds(0.0, 0.0), 2.0));
+        // The neighbour drew Mill Road too, so it's still there
+        assert!(!labels.place(neighbour, "Newmarket Road", bounds(250.0, 100.0), 2.0));
+
+        labels.forget_tile(&neighbour);
+        let index = labels.index.lock().unwrap();
+        assert!(index.labels.is_empty() && index.by_tile.is_empty());
+    }
+
+    fn three_letters() -> TextLayout {
+        TextLayout {
+            glyphs: (0..3).map(|i| LaidOutGlyph { id: GlyphId(0), x: i as f32 * 10.0, advance: 10.0 }).collect(),
+            width: 30.0,
+            ascent: 8.0,
+            descent: -2.0,
+            scale: 1.0,
+        }
+    }
+
+    #[test]
+    fn line_labels_read_left_to_right() {
+        // The road is drawn from right to left, the label should still go from left to right
+        let points = [(100.0, 50.0), (0.0, 50.0)];
+        let glyphs = Polyline::new(&points).place_glyphs(35.0, &three_letters(), 1.0).unwrap();
+        assert!(glyphs[0].x < glyphs[2].x);
+        assert!(glyphs.iter().all(|glyph| glyph.angle.cos() > 0.99));
+    }
+
+    #[test]
+    fn flipped_labels_stay_on_their_stretch_of_line() {
+        // 10 pixels in from the start of a right to left road, so the label covers x = 60..90
+        let points = [(100.0, 50.0), (0.0, 50.0)];
+        let glyphs = Polyline::new(&points).place_glyphs(10.0, &three_letters(), 1.0).unwrap();
+        let xs: Vec<f32> = glyphs.iter().map(|glyph| glyph.x).collect();
+        assert_eq!(xs, vec![65.0, 75.0, 85.0]);
+        assert!(glyphs.iter().all(|glyph| glyph.y == 50.0));
+    }
+
+    #[test]
+    fn line_labels_skip_sharp_bends() {
+        let points = [(0.0, 0.0), (15.0, 0.0), (15.0, 30.0)];
+        let line = Polyline::new(&points);
+        assert!(line.place_glyphs(0.0, &three_letters(), 45f32.to_radians()).is_none());
+        assert!(line.place_glyphs(0.0, &three_letters(), 100f32.to_radians()).is_some());
+    }
+}
@@ -1,24 +1,82 @@
+use std::sync::Arc;
+
 use bevy::{prelude::*, window::PrimaryWindow, winit::{UpdateMode, WinitSettings}};
 use bevy_pancam::PanCamPlugin;
 use camera::{camera_middle_to_lat_long, setup_camera};
 use debug::DebugPlugin;
 use ofm_api::OfmTiles;
 use rstar::RTree;
-use tile::{world_mercator_to_lat_lon, Coord};
+use seed::run_seed;
+use render::run_render;
+use tile::{world_mercator_to_lat_lon, LatLon};
 use tile_map::{ChunkManager, Location, TileMapPlugin, ZoomManager};
+use args::Args;
+use http::HTTP_FLAGS;
+use labels::{labels_from_args, MapLabels, LABEL_FLAGS};
+use style::{style_from_args, MapStyle, STYLE_FLAGS};
+use tile_mesh::{render_mode_from_args, RENDER_MODE_FLAGS};
+use tile_source::{setup_attribution, tile_source_from_args, ActiveTileSource, TILE_SOURCE_FLAGS};
 
+pub mod args;
 pub mod ofm_api;
 pub mod tile;
 pub mod tile_map;
 pub mod debug;
 pub mod camera;
+pub mod tile_source;
+pub mod mbtiles;
+pub mod pmtiles;
+pub mod style;
+pub mod labels;
+pub mod tile_mesh;
+pub mod tile_loader;
+pub mod tile_cache;
+pub mod http;
+pub mod seed;
+pub mod render;
+#[cfg(test)]
+mod test_support;
 
-pub const STARTING_LONG_LAT: Coord = Coord::new(0.011, 0.011);
-pub const STARTING_DISPLACEMENT: Coord = Coord::new(52.207_59, 0.186_745_48);
+pub const STARTING_LONG_LAT: LatLon = LatLon::new(0.011, 0.011);
+pub const STARTING_DISPLACEMENT: LatLon = LatLon::new(52.207_59, 0.186_745_48);
 // This can be changed, it changes the size of each tile too.
 pub const TILE_QUALITY: i32 = 256;
+// World units are pixels at this zoom level, whatever level the tiles on screen are from.
+pub const WORLD_ZOOM: u32 = 14;
+
+/// Something other than the map viewer, picked by the first argument.
+type Subcommand = fn(&[String]) -> Result<(), String>;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Subcommands which run without opening a window
+    let subcommand: Option<Subcommand> = match args.first().map(String::as_str) {
+        Some("seed") => Some(run_seed),
+        Some("render") => Some(run_render),
+        _ => None,
+    };
+    if let Some(subcommand) = subcommand {
+        if let Err(e) = subcommand(&args[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let args = match Args::parse(&args, &[TILE_SOURCE_FLAGS, HTTP_FLAGS, STYLE_FLAGS, LABEL_FLAGS, RENDER_MODE_FLAGS]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (tile_source, style, labels, render_mode) = match (tile_source_from_args(&args), style_from_args(&args), labels_from_args(&args), render_mode_from_args(&args)) {
+        (Ok(tile_source), Ok(style), Ok(labels), Ok(render_mode)) => (tile_source, style, labels, render_mode),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     App::new()
     .add_plugins((DefaultPlugins.set(WindowPlugin {
         primary_window: Some(Window {
@@ -36,7 +94,11 @@ fn main() {
         },
         ..Default::default()
     })
-    .add_systems(Startup, setup_camera)
+    .insert_resource(ActiveTileSource(tile_source))
+    .insert_resource(MapStyle(Arc::new(style)))
+    .insert_resource(MapLabels(Arc::new(labels)))
+    .insert_resource(render_mode)
+    .add_systems(Startup, (setup_camera, setup_attribution))
     .add_systems(Update, handle_mouse)
     .insert_resource(Location::default())
     .add_plugins(DebugPlugin)
@@ -61,20 +123,20 @@ pub fn handle_mouse(
         if let Some(position) = q_windows.single().cursor_position() {
             /*
             let world_pos = camera.viewport_to_world_2d(camera_transform, position).unwrap();
-            let long_lat = world_mercator_to_lat_lon(world_pos.x as f64, world_pos.y as f64, chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size);
+            let long_lat = world_mercator_to_lat_lon(world_pos.into(), chunk_manager.refrence_long_lat, WORLD_ZOOM, zoom_manager.tile_size.into());
             let closest_tile = long_lat.to_tile_coords(zoom_manager.zoom_level).to_lat_long();
             info!("{:?}", closest_tile);
             */
 
             let world_pos = camera.viewport_to_world_2d(camera_transform, position).unwrap();
-            info!("{:?}", world_mercator_to_lat_lon(world_pos.x.into(), world_pos.y.into(), chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size));
+            info!("{:?}", world_mercator_to_lat_lon(world_pos.into(), chunk_manager.refrence_long_lat, WORLD_ZOOM, zoom_manager.tile_size.into()));
         }
     }   
     if buttons.pressed(MouseButton::Middle){
         chunk_manager.update = true;
     }
     if buttons.just_released(MouseButton::Middle) {
-        let movement = camera_middle_to_lat_long(camera_transform, zoom_manager.zoom_level, zoom_manager.tile_size, chunk_manager.refrence_long_lat);
+        let movement = camera_middle_to_lat_long(camera_transform, WORLD_ZOOM, zoom_manager.tile_size, chunk_manager.refrence_long_lat);
         if movement != location_manager.location {
             location_manager.location = movement;
             chunk_manager.update = true;
@@ -0,0 +1,262 @@
+use std::{borrow::Cow, io::{self, Write}, path::Path, sync::Mutex};
+
+use flate2::{write::GzEncoder, Compression};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::{http::FetchError, tile_source::{TileKind, TileSource}};
+
+/// Reads tiles out of an MBTiles file, which is just a SQLite database with a `tiles` and a `metadata` table.
+/// https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+pub struct MbTilesSource {
+    pub name: String,
+    pub kind: TileKind,
+    pub max_zoom: u32,
+    pub attribution: String,
+    // rusqlite connections can't be shared between threads, and tiles are loaded by tasks on the io pool
+    connection: Mutex<Connection>,
+}
+
+impl MbTilesSource {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        let name = metadata(&connection, "name")?.unwrap_or_else(|| {
+            path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+        });
+        // Vector tiles are stored as gzipped pbf, everything else is some kind of image
+        let kind = match metadata(&connection, "format")?.as_deref() {
+            Some("pbf") => TileKind::Vector,
+            _ => TileKind::Raster,
+        };
+        let max_zoom = match metadata(&connection, "maxzoom")?.and_then(|zoom| zoom.parse().ok()) {
+            Some(zoom) => zoom,
+            None => connection.query_row("SELECT MAX(zoom_level) FROM tiles", [], |row| row.get::<_, Option<u32>>(0))?.unwrap_or(19),
+        };
+        let attribution = metadata(&connection, "attribution")?.unwrap_or_default();
+
+        Ok(Self {
+            name,
+            kind,
+            max_zoom,
+            attribution,
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn metadata(connection: &Connection, name: &str) -> rusqlite::Result<Option<String>> {
+    connection
+        .query_row("SELECT value FROM metadata WHERE name = ?1", [name], |row| row.get(0))
+        .optional()
+}
+
+/// MBTiles uses the TMS scheme, so y counts up from the bottom of the map rather than down from the top.
+/// `None` if there's no such tile at that zoom.
+pub fn flip_y(y: u64, zoom: u64) -> Option<u64> {
+    let rows = 1u64.checked_shl(u32::try_from(zoom).ok()?)?;
+    (y < rows).then(|| rows - 1 - y)
+}
+
+// For writing, where a tile which can't exist is a mistake rather than just not being in the file
+fn tms_row(y: u64, zoom: u64) -> rusqlite::Result<u64> {
+    flip_y(y, zoom).ok_or(rusqlite::Error::IntegralValueOutOfRange(3, y as i64))
+}
+
+impl TileSource for MbTilesSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> TileKind {
+        self.kind
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn is_cached(&self, _x: u64, _y: u64, _zoom: u64) -> bool {
+        // Everything is already on disk
+        true
+    }
+
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, FetchError> {
+        let Some(row) = flip_y(y, zoom) else {
+            return Ok(vec![]);
+        };
+        let connection = self.connection.lock().unwrap();
+        let tile = connection
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                [zoom, x, row],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional();
+
+        match tile {
+            Ok(Some(data)) => Ok(data),
+            // The area isn't covered by the file
+            Ok(None) => Ok(vec![]),
+            Err(e) => Err(FetchError::Io(e.to_string())),
+        }
+    }
+}
+
+/// Writes tiles into an MBTiles file, making it if it isn't there yet. Adding to a file which already
+/// has tiles in it is fine, which is how seeding picks up where it left off.
+pub struct MbTilesWriter {
+    kind: TileKind,
+    connection: Connection,
+}
+
+impl MbTilesWriter {
+    pub fn create(path: &Path, name: &str, kind: TileKind, attribution: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+            CREATE UNIQUE INDEX IF NOT EXISTS metadata_name ON metadata (name);
+            CREATE TABLE IF NOT EXISTS tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+            CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles (zoom_level, tile_column, tile_row);",
+        )?;
+
+        let writer = Self { kind, connection };
+        writer.set_metadata("name", name)?;
+        writer.set_metadata("format", match kind {
+            TileKind::Raster => "png",
+            TileKind::Vector => "pbf",
+        })?;
+        if !attribution.is_empty() {
+            writer.set_metadata("attribution", attribution)?;
+        }
+        Ok(writer)
+    }
+
+    pub fn set_metadata(&self, name: &str, value: &str) -> rusqlite::Result<()> {
+        self.connection.execute("INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)", [name, value])?;
+        Ok(())
+    }
+
+    pub fn has_tile(&self, x: u64, y: u64, zoom: u64) -> rusqlite::Result<bool> {
+        let Some(row) = flip_y(y, zoom) else {
+            return Ok(false);
+        };
+        self.connection
+            .query_row(
+                "SELECT 1 FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                [zoom, x, row],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    pub fn put_tile(&self, x: u64, y: u64, zoom: u64, data: &[u8]) -> rusqlite::Result<()> {
+        // The spec wants vector tiles gzipped, servers usually send them already gzipped but not always
+        let data = if self.kind == TileKind::Vector && !data.starts_with(&[0x1f, 0x8b]) {
+            Cow::Owned(gzip(data).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?)
+        } else {
+            Cow::Borrowed(data)
+        };
+        self.connection.execute(
+            "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+            params![zoom, x, tms_row(y, zoom)?, data],
+        )?;
+        Ok(())
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_dir;
+
+    #[test]
+    fn rows_count_up_from_the_bottom() {
+        assert_eq!(flip_y(0, 0), Some(0));
+        assert_eq!(flip_y(0, 1), Some(1));
+        assert_eq!(flip_y(1, 1), Some(0));
+        assert_eq!(flip_y(5, 3), Some(2));
+        assert_eq!(flip_y((1 << 20) - 1, 20), Some(0));
+    }
+
+    #[test]
+    fn rows_which_cant_exist_have_no_flip() {
+        assert_eq!(flip_y(2, 1), None);
+        assert_eq!(flip_y(u64::MAX, 10), None);
+        assert_eq!(flip_y(0, 64), None);
+        assert_eq!(flip_y(0, u64::MAX), None);
+    }
+
+    /// An MBTiles file with the given metadata and no tiles, or the given tiles if there are some.
+    fn write_mbtiles(name: &str, metadata: &[(&str, &str)], tiles: &[(u64, u64, u64, &[u8])]) -> std::path::PathBuf {
+        let path = test_dir(name).join("test.mbtiles");
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+            CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);",
+        ).unwrap();
+        for (name, value) in metadata {
+            connection.execute("INSERT INTO metadata (name, value) VALUES (?1, ?2)", [name, value]).unwrap();
+        }
+        for (zoom, column, row, data) in tiles {
+            connection.execute("INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)", params![zoom, column, row, data]).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn metadata_describes_the_source() {
+        let path = write_mbtiles("mbtiles-metadata", &[("name", "Cambridge"), ("format", "pbf"), ("maxzoom", "12"), ("minzoom", "4"), ("attribution", "OSM")], &[]);
+        let source = MbTilesSource::open(&path).unwrap();
+        assert_eq!(source.name, "Cambridge");
+        assert_eq!(source.kind, TileKind::Vector);
+        assert_eq!(source.max_zoom, 12);
+        assert_eq!(source.attribution, "OSM");
+    }
+
+    #[test]
+    fn missing_metadata_is_worked_out() {
+        // No max zoom so it comes from the tiles, and no format means an image
+        let path = write_mbtiles("mbtiles-no-metadata", &[("format", "jpg")], &[(9, 1, 2, b"tile"), (11, 3, 4, b"tile")]);
+        let source = MbTilesSource::open(&path).unwrap();
+        assert_eq!(source.name, "test");
+        assert_eq!(source.kind, TileKind::Raster);
+        assert_eq!(source.max_zoom, 11);
+        assert_eq!(source.attribution, "");
+
+        let path = write_mbtiles("mbtiles-empty", &[], &[]);
+        assert_eq!(MbTilesSource::open(&path).unwrap().max_zoom, 19);
+    }
+
+    #[test]
+    fn tiles_are_read_with_the_row_flipped() {
+        // Row 5 at zoom 3 is row 2 counting down from the top
+        let path = write_mbtiles("mbtiles-flip", &[], &[(3, 1, 5, b"tile")]);
+        let source = MbTilesSource::open(&path).unwrap();
+        assert_eq!(source.get_tile(1, 2, 3), Ok(b"tile".to_vec()));
+        assert_eq!(source.get_tile(1, 5, 3), Ok(vec![]));
+        assert_eq!(source.get_tile(1, 100, 3), Ok(vec![]));
+        assert_eq!(source.get_tile(1, 0, 70), Ok(vec![]));
+    }
+
+    #[test]
+    fn writing_tiles_which_cant_exist_is_an_error() {
+        let path = test_dir("mbtiles-write").join("test.mbtiles");
+        let writer = MbTilesWriter::create(&path, "test", TileKind::Raster, "").unwrap();
+        writer.put_tile(1, 2, 3, b"tile").unwrap();
+        assert!(writer.has_tile(1, 2, 3).unwrap());
+        assert!(!writer.has_tile(1, 20, 3).unwrap());
+        assert!(writer.put_tile(1, 20, 3, b"tile").is_err());
+        assert_eq!(MbTilesSource::open(&path).unwrap().get_tile(1, 2, 3), Ok(b"tile".to_vec()));
+    }
+}
@@ -1,11 +1,13 @@
-use std::{fs, path::Path};
+use std::{collections::HashMap, io::Read};
 
-use bevy::{asset::RenderAssetUsages, ecs::system::Resource, image::Image, log::info, render::render_resource::{Extent3d, TextureDimension, TextureFormat}};
-use mvt_reader::Reader;
-use raqote::{AntialiasMode, DrawOptions, DrawTarget, PathBuilder, SolidSource, Source, StrokeStyle};
+use bevy::{asset::RenderAssetUsages, ecs::system::Resource, image::Image, log::{debug, info, warn}, render::render_resource::{Extent3d, TextureDimension, TextureFormat}};
+use flate2::read::GzDecoder;
+use geo::Centroid;
+use mvt_reader::{feature::Feature, Reader};
+use raqote::{AntialiasMode, DrawOptions, DrawTarget, Path as RaqotePath, PathBuilder, SolidSource, Source, StrokeStyle, Winding};
 use rstar::{RTree, RTreeObject, AABB};
 
-use crate::tile::{level_to_tile_width, Coord};
+use crate::{http::{CancelFlag, FetchError, HttpClient}, labels::{LabelAnchor, LabelCandidate, LabelRenderer, TextPaint}, style::{feature_properties, format_text_field, geometry_type, EvalContext, LayerKind, Style, SymbolPlacement}, tile::{level_to_tile_width, LatLon}, tile_loader::TileError, tile_cache::{expiry_from_headers, unix_now, CacheHeaders, CacheLookup, TileCache}, tile_source::{TileKind, TileSource}};
 
 #[derive(Resource, Clone)]
 pub struct OfmTiles {
@@ -17,7 +19,7 @@ pub struct OfmTiles {
 pub struct Tile {
     pub name: String,
     pub image: Image,
-    pub tile_location: Coord,
+    pub tile_location: LatLon,
     pub zoom: u32,
 } 
 
@@ -30,17 +32,12 @@ impl RTreeObject for Tile {
     type Envelope = AABB<[f64; 2]>;
 
     fn envelope(&self) -> Self::Envelope {
-        AABB::from_corners(
-            [self.tile_location.long as f64, self.tile_location.lat as f64],
-            [
-                self.tile_location.long as f64 + level_to_tile_width(self.zoom) as f64,
-                self.tile_location.lat as f64 + level_to_tile_width(self.zoom) as f64,
-            ],
-        )
+        let corner = self.tile_location.to_xy();
+        AABB::from_corners(corner.to_array(), (corner + level_to_tile_width(self.zoom)).to_array())
     }
 }
 impl Tile {
-    pub fn new(name: String, image: Image, tile_location: Coord, zoom: u32) -> Self {
+    pub fn new(name: String, image: Image, tile_location: LatLon, zoom: u32) -> Self {
         Self {
             name,
             image,
@@ -56,18 +53,21 @@ pub fn tile_width_meters(zoom: u32) -> f64 {
     earth_circumference_meters / num_tiles
 }
 
-pub fn get_ofm_image(x: u64, y: u64, zoom: u64, tile_size: u32) -> Image {
-    let data = send_vector_request(x, y, zoom, "https://tiles.openfreemap.org/planet/20250122_001001_pt".to_string());
-    buffer_to_bevy_image(ofm_to_data_image(data, tile_size, zoom as u32), tile_size)
+/// Gets a tile from the source and turns it into raw rgba data which can be put into an image.
+pub fn get_tile_data(source: &dyn TileSource, style: &Style, labels: &LabelRenderer, x: u64, y: u64, zoom: u64, tile_size: u32) -> Result<Vec<u8>, TileError> {
+    decode_tile_data(source.kind(), source.get_tile(x, y, zoom)?, style, labels, x, y, zoom, tile_size)
 }
 
-pub fn get_rasta_data(x: u64, y: u64, zoom: u64) -> Vec<u8> {
-    send_image_tile_request(x, y, zoom, "https://tile.openstreetmap.org".to_string())
-}
-
-pub fn get_mvt_data(x: u64, y: u64, zoom: u64, tile_size: u32) -> Vec<u8> {
-    let data = send_vector_request(x, y, zoom, "https://tiles.openfreemap.org/planet/20250122_001001_pt".to_string());
-    ofm_to_data_image(data, tile_size, zoom as u32)
+/// Turns the bytes a tile source gave us into raw rgba data, empty if there weren't any.
+#[allow(clippy::too_many_arguments)]
+pub fn decode_tile_data(kind: TileKind, data: Vec<u8>, style: &Style, labels: &LabelRenderer, x: u64, y: u64, zoom: u64, tile_size: u32) -> Result<Vec<u8>, TileError> {
+    if data.is_empty() {
+        return Ok(data);
+    }
+    match kind {
+        TileKind::Raster => png_to_image(data, tile_size),
+        TileKind::Vector => ofm_to_data_image(decompress_tile(data), tile_size, zoom as u32, x, y, style, labels),
+    }
 }
 
 pub fn buffer_to_bevy_image(data: Vec<u8>, tile_size: u32) -> Image {
@@ -84,89 +84,129 @@ pub fn buffer_to_bevy_image(data: Vec<u8>, tile_size: u32) -> Image {
     )
 }
 
-/// Rather than getting a vector trile which can be tricky to work with, we get a buffer of an image 
-/// https://wiki.openstreetmap.org/wiki/Raster_tile_providers
-fn send_image_tile_request(x: u64, y: u64, zoom: u64, url: String) -> Vec<u8> {
-    let cache_dir = "cache";
-    let cache_file = format!("{}/{}_{}_{}.png", cache_dir, zoom, x, y);
-    
-    // Check if the file exists in the cache
-    if Path::new(&cache_file).exists() {
-        return png_to_image(fs::read(&cache_file).expect("Failed to read cache file"));
-    }
-
-    // If not in cache, fetch from the network
-    let mut status = 429;
-    while status == 429 {
-        if let Ok(response) = ureq::get(format!("{}/{}/{}/{}.png", url, zoom, x, y).as_str()).call() {
-            info!("{}", format!("{}/{}/{}/{}.png", url, zoom, x, y));
-            if response.status() == 200 {
-                let mut reader = response.into_reader();
-                let mut bytes = Vec::new();
-                reader.read_to_end(&mut bytes).expect("Failed to read bytes from response");
-
-                // Save to cache
-                fs::create_dir_all(cache_dir).expect("Failed to create cache directory");
-                fs::write(&cache_file, &bytes).expect("Failed to write cache file");
-
-                return png_to_image(bytes);
-            } else if response.status() == 429 {
-                std::thread::sleep(std::time::Duration::from_secs(5));
-            } else {
-                status = 0;
+/// Gets a tile from the cache, or from the server if it isn't cached or has expired.
+/// Expired tiles are revalidated with the server so it only has to send the tile again if it changed.
+#[allow(clippy::too_many_arguments)]
+pub fn send_tile_request(http: &HttpClient, url: String, cache: &TileCache, x: u64, y: u64, zoom: u64, extension: &str, cancel: &CancelFlag) -> Result<Vec<u8>, FetchError> {
+    let stale = match cache.get(x, y, zoom, extension) {
+        CacheLookup::Fresh(data) => return Ok(data),
+        CacheLookup::Stale(data, meta) => Some((data, meta)),
+        CacheLookup::Missing => None,
+    };
+
+    let mut conditions = Vec::new();
+    if let Some((_, meta)) = &stale {
+        if let Some(etag) = &meta.etag {
+            conditions.push(("If-None-Match", etag.as_str()));
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            conditions.push(("If-Modified-Since", last_modified.as_str()));
+        }
+    }
+
+    let response = match http.get(&url, &conditions, cancel) {
+        Ok(response) => response,
+        // The server doesn't have a tile here, so there's nothing to draw
+        Err(FetchError::Status(404)) => return Ok(Vec::new()),
+        Err(e) => {
+            // An old tile is better than no tile
+            if let Some((data, _)) = stale {
+                warn!("Using expired tile for {}, {}", url, e);
+                return Ok(data);
+            }
+            return Err(e);
+        }
+    };
+    info!("{} {}", response.status(), url);
+    let headers = response_cache_headers(&response);
+
+    if response.status() == 304 {
+        if let Some((data, meta)) = stale {
+            if let Some(headers) = headers {
+                if let Err(e) = cache.revalidated(x, y, zoom, extension, meta, headers) {
+                    warn!("Failed to update cache for {}: {}", url, e);
+                }
             }
+            return Ok(data);
         }
     }
-    vec![]
+    if response.status() == 204 {
+        return Ok(Vec::new());
+    }
+    if response.status() != 200 {
+        return Err(FetchError::Status(response.status()));
+    }
+
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes).map_err(|e| FetchError::Transport(e.to_string()))?;
+    if let Some(headers) = headers {
+        if let Err(e) = cache.put(x, y, zoom, extension, &bytes, headers) {
+            warn!("Failed to cache {}: {}", url, e);
+        }
+    }
+    Ok(bytes)
 }
 
-// Helper convert png to uncompressed image
-fn png_to_image(data: Vec<u8>) -> Vec<u8> {
-    let img = image::load_from_memory(&data).expect("Failed to decode PNG data");
-    let rgba = img.to_rgba8();
-    rgba.to_vec()
+// None when the server says not to store the tile
+fn response_cache_headers(response: &ureq::Response) -> Option<CacheHeaders> {
+    Some(CacheHeaders {
+        etag: response.header("ETag").map(|etag| etag.to_string()),
+        last_modified: response.header("Last-Modified").map(|last_modified| last_modified.to_string()),
+        expires: expiry_from_headers(response.header("Cache-Control"), response.header("Expires"), unix_now())?,
+    })
 }
 
-fn send_vector_request(x: u64, y: u64, zoom: u64, url: String) -> Vec<u8> {
-    let cache_dir = "cache";
-    let cache_file = format!("{}/{}_{}_{}.pbf", cache_dir, zoom, x, y);
+// Helper convert png (or jpeg) to uncompressed image, some sources have bigger tiles so we scale them to the tile size
+fn png_to_image(data: Vec<u8>, tile_size: u32) -> Result<Vec<u8>, TileError> {
+    let mut img = image::load_from_memory(&data).map_err(|e| TileError::Image(e.to_string()))?;
+    if img.width() != tile_size || img.height() != tile_size {
+        img = img.resize_exact(tile_size, tile_size, image::imageops::FilterType::Triangle);
+    }
+    let rgba = img.to_rgba8();
+    Ok(rgba.to_vec())
+}
 
-    // Check if the file exists in the cache
-    if Path::new(&cache_file).exists() {
-        return fs::read(&cache_file).expect("Failed to read cache file");
+/// Vector tiles are often stored gzipped (always in MBTiles), so unzip them if they are.
+pub fn decompress_tile(data: Vec<u8>) -> Vec<u8> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        if GzDecoder::new(data.as_slice()).read_to_end(&mut decoded).is_ok() {
+            return decoded;
+        }
     }
+    data
+}
 
-    // If not in cache, fetch from the network
-    let mut status = 429;
-    while status == 429 {
-        if let Ok(response) = ureq::get(format!("{}/{}/{}/{}.pbf", url, zoom, x, y).as_str()).call() {
-            if response.status() == 200 {
-                let mut reader = response.into_reader();
-                let mut bytes = Vec::new();
-                reader.read_to_end(&mut bytes).expect("Failed to read bytes from response");
+/// Style pixel sizes (line widths, circle radii) are for 512px tiles, like MapLibre uses.
+pub const STYLE_TILE_SIZE: f32 = 512.0;
 
-                // Save to cache
-                fs::create_dir_all(cache_dir).expect("Failed to create cache directory");
-                fs::write(&cache_file, &bytes).expect("Failed to write cache file");
+/// The features of each layer in a tile, along with the scale from tile units to pixels.
+pub type DecodedLayers = HashMap<String, (f32, Vec<(Feature, HashMap<String, serde_json::Value>)>)>;
 
-                return bytes;
-            } else if response.status() == 429 {
-                std::thread::sleep(std::time::Duration::from_secs(5));
-            } else {
-                status = 0;
-            }
-        }
+/// Decodes every layer once up front, as lots of style layers draw from the same source layer.
+pub fn decode_layers(tile: &Reader, size: u32) -> Result<DecodedLayers, TileError> {
+    let extents: HashMap<String, u32> = tile.get_layer_metadata().unwrap_or_default().into_iter().map(|layer| (layer.name, layer.extent)).collect();
+    let mut layers = HashMap::new();
+    for (i, name) in tile.get_layer_names().map_err(|e| TileError::Vector(e.to_string()))?.into_iter().enumerate() {
+        // Geometry is in tile units, which go from 0 to the extent of the layer
+        let scale = size as f32 / extents.get(&name).copied().unwrap_or(4096) as f32;
+        let features: Vec<(Feature, HashMap<String, serde_json::Value>)> = tile.get_features(i).unwrap_or_default().into_iter().map(|feature| {
+            let properties = feature_properties(&feature.properties);
+            (feature, properties)
+        }).collect();
+        layers.insert(name, (scale, features));
     }
-    vec![]
+    Ok(layers)
 }
 
-/// This converts it to an image which is as many meters as the tile width This would be AAAMAAZZZING to multithread
-fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Vec<u8> {
-    let tile = Reader::new(data).unwrap();
-    //let size_multiplyer = TILE_QUALITY as u32 / size ;
+/// This draws the vector tile into an image, going through the style layers in order and drawing the features
+/// of each layer's source layer which pass its filter. This would be AAAMAAZZZING to multithread.
+fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32, x: u64, y: u64, style: &Style, labels: &LabelRenderer) -> Result<Vec<u8>, TileError> {
+    let tile = Reader::new(data).map_err(|e| TileError::Vector(e.to_string()))?;
     let mut dt = DrawTarget::new(size as i32 , size as i32);
 
-    if cfg!(debug_assertions) {
+    // A dashed line round the edge so you can see where the tiles are
+    if style.debug_borders {
         let mut pb: PathBuilder = PathBuilder::new();
         pb.move_to(0.0, 0.0);
         pb.line_to(size as f32, 0.0);
@@ -199,191 +239,398 @@ fn ofm_to_data_image(data: Vec<u8>, size: u32, zoom: u32) -> Vec<u8> {
             },
         );
     }
-    
-    let scale = (size as f32 / tile_width_meters(14.try_into().unwrap()).round() as f32) * 0.597_014_9;
-    dt.set_transform(&raqote::Transform::scale(scale, scale));
-
-    // Iterate over layers and features]
-    let layer_names = tile.get_layer_names().unwrap();
-    for (i, title) in layer_names.into_iter().enumerate() {
-        for features in tile.get_features(i).iter() {
-            for feature in features {
-                let mut pb: PathBuilder = PathBuilder::new();
-                match &feature.geometry {
-                    geo::Geometry::Point(point) 
-                        => {
-                            if zoom >= 15 {
-                                pb.move_to(point.x(), point.y());
-                                pb.line_to(point.x() + 1.0, point.y() + 1.0);
-                                pb.line_to(point.x() + 1.0, point.y());
-                                pb.line_to(point.x(), point.y() + 1.0)
-                            }
-                        },
-                    geo::Geometry::Line(line) 
-                        => {
-                            pb.move_to(line.start.x, line.start.y);
-                            pb.line_to(line.end.x, line.end.y);
-                        },
-                    geo::Geometry::LineString(line_string) 
-                        => {
-                            for (j, line) in line_string.lines().enumerate() {
-                                if j == 0 {
-                                    pb.move_to(line.start.x, line.start.y);
-                                    pb.line_to(line.end.x, line.end.y);
-                                } else {
-                                    pb.line_to(line.start.x, line.start.y);
-                                    pb.line_to(line.end.x, line.end.y);
-                                }
-                            }
-                        },
-                    geo::Geometry::Polygon(polygon) 
-                        => {
-                                for (j, line) in polygon.exterior().0.iter().enumerate() {
-                                    if j == 0 {
-                                        pb.move_to(line.x, line.y);
-                                        pb.line_to(line.x, line.y);
-                                    } else {
-                                        pb.line_to(line.x, line.y);
-                                        pb.line_to(line.x, line.y);
-                                    }
-                                }
-                        },
-                    geo::Geometry::MultiPolygon(multi_polygon)
-                        => {
-                                for polygon in multi_polygon {
-                                    for (j, line) in polygon.exterior().0.iter().enumerate() {
-                                        if j == 0 {
-                                            pb.move_to(line.x, line.y);
-                                            pb.line_to(line.x, line.y);
-                                        } else {
-                                            pb.line_to(line.x, line.y);
-                                            pb.line_to(line.x, line.y);
-                                        }
-                                    }
-                                }
-                        },
-                    geo::Geometry::MultiPoint(multi_point) 
-                        => {
-                            if zoom >= 15 {
-                                for point in multi_point {
-                                    pb.move_to(point.x(), point.y());
-                                    pb.line_to(point.x() + 1.0, point.y() + 1.0);
-                                    pb.line_to(point.x() + 1.0, point.y());
-                                    pb.line_to(point.x(), point.y() + 1.0)
-                                }
-                            }
-                        },
-                    geo::Geometry::MultiLineString(multi_line_string) 
-                        => {
-                            for line_string in multi_line_string {
-                                for (j, line) in line_string.lines().enumerate() {
-                                    if j == 0 {
-                                        pb.move_to(line.start.x, line.start.y);
-                                        pb.line_to(line.end.x, line.end.y);
-                                    } else {
-                                        pb.line_to(line.start.x, line.start.y);
-                                        pb.line_to(line.end.x, line.end.y);
-                                    }
-                                }
-                            }
-                        },
-                    geo::Geometry::GeometryCollection(geometry_collection) => {
-                        println!("GeometryCollection: {:?}", geometry_collection);
-                    },
-                    geo::Geometry::Rect(rect) => {
-                        println!("Rect: {:?}", rect);
-                    },
-                    geo::Geometry::Triangle(triangle) => {
-                        println!("Triangle: {:?}", triangle);
-                    },
-                }
 
-                if title == "building" {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0xff,
-                            g: 0xff,
-                            b: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title == "park" {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0x00,
-                            g: 0xff,
-                            b: 0x00,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title == ("water") {
-                    let path = pb.finish();
-                    dt.fill(
-                        &path,
-                        // For some reason red and blue are swapped
-                    &Source::Solid(SolidSource {
-                            b: 0x00,
-                            g: 0x00,
-                            r: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            blend_mode: raqote::BlendMode::SrcOver,
-                            alpha: 0.5,
-                        },
-                    );
-                } else if title.contains("water") || title.contains("mountian") || title.contains("land") {
+    let layers = decode_layers(&tile, size)?;
 
-                }
-                else {
-                    let path = pb.finish();
+    let pixel_ratio = size as f32 / STYLE_TILE_SIZE;
+    let zoom_level = zoom;
+    let zoom = zoom as f32;
+    let no_properties = HashMap::new();
+    let mut label_candidates = Vec::new();
+    for (layer_index, style_layer) in style.layers.iter().enumerate().filter(|(_, layer)| layer.visible_at(zoom)) {
+        if let LayerKind::Background { color, opacity } = &style_layer.kind {
+            let context = EvalContext { zoom, geometry_type: "Polygon", properties: &no_properties };
+            dt.fill_rect(0.0, 0.0, size as f32, size as f32, &Source::Solid(color.evaluate(&context).to_source(opacity.evaluate(&context))), &DrawOptions::new());
+            continue;
+        }
 
+        let Some((scale, features)) = style_layer.source_layer.as_ref().and_then(|name| layers.get(name)) else {
+            continue;
+        };
+        for (feature, properties) in features {
+            let context = EvalContext { zoom, geometry_type: geometry_type(&feature.geometry), properties };
+            if !style_layer.matches(&context) {
+                continue;
+            }
+            if let LayerKind::Symbol { .. } = style_layer.kind {
+                label_candidates.extend(feature_labels(&style_layer.kind, layer_index, &feature.geometry, *scale, pixel_ratio, &context));
+            } else {
+                draw_feature(&mut dt, &style_layer.kind, &feature.geometry, *scale, pixel_ratio, &context);
+            }
+        }
+    }
+
+    // Labels go on top of everything else
+    labels.draw_labels(&mut dt, label_candidates, crate::tile::Tile::new(x as i32, y as i32, zoom_level));
+
+    Ok(draw_target_to_rgba(&dt))
+}
+
+fn draw_feature(dt: &mut DrawTarget, kind: &LayerKind, geometry: &geo::Geometry<f32>, scale: f32, pixel_ratio: f32, context: &EvalContext) {
+    let options = DrawOptions {
+        antialias: AntialiasMode::Gray,
+        blend_mode: raqote::BlendMode::SrcOver,
+        alpha: 1.0,
+    };
+
+    match kind {
+        LayerKind::Fill { color, opacity, outline_color } => {
+            if context.geometry_type != "Polygon" {
+                return;
+            }
+            let path = geometry_to_path(geometry, scale);
+            dt.fill(&path, &Source::Solid(color.evaluate(context).to_source(opacity.evaluate(context))), &options);
+            if let Some(outline_color) = outline_color {
+                let stroke_style = StrokeStyle {
+                    width: 1.0,
+                    ..Default::default()
+                };
+                dt.stroke(&path, &Source::Solid(outline_color.evaluate(context).to_source(opacity.evaluate(context))), &stroke_style, &options);
+            }
+        }
+        LayerKind::Line { color, width, opacity, dash_array, cap, join } => {
+            if context.geometry_type == "Point" {
+                return;
+            }
+            let width = width.evaluate(context) * pixel_ratio;
+            let stroke_style = StrokeStyle {
+                cap: *cap,
+                join: *join,
+                width,
+                miter_limit: 10.0,
+                // Dashes are given in line widths
+                dash_array: dash_array.evaluate(context).iter().map(|dash| dash * width).collect(),
+                dash_offset: 0.0,
+            };
+            let path = geometry_to_path(geometry, scale);
+            dt.stroke(&path, &Source::Solid(color.evaluate(context).to_source(opacity.evaluate(context))), &stroke_style, &options);
+        }
+        LayerKind::Circle { color, radius, opacity, stroke_color, stroke_width } => {
+            let points: Vec<geo::Point<f32>> = match geometry {
+                geo::Geometry::Point(point) => vec![*point],
+                geo::Geometry::MultiPoint(multi_point) => multi_point.0.clone(),
+                _ => return,
+            };
+            let radius = radius.evaluate(context) * pixel_ratio;
+            let stroke_width = stroke_width.evaluate(context) * pixel_ratio;
+            let opacity = opacity.evaluate(context);
+            for point in points {
+                let mut pb = PathBuilder::new();
+                pb.arc(point.x() * scale, point.y() * scale, radius, 0.0, 2.0 * std::f32::consts::PI);
+                let path = pb.finish();
+                dt.fill(&path, &Source::Solid(color.evaluate(context).to_source(opacity)), &options);
+                if stroke_width > 0.0 {
                     let stroke_style = StrokeStyle {
-                        cap: raqote::LineCap::Round,
-                        join: raqote::LineJoin::Round,
-                        width: 10.,
-                        miter_limit: 10.0,
-                        dash_array: vec![],
-                        dash_offset: 0.0,
+                        width: stroke_width,
+                        ..Default::default()
                     };
-                
-                    dt.stroke(
-                        &path,
-                    &Source::Solid(SolidSource {
-                            r: 0xff,
-                            g: 0xff,
-                            b: 0xff,
-                            a: 0xff,
-                        }),        
-                        
-                        &stroke_style,
-                        &DrawOptions {
-                            antialias: AntialiasMode::Gray,
-                            ..Default::default()
-                        },
-                    );
+                    dt.stroke(&path, &Source::Solid(stroke_color.evaluate(context).to_source(opacity)), &stroke_style, &options);
                 }
             }
         }
+        LayerKind::Background { .. } | LayerKind::Symbol { .. } => {}
     }
+}
+
+/// Works out where a feature's labels would go and what they look like, points are labeled where they are,
+/// polygons in their middle and lines along them.
+fn feature_labels(kind: &LayerKind, layer_index: usize, geometry: &geo::Geometry<f32>, scale: f32, pixel_ratio: f32, context: &EvalContext) -> Vec<LabelCandidate> {
+    let LayerKind::Symbol { text_field, text_size, text_transform, text_padding, sort_key, placement, spacing, max_angle, color, opacity, halo_color, halo_width } = kind else {
+        return Vec::new();
+    };
+    let anchors = match (placement, geometry) {
+        (SymbolPlacement::Point, geo::Geometry::Point(point)) => vec![LabelAnchor::Point(point.x() * scale, point.y() * scale)],
+        (SymbolPlacement::Point, geo::Geometry::MultiPoint(multi_point)) => multi_point.iter().map(|point| LabelAnchor::Point(point.x() * scale, point.y() * scale)).collect(),
+        (SymbolPlacement::Point, geo::Geometry::Polygon(polygon)) => polygon.centroid().map(|point| LabelAnchor::Point(point.x() * scale, point.y() * scale)).into_iter().collect(),
+        (SymbolPlacement::Point, geo::Geometry::MultiPolygon(multi_polygon)) => multi_polygon.centroid().map(|point| LabelAnchor::Point(point.x() * scale, point.y() * scale)).into_iter().collect(),
+        (SymbolPlacement::Line, geo::Geometry::LineString(line_string)) => vec![line_anchor(line_string, scale, *spacing * pixel_ratio, *max_angle)],
+        (SymbolPlacement::Line, geo::Geometry::MultiLineString(multi_line_string)) => multi_line_string.iter().map(|line_string| line_anchor(line_string, scale, *spacing * pixel_ratio, *max_angle)).collect(),
+        _ => return Vec::new(),
+    };
+    let Some(text) = format_text_field(text_field, text_transform.as_deref(), context) else {
+        return Vec::new();
+    };
+    let opacity = opacity.evaluate(context);
 
-    dt.get_data_u8().to_vec()
-}
\ No newline at end of file
+    anchors.into_iter().map(|anchor| LabelCandidate {
+        text: text.clone(),
+        anchor,
+        size: text_size.evaluate(context) * pixel_ratio,
+        padding: text_padding * pixel_ratio,
+        paint: TextPaint {
+            color: color.evaluate(context).to_source(opacity),
+            halo_color: halo_color.evaluate(context).to_source(opacity),
+            halo_width: halo_width.evaluate(context) * pixel_ratio,
+        },
+        priority: (
+            sort_key.as_ref().map_or(0.0, |sort_key| sort_key.evaluate(context)),
+            layer_index,
+            context.properties.get("rank").and_then(|rank| rank.as_f64()).unwrap_or(f64::MAX) as f32,
+        ),
+    }).collect()
+}
+
+fn line_anchor(line_string: &geo::LineString<f32>, scale: f32, spacing: f32, max_angle: f32) -> LabelAnchor {
+    LabelAnchor::Line {
+        points: line_string.0.iter().map(|point| (point.x * scale, point.y * scale)).collect(),
+        spacing,
+        max_angle: max_angle.to_radians(),
+    }
+}
+
+/// Traces the geometry, scaled from tile units into pixels.
+fn geometry_to_path(geometry: &geo::Geometry<f32>, scale: f32) -> RaqotePath {
+    let mut pb: PathBuilder = PathBuilder::new();
+    match geometry {
+        geo::Geometry::Point(point) 
+            => {
+                pb.move_to(point.x() * scale, point.y() * scale);
+            },
+        geo::Geometry::Line(line) 
+            => {
+                pb.move_to(line.start.x * scale, line.start.y * scale);
+                pb.line_to(line.end.x * scale, line.end.y * scale);
+            },
+        geo::Geometry::LineString(line_string) 
+            => {
+                trace_line_string(&mut pb, line_string, scale);
+            },
+        geo::Geometry::Polygon(polygon) 
+            => {
+                trace_polygon(&mut pb, polygon, scale);
+            },
+        geo::Geometry::MultiPolygon(multi_polygon)
+            => {
+                for polygon in multi_polygon {
+                    trace_polygon(&mut pb, polygon, scale);
+                }
+            },
+        geo::Geometry::MultiPoint(multi_point) 
+            => {
+                for point in multi_point {
+                    pb.move_to(point.x() * scale, point.y() * scale);
+                }
+            },
+        geo::Geometry::MultiLineString(multi_line_string) 
+            => {
+                for line_string in multi_line_string {
+                    trace_line_string(&mut pb, line_string, scale);
+                }
+            },
+        geo::Geometry::GeometryCollection(geometry_collection) => {
+            debug!("Skipping a GeometryCollection: {:?}", geometry_collection);
+        },
+        geo::Geometry::Rect(rect) => {
+            trace_polygon(&mut pb, &rect.to_polygon(), scale);
+        },
+        geo::Geometry::Triangle(triangle) => {
+            trace_polygon(&mut pb, &triangle.to_polygon(), scale);
+        },
+    }
+    let mut path = pb.finish();
+    // Polygons which overlap in a multipolygon should both be filled, so even-odd can't be used
+    path.winding = Winding::NonZero;
+    path
+}
+
+/// Traces the outside of the polygon and then its holes. Tiles are meant to wind the holes the other way
+/// round to the outside, but we don't trust that and wind them ourselves, otherwise non-zero filling would fill the holes in.
+fn trace_polygon(pb: &mut PathBuilder, polygon: &geo::Polygon<f32>, scale: f32) {
+    let exterior = polygon.exterior();
+    trace_ring(pb, exterior, scale, ring_area(exterior) < 0.0);
+    for interior in polygon.interiors() {
+        trace_ring(pb, interior, scale, ring_area(interior) > 0.0);
+    }
+}
+
+fn trace_ring(pb: &mut PathBuilder, ring: &geo::LineString<f32>, scale: f32, reverse: bool) {
+    let mut points: Vec<&geo::Coord<f32>> = ring.0.iter().collect();
+    // Rings repeat the first point at the end, close() draws that edge for us
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    if points.len() < 3 {
+        return;
+    }
+    if reverse {
+        points.reverse();
+    }
+    pb.move_to(points[0].x * scale, points[0].y * scale);
+    for point in &points[1..] {
+        pb.line_to(point.x * scale, point.y * scale);
+    }
+    pb.close();
+}
+
+/// The shoelace formula, positive for rings which go clockwise on screen (y pointing down) like the outside of a polygon should.
+pub fn ring_area(ring: &geo::LineString<f32>) -> f32 {
+    let points = &ring.0;
+    (0..points.len()).map(|i| {
+        let (a, b) = (points[i], points[(i + 1) % points.len()]);
+        a.x * b.y - b.x * a.y
+    }).sum::<f32>() / 2.0
+}
+
+fn trace_line_string(pb: &mut PathBuilder, line_string: &geo::LineString<f32>, scale: f32) {
+    for (j, point) in line_string.0.iter().enumerate() {
+        if j == 0 {
+            pb.move_to(point.x * scale, point.y * scale);
+        } else {
+            pb.line_to(point.x * scale, point.y * scale);
+        }
+    }
+}
+
+/// raqote stores pixels as premultiplied ARGB, which is why the colours used to come out with red and blue swapped,
+/// so turn it into the straight RGBA bevy expects.
+pub fn draw_target_to_rgba(dt: &DrawTarget) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(dt.get_data().len() * 4);
+    for pixel in dt.get_data() {
+        let a = (pixel >> 24) & 0xff;
+        let unpremultiply = |channel: u32| (channel * 255 + a / 2).checked_div(a).map_or(0, |v| v.min(255) as u8);
+        rgba.push(unpremultiply((pixel >> 16) & 0xff));
+        rgba.push(unpremultiply((pixel >> 8) & 0xff));
+        rgba.push(unpremultiply(pixel & 0xff));
+        rgba.push(a as u8);
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{args::Args, labels::labels_from_args, style::{style_from_args, STYLE_FLAGS}};
+    use crate::test_support::{encode_mvt, square, square_hole, TestFeature, TestGeometry};
+
+    const SIZE: u32 = 256;
+
+    const TEST_STYLE: &str = r##"{
+        "version": 8,
+        "layers": [
+            { "id": "water", "type": "fill", "source-layer": "water", "paint": { "fill-color": "#0000ff" } },
+            { "id": "building", "type": "fill", "source-layer": "building", "paint": { "fill-color": "#ff0000" } },
+            { "id": "outline", "type": "line", "source-layer": "outline", "paint": { "line-color": "#00ff00", "line-width": 8 } }
+        ]
+    }"##;
+
+    fn render(layers: &[(&str, Vec<TestFeature>)]) -> Vec<u8> {
+        let style = Style::from_json(TEST_STYLE).unwrap();
+        let labels = labels_from_args(&Args::default()).unwrap();
+        ofm_to_data_image(encode_mvt(layers), SIZE, 14, 0, 0, &style, &labels).unwrap()
+    }
+
+    #[test]
+    fn tile_borders_are_only_drawn_when_asked_for() {
+        let labels = labels_from_args(&Args::default()).unwrap();
+        let args = Args::parse(&["--debug-borders".to_string()], &[STYLE_FLAGS]).unwrap();
+        let with_borders = style_from_args(&args).unwrap();
+        let without = style_from_args(&Args::default()).unwrap();
+        let tile = || encode_mvt(&[("water", vec![])]);
+
+        let rgba = decode_tile_data(TileKind::Vector, tile(), &without, &labels, 0, 0, 14, SIZE).unwrap();
+        assert!(rgba.chunks(4).all(|pixel| pixel == [0, 0, 0, 0]));
+        let rgba = decode_tile_data(TileKind::Vector, tile(), &with_borders, &labels, 0, 0, 14, SIZE).unwrap();
+        assert!(pixel(&rgba, 2, 0)[3] > 0);
+    }
+
+    fn pixel(rgba: &[u8], x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * SIZE + x) * 4) as usize;
+        rgba[i..i + 4].try_into().unwrap()
+    }
+
+    /// Compares against `tests/golden/<name>.png`. Run with `UPDATE_GOLDEN=1` to write the images again
+    /// after changing how things are drawn, then check them and commit them.
+    fn assert_matches_golden(name: &str, rgba: &[u8]) {
+        let path = format!("{}/tests/golden/{}.png", env!("CARGO_MANIFEST_DIR"), name);
+        let actual = image::RgbaImage::from_raw(SIZE, SIZE, rgba.to_vec()).unwrap();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            actual.save(&path).unwrap();
+            eprintln!("Wrote golden image {}", path);
+            return;
+        }
+        match image::open(&path) {
+            Ok(golden) => {
+                let golden = golden.to_rgba8();
+                assert_eq!(golden.dimensions(), actual.dimensions());
+                // Allow for tiny antialiasing differences
+                let different = golden.pixels().zip(actual.pixels())
+                    .filter(|(a, b)| a.0.iter().zip(b.0.iter()).any(|(a, b)| a.abs_diff(*b) > 2))
+                    .count();
+                assert!(different <= 16, "{} pixels differ from {}", different, path);
+            }
+            Err(e) => panic!("Couldn't open golden image {} ({}), run with UPDATE_GOLDEN=1 to make it", path, e),
+        }
+    }
+
+    #[test]
+    fn lake_with_island_leaves_the_island_empty() {
+        let rgba = render(&[("water", vec![
+            TestFeature::new(TestGeometry::Polygon(vec![square(512, 3584), square_hole(1536, 2560)])),
+        ])]);
+
+        assert_eq!(pixel(&rgba, 64, 64), [0, 0, 255, 255]);
+        assert_eq!(pixel(&rgba, 128, 128), [0, 0, 0, 0]);
+        assert_eq!(pixel(&rgba, 8, 8), [0, 0, 0, 0]);
+        assert_matches_golden("lake_with_island", &rgba);
+    }
+
+    #[test]
+    fn hole_wound_the_wrong_way_is_still_a_hole() {
+        // A tile can't give us this as the reader would start a new polygon, so build it by hand
+        let ring = |min: f32, max: f32| geo::LineString::from(vec![(min, min), (max, min), (max, max), (min, max), (min, min)]);
+        let polygon = geo::Polygon::new(ring(32.0, 224.0), vec![ring(96.0, 160.0)]);
+        let mut dt = DrawTarget::new(SIZE as i32, SIZE as i32);
+        let path = geometry_to_path(&geo::Geometry::Polygon(polygon), 1.0);
+        dt.fill(&path, &Source::Solid(SolidSource { r: 0, g: 0, b: 0xff, a: 0xff }), &DrawOptions::new());
+
+        let alpha = |x: u32, y: u32| dt.get_data()[(y * SIZE + x) as usize] >> 24;
+        assert_eq!(alpha(64, 64), 0xff);
+        assert_eq!(alpha(128, 128), 0);
+    }
+
+    #[test]
+    fn multipolygon_buildings_with_courtyard() {
+        let rgba = render(&[("building", vec![
+            TestFeature::new(TestGeometry::Polygon(vec![square(256, 1280), square_hole(512, 1024), square(2048, 3840)])),
+        ])]);
+
+        // Around the courtyard
+        assert_eq!(pixel(&rgba, 20, 20), [255, 0, 0, 255]);
+        // In the courtyard
+        assert_eq!(pixel(&rgba, 48, 48), [0, 0, 0, 0]);
+        // The second building
+        assert_eq!(pixel(&rgba, 200, 200), [255, 0, 0, 255]);
+        // Between them
+        assert_eq!(pixel(&rgba, 100, 100), [0, 0, 0, 0]);
+        assert_matches_golden("buildings_with_courtyard", &rgba);
+    }
+
+    #[test]
+    fn polygon_outline_is_closed() {
+        let rgba = render(&[("outline", vec![
+            TestFeature::new(TestGeometry::Polygon(vec![square(1024, 3072)])),
+        ])]);
+
+        // The last edge, which goes from the bottom left corner back up to the start
+        assert_eq!(pixel(&rgba, 64, 128), [0, 255, 0, 255]);
+        // The other edges
+        assert_eq!(pixel(&rgba, 128, 64), [0, 255, 0, 255]);
+        assert_eq!(pixel(&rgba, 192, 128), [0, 255, 0, 255]);
+        // The middle isn't filled
+        assert_eq!(pixel(&rgba, 128, 128), [0, 0, 0, 0]);
+        assert_matches_golden("closed_outline", &rgba);
+    }
+}
@@ -0,0 +1,477 @@
+use std::{collections::HashMap, fs::File, io::{self, Read, Seek, SeekFrom}, path::Path, sync::{Arc, Mutex}};
+
+use flate2::read::GzDecoder;
+
+use crate::{http::FetchError, tile_source::{TileKind, TileSource}};
+
+const HEADER_LENGTH: usize = 127;
+// The spec says that you should never need to go deeper than this
+const MAX_DIRECTORY_DEPTH: usize = 4;
+
+/// How parts of the archive are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl From<u8> for Compression {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+}
+
+/// The fixed size header at the start of every PMTiles v3 archive.
+/// https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub root_directory_offset: u64,
+    pub root_directory_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_directories_offset: u64,
+    pub leaf_directories_length: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_length: u64,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..7] != b"PMTiles" {
+            return Err(invalid_data("Not a PMTiles archive"));
+        }
+        if bytes[7] != 3 {
+            return Err(invalid_data(&format!("Unsupported PMTiles version {}", bytes[7])));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        Ok(Self {
+            root_directory_offset: u64_at(8),
+            root_directory_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_directories_offset: u64_at(40),
+            leaf_directories_length: u64_at(48),
+            tile_data_offset: u64_at(56),
+            tile_data_length: u64_at(64),
+            internal_compression: bytes[97].into(),
+            tile_compression: bytes[98].into(),
+            tile_type: bytes[99],
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+        })
+    }
+}
+
+/// A directory entry, if `run_length` is 0 the entry points at a leaf directory rather than tile data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    pub run_length: u32,
+}
+
+/// Reads tiles out of a local PMTiles v3 archive.
+pub struct PmTilesSource {
+    pub name: String,
+    pub kind: TileKind,
+    pub max_zoom: u32,
+    pub attribution: String,
+    header: Header,
+    root_directory: Arc<Vec<Entry>>,
+    leaf_directories: Mutex<HashMap<u64, Arc<Vec<Entry>>>>,
+    file: Mutex<File>,
+}
+
+impl PmTilesSource {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact(&mut header)?;
+        let header = Header::parse(&header)?;
+
+        let kind = match header.tile_type {
+            1 => TileKind::Vector,
+            2..=5 => TileKind::Raster,
+            tile_type => return Err(invalid_data(&format!("Unknown tile type {}", tile_type))),
+        };
+
+        let root_directory = read_range(&mut file, header.root_directory_offset, header.root_directory_length)?;
+        let root_directory = Arc::new(parse_directory(&decompress(root_directory, header.internal_compression)?)?);
+
+        let metadata = read_range(&mut file, header.metadata_offset, header.metadata_length)?;
+        let metadata: serde_json::Value = serde_json::from_slice(&decompress(metadata, header.internal_compression)?).unwrap_or_default();
+        let name = metadata["name"].as_str().map(|name| name.to_string()).unwrap_or_else(|| {
+            path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+        });
+        let attribution = metadata["attribution"].as_str().unwrap_or_default().to_string();
+
+        Ok(Self {
+            name,
+            kind,
+            max_zoom: header.max_zoom as u32,
+            attribution,
+            header,
+            root_directory,
+            leaf_directories: Mutex::new(HashMap::new()),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn read(&self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        read_range(&mut self.file.lock().unwrap(), offset, length)
+    }
+
+    fn leaf_directory(&self, offset: u64, length: u64) -> io::Result<Arc<Vec<Entry>>> {
+        if let Some(directory) = self.leaf_directories.lock().unwrap().get(&offset) {
+            return Ok(directory.clone());
+        }
+        let bytes = self.read(self.header.leaf_directories_offset.saturating_add(offset), length)?;
+        let directory = Arc::new(parse_directory(&decompress(bytes, self.header.internal_compression)?)?);
+        self.leaf_directories.lock().unwrap().insert(offset, directory.clone());
+        Ok(directory)
+    }
+
+    /// Walks down the directories to find the tile, returns `None` if the archive doesn't have it.
+    pub fn read_tile(&self, x: u64, y: u64, zoom: u64) -> io::Result<Option<Vec<u8>>> {
+        // Tile ids only go up to zoom 31, and tiles past the edge of the map don't exist
+        if zoom > 31 || x >= 1 << zoom || y >= 1 << zoom {
+            return Ok(None);
+        }
+        let tile_id = zxy_to_tile_id(zoom as u8, x, y);
+
+        let mut directory = self.root_directory.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(entry) = find_tile(&directory, tile_id) else {
+                return Ok(None);
+            };
+            if entry.run_length == 0 {
+                directory = self.leaf_directory(entry.offset, entry.length as u64)?;
+            } else {
+                let tile = self.read(self.header.tile_data_offset.saturating_add(entry.offset), entry.length as u64)?;
+                return decompress(tile, self.header.tile_compression).map(Some);
+            }
+        }
+        Err(invalid_data("Too many leaf directories"))
+    }
+}
+
+impl TileSource for PmTilesSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> TileKind {
+        self.kind
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn is_cached(&self, _x: u64, _y: u64, _zoom: u64) -> bool {
+        // Everything is already on disk
+        true
+    }
+
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, FetchError> {
+        match self.read_tile(x, y, zoom) {
+            Ok(data) => Ok(data.unwrap_or_default()),
+            Err(e) => Err(FetchError::Io(e.to_string())),
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_range(file: &mut File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    // Check before allocating, a broken header could ask for far more than there is
+    let file_length = file.metadata()?.len();
+    if offset.checked_add(length).is_none_or(|end| end > file_length) {
+        return Err(invalid_data(&format!("{} bytes at {} goes past the end of the {} byte archive", length, offset, file_length)));
+    }
+    let mut bytes = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn decompress(bytes: Vec<u8>, compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None | Compression::Unknown => Ok(bytes),
+        Compression::Gzip => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(bytes.as_slice()).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        compression => Err(io::Error::new(io::ErrorKind::Unsupported, format!("{:?} compression is not supported", compression))),
+    }
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> io::Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*position).ok_or_else(|| invalid_data("Directory ended early"))?;
+        *position += 1;
+        if shift >= 64 {
+            return Err(invalid_data("Varint is too long"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Directories are stored column by column, tile ids are delta encoded and an offset of 0 means
+/// the tile directly follows the previous one.
+pub fn parse_directory(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let mut position = 0;
+    let count = read_varint(bytes, &mut position)? as usize;
+    // Every entry takes at least four bytes
+    if count > bytes.len() / 4 {
+        return Err(invalid_data("Directory has more entries than it has room for"));
+    }
+    let mut entries = vec![Entry { tile_id: 0, offset: 0, length: 0, run_length: 0 }; count];
+
+    let mut last_id = 0;
+    for entry in entries.iter_mut() {
+        last_id += read_varint(bytes, &mut position)?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(bytes, &mut position)? as u32;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(bytes, &mut position)? as u32;
+    }
+    for i in 0..count {
+        let offset = read_varint(bytes, &mut position)?;
+        entries[i].offset = if offset == 0 && i > 0 {
+            entries[i - 1].offset + entries[i - 1].length as u64
+        } else {
+            offset.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+/// Finds the entry which covers the tile, either because the tile falls in its run or because it's the leaf directory the tile would be in.
+fn find_tile(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let index = entries.partition_point(|entry| entry.tile_id <= tile_id).checked_sub(1)?;
+    let entry = entries[index];
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length as u64 {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Tiles are numbered zoom level by zoom level, and along a hilbert curve within each level.
+pub fn zxy_to_tile_id(zoom: u8, x: u64, y: u64) -> u64 {
+    // The number of tiles in all of the zoom levels before this one
+    let base = ((1u64 << (2 * zoom as u64)) - 1) / 3;
+    let n = 1u64 << zoom;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u64;
+        let ry = ((y & s) > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    base + d
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::test_support::{test_dir, write_varint};
+
+    fn entry(tile_id: u64, offset: u64, length: u32, run_length: u32) -> Entry {
+        Entry { tile_id, offset, length, run_length }
+    }
+
+    /// The simplest encoding, every offset is written out rather than using 0 for "straight after the last one".
+    fn encode_directory(entries: &[Entry]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, entries.len() as u64);
+        let mut last_id = 0;
+        for entry in entries {
+            write_varint(&mut bytes, entry.tile_id - last_id);
+            last_id = entry.tile_id;
+        }
+        for entry in entries {
+            write_varint(&mut bytes, entry.run_length as u64);
+        }
+        for entry in entries {
+            write_varint(&mut bytes, entry.length as u64);
+        }
+        for entry in entries {
+            write_varint(&mut bytes, entry.offset + 1);
+        }
+        bytes
+    }
+
+    /// An uncompressed vector archive, laid out as header, root directory, metadata, leaf directories then tile data.
+    fn write_archive(name: &str, root: &[Entry], leaves: &[u8], tiles: &[u8]) -> PathBuf {
+        let root = encode_directory(root);
+        let metadata = br#"{"name": "test"}"#;
+        let mut header = vec![0; HEADER_LENGTH];
+        header[0..7].copy_from_slice(b"PMTiles");
+        header[7] = 3;
+        let sections = [root.as_slice(), metadata, leaves, tiles];
+        let mut offset = HEADER_LENGTH as u64;
+        for (i, section) in sections.iter().enumerate() {
+            header[8 + i * 16..16 + i * 16].copy_from_slice(&offset.to_le_bytes());
+            header[16 + i * 16..24 + i * 16].copy_from_slice(&(section.len() as u64).to_le_bytes());
+            offset += section.len() as u64;
+        }
+        header[97] = 1;
+        header[98] = 1;
+        header[99] = 1;
+        header[101] = 14;
+
+        let path = test_dir(name).join("test.pmtiles");
+        fs::write(&path, [header, sections.concat()].concat()).unwrap();
+        path
+    }
+
+    #[test]
+    fn varints() {
+        let read = |bytes: &[u8]| read_varint(bytes, &mut 0);
+        assert_eq!(read(&[0x01]).unwrap(), 1);
+        assert_eq!(read(&[0xac, 0x02]).unwrap(), 300);
+        assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap(), u64::MAX);
+        assert!(read(&[0x80]).is_err());
+        assert!(read(&[0xff; 11]).is_err());
+
+        let mut position = 0;
+        let bytes = [0xac, 0x02, 0x05];
+        assert_eq!(read_varint(&bytes, &mut position).unwrap(), 300);
+        assert_eq!(read_varint(&bytes, &mut position).unwrap(), 5);
+        assert_eq!(position, 3);
+    }
+
+    #[test]
+    fn directories_are_delta_encoded() {
+        let mut bytes = Vec::new();
+        // Three entries, with tile ids 5, 6 and 10
+        for value in [3, 5, 1, 4] {
+            write_varint(&mut bytes, value);
+        }
+        // Run lengths, the last one is a leaf directory
+        for value in [1, 2, 0] {
+            write_varint(&mut bytes, value);
+        }
+        // Lengths
+        for value in [100, 50, 30] {
+            write_varint(&mut bytes, value);
+        }
+        // Offsets are one more than they are, apart from 0 which means straight after the last one
+        for value in [1, 0, 201] {
+            write_varint(&mut bytes, value);
+        }
+
+        assert_eq!(parse_directory(&bytes).unwrap(), vec![entry(5, 0, 100, 1), entry(6, 100, 50, 2), entry(10, 200, 30, 0)]);
+    }
+
+    #[test]
+    fn broken_directories_are_an_error() {
+        // Says it has a huge number of entries
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, u32::MAX as u64);
+        assert!(parse_directory(&bytes).is_err());
+        // Stops half way through
+        let bytes = encode_directory(&[entry(5, 0, 100, 1), entry(6, 100, 50, 2)]);
+        assert!(parse_directory(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn tile_ids_go_along_a_hilbert_curve() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(2, 3, 0), 20);
+    }
+
+    #[test]
+    fn tiles_are_found_in_runs_and_leaf_directories() {
+        let directory = [entry(5, 0, 100, 1), entry(6, 100, 50, 3), entry(20, 150, 30, 0)];
+        assert_eq!(find_tile(&directory, 4), None);
+        assert_eq!(find_tile(&directory, 5), Some(directory[0]));
+        assert_eq!(find_tile(&directory, 8), Some(directory[1]));
+        assert_eq!(find_tile(&directory, 9), None);
+        // Everything from a leaf directory's id onwards might be in it
+        assert_eq!(find_tile(&directory, 20), Some(directory[2]));
+        assert_eq!(find_tile(&directory, 1000), Some(directory[2]));
+    }
+
+    #[test]
+    fn tiles_are_read_through_leaf_directories() {
+        // The root only points at a leaf, which has 0/0/0 and 2/0/0
+        let tiles = b"zoom0zoom2";
+        let leaf = encode_directory(&[entry(0, 0, 5, 1), entry(5, 5, 5, 1)]);
+        let path = write_archive("pmtiles-leaves", &[entry(0, 0, leaf.len() as u32, 0)], &leaf, tiles);
+        let source = PmTilesSource::open(&path).unwrap();
+
+        assert_eq!(source.name, "test");
+        assert_eq!(source.kind, TileKind::Vector);
+        assert_eq!(source.read_tile(0, 0, 0).unwrap(), Some(b"zoom0".to_vec()));
+        assert_eq!(source.read_tile(0, 0, 2).unwrap(), Some(b"zoom2".to_vec()));
+        assert_eq!(source.read_tile(1, 0, 1).unwrap(), None);
+        assert_eq!(source.read_tile(4, 0, 2).unwrap(), None);
+        assert_eq!(source.read_tile(0, 0, 40).unwrap(), None);
+    }
+
+    #[test]
+    fn ranges_past_the_end_of_the_file_are_an_error() {
+        let tiles = b"zoom0";
+        let path = write_archive("pmtiles-too-long", &[entry(0, 0, 5, 1)], &[], tiles);
+        let source = PmTilesSource::open(&path).unwrap();
+        assert!(source.read(0, 1 << 40).is_err());
+        assert!(source.read(u64::MAX, 2).is_err());
+
+        // A header which says the root directory is huge
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(PmTilesSource::open(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{path::PathBuf, thread};
+
+use raqote::{DrawOptions, DrawTarget, PathBuilder, SolidSource, Source, StrokeStyle};
+
+use crate::{args::{Args, Flag}, http::HTTP_FLAGS, labels::{labels_from_args, LabelRenderer, TextPaint, LABEL_FLAGS}, ofm_api::{draw_target_to_rgba, get_tile_data}, style::{style_from_args, Style, STYLE_FLAGS}, tile::{nearest_copy, parse_bbox, parse_zoom, LatLon}, tile_source::{tile_source_from_args, TileSource, TILE_SOURCE_FLAGS}, TILE_QUALITY};
+
+// How many tiles are fetched at once, tile servers don't like being asked for lots at the same time
+const PARALLEL_FETCHES: usize = 4;
+// Same as the window's clear colour, as premultiplied argb
+const BACKGROUND: u32 = 0xff1a1a1a;
+
+/// What to draw, `bevy-ofm-viewer render --output <file.png> (--center <lat>,<long> --zoom <zoom> | --bbox <west>,<south>,<east>,<north>)`
+/// with `--size <width>x<height>`, any number of `--marker <lat>,<long>`, `--path <lat>,<long>;<lat>,<long>;...` and `--no-attribution`,
+/// along with the usual `--source` and `--style` arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    pub center: LatLon,
+    pub zoom: u32,
+    pub width: u32,
+    pub height: u32,
+    pub output: PathBuf,
+    pub markers: Vec<LatLon>,
+    pub path: Vec<LatLon>,
+    pub attribution: bool,
+}
+
+pub const RENDER_FLAGS: &[Flag] = &[
+    Flag::value("--center"),
+    Flag::value("--zoom"),
+    Flag::value("--bbox"),
+    Flag::value("--size"),
+    Flag::value("--output"),
+    Flag::value("--marker"),
+    Flag::value("--path"),
+    Flag::switch("--no-attribution"),
+];
+
+pub fn render_options_from_args(args: &Args) -> Result<RenderOptions, String> {
+    let center = args.get("--center").map(|center| parse_coord(center).ok_or("--center needs <lat>,<long>")).transpose()?;
+    let zoom = args.get("--zoom").map(|zoom| parse_zoom(zoom, "--zoom")).transpose()?;
+    let bbox = args.get("--bbox").map(parse_bbox).transpose()?;
+    let (width, height) = match args.get("--size") {
+        Some(size) => {
+            let parsed = size.split_once('x').and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)));
+            match parsed {
+                Some((w, h)) if w > 0 && h > 0 => (w, h),
+                _ => return Err("--size needs <width>x<height>".to_string()),
+            }
+        }
+        None => (1024, 768),
+    };
+    let output = args.get("--output").map(PathBuf::from);
+    let markers = args.get_all("--marker").map(parse_coord).collect::<Option<_>>().ok_or("--marker needs <lat>,<long>")?;
+    let path = match args.get("--path") {
+        Some(path) => path.split(';').map(parse_coord).collect::<Option<_>>().ok_or("--path needs <lat>,<long>;<lat>,<long>;...")?,
+        None => Vec::new(),
+    };
+    let attribution = !args.has("--no-attribution");
+
+    let (center, zoom) = match (center, zoom, bbox) {
+        (Some(center), Some(zoom), _) => (center, zoom),
+        (None, None, Some((north_west, south_east))) => fit_bbox(north_west, south_east, width, height),
+        _ => return Err("render needs either --center and --zoom, or --bbox".to_string()),
+    };
+    Ok(RenderOptions {
+        center,
+        zoom,
+        width,
+        height,
+        output: output.ok_or("render needs an --output file")?,
+        markers,
+        path,
+        attribution,
+    })
+}
+
+fn parse_coord(coord: &str) -> Option<LatLon> {
+    let (lat, long) = coord.split_once(',')?;
+    Some(LatLon::new(lat.trim().parse().ok()?, long.trim().parse().ok()?))
+}
+
+/// Where a point is in pixels from the top left of the whole map at this zoom level.
+pub fn global_pixel(coord: LatLon, zoom: u32, tile_size: u32) -> (f64, f64) {
+    let tile_xy = coord.to_tile_xy(zoom);
+    (tile_xy.x * tile_size as f64, tile_xy.y * tile_size as f64)
+}
+
+/// The middle of the box, and the closest zoom level which still fits all of it in the image.
+fn fit_bbox(north_west: LatLon, south_east: LatLon, width: u32, height: u32) -> (LatLon, u32) {
+    let center = LatLon::new((north_west.lat + south_east.lat) / 2.0, (north_west.long + south_east.long) / 2.0);
+    let zoom = (0..=22).rev().find(|zoom| {
+        let (left, top) = global_pixel(north_west, *zoom, TILE_QUALITY as u32);
+        let (right, bottom) = global_pixel(south_east, *zoom, TILE_QUALITY as u32);
+        right - left <= width as f64 && bottom - top <= height as f64
+    }).unwrap_or(0);
+    (center, zoom)
+}
+
+/// Draws the map into rgba pixels, without needing a window or the gpu.
+pub fn render_map(source: &dyn TileSource, style: &Style, labels: &LabelRenderer, options: &RenderOptions) -> Result<Vec<u8>, String> {
+    if options.zoom > source.max_zoom() {
+        return Err(format!("{} only goes up to zoom {}", source.name(), source.max_zoom()));
+    }
+    let tile_size = TILE_QUALITY as u32;
+    let tiles_across = 1i64 << options.zoom;
+    let (center_x, center_y) = global_pixel(options.center, options.zoom, tile_size);
+    let (left, top) = (center_x - options.width as f64 / 2.0, center_y - options.height as f64 / 2.0);
+
+    let mut dt = DrawTarget::new(options.width as i32, options.height as i32);
+    dt.get_data_mut().fill(BACKGROUND);
+
+    // Every tile the image covers, the map repeats sideways but not past the top and bottom
+    let tile_range = |start: f64, length: u32| (start / tile_size as f64).floor() as i64..=((start + length as f64 - 1.0) / tile_size as f64).floor() as i64;
+    let tiles: Vec<(i64, i64)> = tile_range(top, options.height)
+        .filter(|y| (0..tiles_across).contains(y))
+        .flat_map(|y| tile_range(left, options.width).map(move |x| (x, y)))
+        .collect();
+
+    for batch in tiles.chunks(PARALLEL_FETCHES) {
+        let fetched: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = batch.iter().map(|(x, y)| {
+                let x = x.rem_euclid(tiles_across) as u64;
+                scope.spawn(move || get_tile_data(source, style, labels, x, *y as u64, options.zoom as u64, tile_size))
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().expect("Tile thread panicked")).collect()
+        });
+
+        for ((x, y), rgba) in batch.iter().zip(fetched) {
+            match rgba {
+                Ok(rgba) if !rgba.is_empty() => {
+                    let (offset_x, offset_y) = (*x as f64 * tile_size as f64 - left, *y as f64 * tile_size as f64 - top);
+                    blit(&mut dt, &rgba, tile_size, offset_x.round() as i64, offset_y.round() as i64);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Tile {}/{}/{} failed: {}", options.zoom, x.rem_euclid(tiles_across), y, e),
+            }
+        }
+    }
+
+    let (path, markers) = overlay_points(options, left, top, tile_size);
+    draw_overlays(&mut dt, &path, &markers);
+    if options.attribution && !source.attribution().is_empty() {
+        let layout = labels.layout(source.attribution(), 12.0);
+        let paint = TextPaint {
+            color: SolidSource { r: 0x20, g: 0x20, b: 0x20, a: 0xff },
+            halo_color: SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff },
+            halo_width: 2.0,
+        };
+        labels.draw_text(&mut dt, &layout, options.width as f32 - layout.width / 2.0 - 6.0, options.height as f32 - 10.0, &paint);
+    }
+
+    Ok(draw_target_to_rgba(&dt))
+}
+
+/// Copies a tile's pixels into the draw target, leaving out whatever falls outside of it.
+fn blit(dt: &mut DrawTarget, rgba: &[u8], tile_size: u32, offset_x: i64, offset_y: i64) {
+    let (width, height) = (dt.width() as i64, dt.height() as i64);
+    let data = dt.get_data_mut();
+    for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+        let (x, y) = (offset_x + (i as u32 % tile_size) as i64, offset_y + (i as u32 / tile_size) as i64);
+        if x < 0 || y < 0 || x >= width || y >= height {
+            continue;
+        }
+        // Draw targets hold premultiplied argb
+        let a = pixel[3] as u32;
+        let premultiply = |channel: u8| (channel as u32 * a + 127) / 255;
+        data[(y * width + x) as usize] = (a << 24) | (premultiply(pixel[0]) << 16) | (premultiply(pixel[1]) << 8) | premultiply(pixel[2]);
+    }
+}
+
+// x and y in the image, for the path or the markers
+type ImagePoints = Vec<(f32, f32)>;
+
+/// Where the path and the markers go in the image, on whichever copy of the world is closest to the middle.
+/// Each point of the path goes next to the one before, so a path over the antimeridian doesn't go the long way round.
+fn overlay_points(options: &RenderOptions, left: f64, top: f64, tile_size: u32) -> (ImagePoints, ImagePoints) {
+    let world_width = tile_size as f64 * 2f64.powi(options.zoom as i32);
+    let to_image = |coord: LatLon, near: f64| {
+        let (x, y) = global_pixel(coord, options.zoom, tile_size);
+        (nearest_copy(x - left, near, world_width), y - top)
+    };
+
+    let middle = options.width as f64 / 2.0;
+    let mut near = middle;
+    let path = options.path.iter().map(|coord| {
+        let (x, y) = to_image(*coord, near);
+        near = x;
+        (x as f32, y as f32)
+    }).collect();
+    let markers = options.markers.iter().map(|coord| {
+        let (x, y) = to_image(*coord, middle);
+        (x as f32, y as f32)
+    }).collect();
+    (path, markers)
+}
+
+fn draw_overlays(dt: &mut DrawTarget, path: &[(f32, f32)], markers: &[(f32, f32)]) {
+    let draw_options = DrawOptions::new();
+    if path.len() > 1 {
+        let mut pb = PathBuilder::new();
+        for (i, (x, y)) in path.iter().copied().enumerate() {
+            if i == 0 {
+                pb.move_to(x, y);
+            } else {
+                pb.line_to(x, y);
+            }
+        }
+        let style = StrokeStyle { width: 4.0, cap: raqote::LineCap::Round, join: raqote::LineJoin::Round, ..Default::default() };
+        dt.stroke(&pb.finish(), &Source::Solid(SolidSource { r: 0x20, g: 0x60, b: 0xe0, a: 0xff }), &style, &draw_options);
+    }
+
+    for (x, y) in markers.iter().copied() {
+        let mut pb = PathBuilder::new();
+        pb.arc(x, y, 7.0, 0.0, 2.0 * std::f32::consts::PI);
+        pb.close();
+        let circle = pb.finish();
+        dt.fill(&circle, &Source::Solid(SolidSource { r: 0xe0, g: 0x30, b: 0x30, a: 0xff }), &draw_options);
+        dt.stroke(&circle, &Source::Solid(SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff }), &StrokeStyle { width: 2.0, ..Default::default() }, &draw_options);
+    }
+}
+
+/// Runs the `render` subcommand.
+pub fn run_render(args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &[TILE_SOURCE_FLAGS, HTTP_FLAGS, STYLE_FLAGS, LABEL_FLAGS, RENDER_FLAGS])?;
+    let options = render_options_from_args(&args)?;
+    let source = tile_source_from_args(&args)?;
+    let style = style_from_args(&args)?;
+    let labels = labels_from_args(&args)?;
+
+    let rgba = render_map(source.as_ref(), &style, &labels, &options)?;
+    image::save_buffer(&options.output, &rgba, options.width, options.height, image::ColorType::Rgba8)
+        .map_err(|e| format!("Failed to write {}: {}", options.output.display(), e))?;
+    println!("Wrote {}", options.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::FetchError, test_support::solid_png, tile_source::TileKind};
+
+    fn args(args: &str) -> Args {
+        Args::parse(&args.split_whitespace().map(|arg| arg.to_string()).collect::<Vec<_>>(), &[RENDER_FLAGS]).unwrap()
+    }
+
+    /// Red and blue tiles like a chess board, so we can tell which tile ended up where.
+    struct CheckerSource;
+
+    impl TileSource for CheckerSource {
+        fn name(&self) -> &str {
+            "checker"
+        }
+
+        fn kind(&self) -> TileKind {
+            TileKind::Raster
+        }
+
+        fn max_zoom(&self) -> u32 {
+            19
+        }
+
+        fn attribution(&self) -> &str {
+            ""
+        }
+
+        fn get_tile(&self, x: u64, y: u64, _zoom: u64) -> Result<Vec<u8>, FetchError> {
+            Ok(solid_png(256, if (x + y).is_multiple_of(2) { [255, 0, 0, 255] } else { [0, 0, 255, 255] }))
+        }
+    }
+
+    fn pixel(rgba: &[u8], width: u32, x: u32, y: u32) -> &[u8] {
+        let i = ((y * width + x) * 4) as usize;
+        &rgba[i..i + 4]
+    }
+
+    #[test]
+    fn parses_the_center_or_the_bbox() {
+        let options = render_options_from_args(&args("--center 51.5,-0.1 --zoom 12 --size 800x600 --output map.png --marker 51.5,-0.1")).unwrap();
+        assert_eq!((options.center, options.zoom, options.width, options.height), (LatLon::new(51.5, -0.1), 12, 800, 600));
+        assert_eq!(options.markers, vec![LatLon::new(51.5, -0.1)]);
+
+        let options = render_options_from_args(&args("--bbox -0.2,51.4,0.0,51.6 --size 512x512 --output map.png")).unwrap();
+        assert_eq!(options.center, LatLon::new(51.5, -0.1));
+        // The box is about 466 pixels tall at zoom 11, so twice that at 12 wouldn't fit
+        assert_eq!(options.zoom, 11);
+
+        // Same checks on the box as seeding
+        assert!(render_options_from_args(&args("--bbox 0.0,51.4,-0.2,51.6 --output map.png")).is_err());
+        assert!(render_options_from_args(&args("--bbox -0.2,51.6,0.0,51.4 --output map.png")).is_err());
+        assert!(render_options_from_args(&args("--bbox -0.2,51.4,0.0 --output map.png")).is_err());
+        assert!(render_options_from_args(&args("--zoom 12 --output map.png")).is_err());
+        assert!(render_options_from_args(&args("--center 51.5,-0.1 --zoom 31 --output map.png")).is_err());
+        assert!(render_options_from_args(&args("--center 51.5,-0.1 --zoom 12")).is_err());
+    }
+
+    #[test]
+    fn tiles_go_around_the_center() {
+        let style = style_from_args(&Args::default()).unwrap();
+        let labels = labels_from_args(&Args::default()).unwrap();
+        let options = render_options_from_args(&args("--center 0,0 --zoom 1 --size 512x512 --output map.png")).unwrap();
+        let rgba = render_map(&CheckerSource, &style, &labels, &options).unwrap();
+
+        // The middle of the world is where the four zoom 1 tiles meet
+        assert_eq!(pixel(&rgba, 512, 10, 10), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&rgba, 512, 500, 10), &[0, 0, 255, 255]);
+        assert_eq!(pixel(&rgba, 512, 10, 500), &[0, 0, 255, 255]);
+        assert_eq!(pixel(&rgba, 512, 500, 500), &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn map_repeats_sideways_but_not_past_the_poles() {
+        let style = style_from_args(&Args::default()).unwrap();
+        let labels = labels_from_args(&Args::default()).unwrap();
+        let options = render_options_from_args(&args("--center 0,0 --zoom 0 --size 768x512 --output map.png")).unwrap();
+        let rgba = render_map(&CheckerSource, &style, &labels, &options).unwrap();
+
+        assert_eq!(pixel(&rgba, 768, 10, 256), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&rgba, 768, 384, 256), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&rgba, 768, 384, 10), &[0x1a, 0x1a, 0x1a, 255]);
+    }
+
+    #[test]
+    fn overlays_go_on_the_nearest_copy_of_the_world() {
+        // Looking at the antimeridian, with everything on the other side of it from the center
+        let options = render_options_from_args(&args("--center 0,179.99 --zoom 3 --size 256x256 --output map.png --no-attribution --marker 0,-179 --path 0,178.5;0,-178.5")).unwrap();
+        let (center_x, center_y) = global_pixel(options.center, options.zoom, 256);
+        let (path, markers) = overlay_points(&options, center_x - 128.0, center_y - 128.0, 256);
+
+        // The marker is just right of the middle rather than a whole world away
+        let close = |(x, y): (f32, f32), (to_x, to_y): (f32, f32)| (x - to_x).abs() < 0.1 && (y - to_y).abs() < 0.1;
+        assert!(close(markers[0], (133.75, 128.0)), "{:?}", markers);
+        // and the path goes straight across the middle
+        assert!(close(path[0], (119.5, 128.0)) && close(path[1], (136.6, 128.0)), "{:?}", path);
+    }
+}
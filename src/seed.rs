@@ -0,0 +1,245 @@
+use std::{io::Write, ops::RangeInclusive, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+
+use crate::{args::{Args, Flag}, http::HTTP_FLAGS, mbtiles::MbTilesWriter, tile::{parse_bbox, parse_zoom, LatLon, Tile}, tile_source::{tile_source_from_args, TileSource, TILE_SOURCE_FLAGS}};
+
+/// What to download, `bevy-ofm-viewer seed --bbox <west>,<south>,<east>,<north> --zooms 10-16 [--rate <tiles per second>] [--output <file.mbtiles>]`
+/// along with the usual `--source` arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedOptions {
+    pub north_west: LatLon,
+    pub south_east: LatLon,
+    pub zooms: RangeInclusive<u32>,
+    /// Most tile servers don't want to be hammered, so we only ask for this many tiles a second
+    pub rate: f64,
+    /// Where to put the tiles, if there's no file they go into the tile cache
+    pub output: Option<PathBuf>,
+}
+
+pub const SEED_FLAGS: &[Flag] = &[Flag::value("--bbox"), Flag::value("--zooms"), Flag::value("--rate"), Flag::value("--output")];
+
+pub fn seed_options_from_args(args: &Args) -> Result<SeedOptions, String> {
+    let bbox = args.get("--bbox").map(parse_bbox).transpose()?;
+    let zooms = match args.get("--zooms") {
+        Some(zoom_range) => {
+            let (min, max) = zoom_range.split_once('-').unwrap_or((zoom_range, zoom_range));
+            let (min, max) = (parse_zoom(min, "--zooms")?, parse_zoom(max, "--zooms")?);
+            if min > max {
+                return Err("--zooms needs a zoom or a range like 10-16".to_string());
+            }
+            Some(min..=max)
+        }
+        None => None,
+    };
+    let rate = match args.get("--rate") {
+        Some(rate) => rate.parse().ok().filter(|rate: &f64| *rate > 0.0).ok_or("--rate needs a number of tiles per second")?,
+        None => 4.0,
+    };
+    let output = args.get("--output").map(PathBuf::from);
+
+    let (north_west, south_east) = bbox.ok_or("seed needs a --bbox")?;
+    Ok(SeedOptions {
+        north_west,
+        south_east,
+        zooms: zooms.ok_or("seed needs --zooms")?,
+        rate,
+        output,
+    })
+}
+
+/// Every tile at `zoom` which covers part of the box.
+pub fn tiles_in_bbox(north_west: LatLon, south_east: LatLon, zoom: u32) -> impl Iterator<Item = Tile> {
+    // Clamp to the edge of the map, as web mercator stops short of the poles
+    let last = (1 << zoom) - 1;
+    let top_left = north_west.to_tile_coords(zoom);
+    let bottom_right = south_east.to_tile_coords(zoom);
+    let (min_x, max_x) = (top_left.x.clamp(0, last), bottom_right.x.clamp(0, last));
+    let (min_y, max_y) = (top_left.y.clamp(0, last), bottom_right.y.clamp(0, last));
+    (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| Tile::new(x, y, zoom)))
+}
+
+/// How far through seeding we are.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SeedProgress {
+    pub total: usize,
+    /// Tiles which were downloaded this time
+    pub fetched: usize,
+    /// Tiles which were already there, from an earlier run which got interrupted
+    pub skipped: usize,
+    /// Tiles which the source didn't have
+    pub missing: usize,
+    /// Tiles which couldn't be fetched even after retrying
+    pub failed: usize,
+}
+
+impl SeedProgress {
+    fn done(&self) -> usize {
+        self.fetched + self.skipped + self.missing + self.failed
+    }
+}
+
+/// Waits long enough between requests to keep to a number of requests a second.
+struct RateLimiter {
+    interval: Duration,
+    next: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
+}
+
+/// Downloads every tile in the box for each zoom level. Tiles which are already there are skipped,
+/// so running it again after it was stopped carries on from where it got to.
+pub fn seed(source: &dyn TileSource, options: &SeedOptions, mut report: impl FnMut(&SeedProgress)) -> Result<SeedProgress, String> {
+    let writer = match &options.output {
+        Some(path) => Some(
+            MbTilesWriter::create(path, source.name(), source.kind(), source.attribution())
+                .and_then(|writer| {
+                    writer.set_metadata("minzoom", &options.zooms.start().to_string())?;
+                    writer.set_metadata("maxzoom", &options.zooms.end().to_string())?;
+                    writer.set_metadata("bounds", &format!("{},{},{},{}", options.north_west.long, options.south_east.lat, options.south_east.long, options.north_west.lat))?;
+                    Ok(writer)
+                })
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+
+    let zooms = || options.zooms.clone().filter(|zoom| *zoom <= source.max_zoom());
+    let mut progress = SeedProgress {
+        total: zooms().map(|zoom| tiles_in_bbox(options.north_west, options.south_east, zoom).count()).sum(),
+        ..Default::default()
+    };
+    let mut rate_limiter = RateLimiter::new(options.rate);
+
+    for zoom in zooms() {
+        for tile in tiles_in_bbox(options.north_west, options.south_east, zoom) {
+            let (x, y, zoom) = (tile.x as u64, tile.y as u64, tile.zoom as u64);
+            let already_have = match &writer {
+                Some(writer) => writer.has_tile(x, y, zoom).map_err(|e| e.to_string())?,
+                None => source.is_cached(x, y, zoom),
+            };
+
+            if already_have {
+                progress.skipped += 1;
+            } else {
+                // Only the network needs to be taken slowly
+                if !source.is_cached(x, y, zoom) {
+                    rate_limiter.wait();
+                }
+                match source.get_tile(x, y, zoom) {
+                    Ok(data) if data.is_empty() => progress.missing += 1,
+                    Ok(data) => {
+                        if let Some(writer) = &writer {
+                            writer.put_tile(x, y, zoom, &data).map_err(|e| format!("Failed to write tile {}/{}/{}: {}", zoom, x, y, e))?;
+                        }
+                        progress.fetched += 1;
+                    }
+                    // Left for next time, running it again will try these again
+                    Err(e) => {
+                        eprintln!("\nFailed to get tile {}/{}/{}: {}", zoom, x, y, e);
+                        progress.failed += 1;
+                    }
+                }
+            }
+            report(&progress);
+        }
+    }
+    Ok(progress)
+}
+
+/// Runs the `seed` subcommand, printing how far it's got as it goes.
+pub fn run_seed(args: &[String]) -> Result<(), String> {
+    let args = Args::parse(args, &[TILE_SOURCE_FLAGS, HTTP_FLAGS, SEED_FLAGS])?;
+    let options = seed_options_from_args(&args)?;
+    let source = tile_source_from_args(&args)?;
+    let destination = options.output.as_deref().map(Path::display).map(|path| path.to_string()).unwrap_or("the tile cache".to_string());
+    println!("Seeding {} from {} at zooms {}-{}", destination, source.name(), options.zooms.start(), options.zooms.end());
+
+    let progress = seed(source.as_ref(), &options, |progress| {
+        print!("\r{}/{} tiles ({} downloaded, {} already had, {} missing, {} failed)", progress.done(), progress.total, progress.fetched, progress.skipped, progress.missing, progress.failed);
+        let _ = std::io::stdout().flush();
+    })?;
+    println!();
+    if progress.failed > 0 {
+        println!("{} tiles failed, run the same command again to retry them", progress.failed);
+    }
+    if progress.total == 0 {
+        println!("Nothing to do, {} only goes up to zoom {}", source.name(), source.max_zoom());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::{mbtiles::MbTilesSource, test_support::{solid_png, test_dir, TestTileSource}};
+
+    fn args(args: &str) -> Args {
+        Args::parse(&args.split_whitespace().map(|arg| arg.to_string()).collect::<Vec<_>>(), &[SEED_FLAGS]).unwrap()
+    }
+
+    #[test]
+    fn parses_the_bbox_and_zooms() {
+        let options = seed_options_from_args(&args("--bbox -0.2,51.4,0.1,51.6 --zooms 10-16 --rate 2")).unwrap();
+        assert_eq!(options.north_west, LatLon::new(51.6, -0.2));
+        assert_eq!(options.south_east, LatLon::new(51.4, 0.1));
+        assert_eq!(options.zooms, 10..=16);
+        assert_eq!(options.rate, 2.0);
+        assert_eq!(seed_options_from_args(&args("--bbox 0,0,1,1 --zooms 12")).unwrap().zooms, 12..=12);
+        assert!(seed_options_from_args(&args("--bbox 1,0,0,1 --zooms 12")).is_err());
+        assert!(seed_options_from_args(&args("--bbox 0,0,1,1 --zooms 16-10")).is_err());
+        assert!(seed_options_from_args(&args("--zooms 10")).is_err());
+        assert!(seed_options_from_args(&args("--bbox 0,0,1,1 --zooms 10-31")).is_err());
+        assert!(seed_options_from_args(&args("--bbox 0,0,1,1 --zooms ten")).is_err());
+        assert_eq!(seed_options_from_args(&args("--bbox 0,0,1,1 --zooms 30")).unwrap().zooms, 30..=30);
+    }
+
+    #[test]
+    fn whole_world_is_every_tile() {
+        let north_west = LatLon::new(85.0, -180.0);
+        let south_east = LatLon::new(-85.0, 179.9);
+        assert_eq!(tiles_in_bbox(north_west, south_east, 0).count(), 1);
+        assert_eq!(tiles_in_bbox(north_west, south_east, 3).count(), 64);
+    }
+
+    #[test]
+    fn seeding_again_carries_on_where_it_stopped() {
+        let path = test_dir("seed").join("seed.mbtiles");
+        let options = SeedOptions {
+            north_west: LatLon::new(51.6, -0.2),
+            south_east: LatLon::new(51.4, 0.1),
+            zooms: 8..=10,
+            rate: 1000.0,
+            output: Some(path.clone()),
+        };
+        let source = TestTileSource { tile: solid_png(256, [0, 0, 255, 255]), ..Default::default() };
+
+        // Stop part way through, like someone pressing ctrl-c
+        let partial = SeedOptions { zooms: 8..=9, ..options.clone() };
+        let first = seed(&source, &partial, |_| {}).unwrap();
+        let progress = seed(&source, &options, |_| {}).unwrap();
+        assert_eq!(progress.skipped, first.fetched);
+        assert_eq!(progress.fetched, progress.total - first.fetched);
+        assert_eq!(source.fetched.load(Ordering::Relaxed), progress.total);
+
+        let seeded = MbTilesSource::open(&path).unwrap();
+        let tile = tiles_in_bbox(options.north_west, options.south_east, 10).next().unwrap();
+        assert_eq!(seeded.get_tile(tile.x as u64, tile.y as u64, 10), Ok(source.tile.clone()));
+        assert_eq!(seeded.max_zoom, 10);
+    }
+}
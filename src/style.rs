@@ -0,0 +1,880 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use bevy::prelude::*;
+use mvt_reader::feature::Value as MvtValue;
+use raqote::{LineCap, LineJoin, SolidSource};
+use serde_json::Value;
+
+use crate::args::{Args, Flag};
+
+/// Used when no style is given, it roughly looks like the map always has.
+pub const DEFAULT_STYLE: &str = include_str!("../assets/style.json");
+
+/// The style which vector tiles are drawn with.
+#[derive(Resource, Clone, Deref)]
+pub struct MapStyle(pub Arc<Style>);
+
+/// The parts of a MapLibre/Mapbox GL style which we know how to draw.
+/// https://maplibre.org/maplibre-style-spec/
+#[derive(Debug, Clone)]
+pub struct Style {
+    pub layers: Vec<StyleLayer>,
+    /// Draws a dashed line round each tile, for seeing where the tiles are
+    pub debug_borders: bool,
+}
+
+impl Style {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let style: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let layers = style["layers"].as_array().ok_or("The style has no layers")?;
+        Ok(Self {
+            layers: layers.iter().filter_map(StyleLayer::parse).collect(),
+            debug_borders: false,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_json(&json)
+    }
+}
+
+/// `--style <path>` and `--debug-borders`
+pub const STYLE_FLAGS: &[Flag] = &[Flag::value("--style"), Flag::switch("--debug-borders")];
+
+/// Loads the style given with `--style`, or the default style.
+pub fn style_from_args(args: &Args) -> Result<Style, String> {
+    let mut style = match args.get("--style") {
+        Some(path) => Style::load(Path::new(path))?,
+        None => Style::from_json(DEFAULT_STYLE)?,
+    };
+    style.debug_borders = args.has("--debug-borders");
+    Ok(style)
+}
+
+#[derive(Debug, Clone)]
+pub enum LayerKind {
+    Background {
+        color: Property<StyleColor>,
+        opacity: Property<f32>,
+    },
+    Fill {
+        color: Property<StyleColor>,
+        opacity: Property<f32>,
+        outline_color: Option<Property<StyleColor>>,
+    },
+    Line {
+        color: Property<StyleColor>,
+        width: Property<f32>,
+        opacity: Property<f32>,
+        dash_array: Property<Vec<f32>>,
+        cap: LineCap,
+        join: LineJoin,
+    },
+    Circle {
+        color: Property<StyleColor>,
+        radius: Property<f32>,
+        opacity: Property<f32>,
+        stroke_color: Property<StyleColor>,
+        stroke_width: Property<f32>,
+    },
+    Symbol {
+        text_field: Value,
+        text_size: Property<f32>,
+        text_transform: Option<String>,
+        text_padding: f32,
+        sort_key: Option<Property<f32>>,
+        placement: SymbolPlacement,
+        spacing: f32,
+        max_angle: f32,
+        color: Property<StyleColor>,
+        opacity: Property<f32>,
+        halo_color: Property<StyleColor>,
+        halo_width: Property<f32>,
+    },
+}
+
+/// Whether labels go at a point or follow along lines, like street names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolPlacement {
+    Point,
+    Line,
+}
+
+#[derive(Debug, Clone)]
+pub struct StyleLayer {
+    pub id: String,
+    pub kind: LayerKind,
+    pub source_layer: Option<String>,
+    pub filter: Option<Value>,
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+}
+
+impl StyleLayer {
+    /// Returns `None` for layer types we can't draw, or layers which are hidden.
+    fn parse(layer: &Value) -> Option<Self> {
+        let paint = &layer["paint"];
+        let layout = &layer["layout"];
+        if layout["visibility"].as_str() == Some("none") {
+            return None;
+        }
+
+        let kind = match layer["type"].as_str()? {
+            "background" => LayerKind::Background {
+                color: Property::parse(&paint["background-color"], StyleColor::BLACK),
+                opacity: Property::parse(&paint["background-opacity"], 1.0),
+            },
+            "fill" => LayerKind::Fill {
+                color: Property::parse(&paint["fill-color"], StyleColor::BLACK),
+                opacity: Property::parse(&paint["fill-opacity"], 1.0),
+                outline_color: (!paint["fill-outline-color"].is_null()).then(|| Property::parse(&paint["fill-outline-color"], StyleColor::BLACK)),
+            },
+            "line" => LayerKind::Line {
+                color: Property::parse(&paint["line-color"], StyleColor::BLACK),
+                width: Property::parse(&paint["line-width"], 1.0),
+                opacity: Property::parse(&paint["line-opacity"], 1.0),
+                dash_array: Property::parse(&paint["line-dasharray"], Vec::new()),
+                cap: match layout["line-cap"].as_str() {
+                    Some("round") => LineCap::Round,
+                    Some("square") => LineCap::Square,
+                    _ => LineCap::Butt,
+                },
+                join: match layout["line-join"].as_str() {
+                    Some("round") => LineJoin::Round,
+                    Some("bevel") => LineJoin::Bevel,
+                    _ => LineJoin::Miter,
+                },
+            },
+            "circle" => LayerKind::Circle {
+                color: Property::parse(&paint["circle-color"], StyleColor::BLACK),
+                radius: Property::parse(&paint["circle-radius"], 5.0),
+                opacity: Property::parse(&paint["circle-opacity"], 1.0),
+                stroke_color: Property::parse(&paint["circle-stroke-color"], StyleColor::BLACK),
+                stroke_width: Property::parse(&paint["circle-stroke-width"], 0.0),
+            },
+            "symbol" => {
+                // Only text labels, we don't have any icons
+                if layout["text-field"].is_null() {
+                    return None;
+                }
+                LayerKind::Symbol {
+                    text_field: layout["text-field"].clone(),
+                    text_size: Property::parse(&layout["text-size"], 16.0),
+                    text_transform: layout["text-transform"].as_str().map(|transform| transform.to_string()),
+                    text_padding: layout["text-padding"].as_f64().unwrap_or(2.0) as f32,
+                    sort_key: (!layout["symbol-sort-key"].is_null()).then(|| Property::parse(&layout["symbol-sort-key"], 0.0)),
+                    placement: match layout["symbol-placement"].as_str() {
+                        Some("line") | Some("line-center") => SymbolPlacement::Line,
+                        _ => SymbolPlacement::Point,
+                    },
+                    spacing: layout["symbol-spacing"].as_f64().unwrap_or(250.0) as f32,
+                    max_angle: layout["text-max-angle"].as_f64().unwrap_or(45.0) as f32,
+                    color: Property::parse(&paint["text-color"], StyleColor::BLACK),
+                    opacity: Property::parse(&paint["text-opacity"], 1.0),
+                    halo_color: Property::parse(&paint["text-halo-color"], StyleColor::new(0.0, 0.0, 0.0, 0.0)),
+                    halo_width: Property::parse(&paint["text-halo-width"], 0.0),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            id: layer["id"].as_str().unwrap_or_default().to_string(),
+            kind,
+            source_layer: layer["source-layer"].as_str().map(|name| name.to_string()),
+            filter: layer.get("filter").cloned(),
+            min_zoom: layer["minzoom"].as_f64().unwrap_or(0.0) as f32,
+            max_zoom: layer["maxzoom"].as_f64().unwrap_or(24.0) as f32,
+        })
+    }
+
+    pub fn visible_at(&self, zoom: f32) -> bool {
+        zoom >= self.min_zoom && zoom < self.max_zoom
+    }
+
+    pub fn matches(&self, context: &EvalContext) -> bool {
+        match &self.filter {
+            Some(filter) => filter_matches(filter, context),
+            None => true,
+        }
+    }
+}
+
+/// Works out the text of a label, `text-field` is either an expression or a string with `{property}` tokens in it.
+pub fn format_text_field(text_field: &Value, text_transform: Option<&str>, context: &EvalContext) -> Option<String> {
+    let text = match text_field {
+        Value::String(template) => {
+            let mut text = String::new();
+            let mut rest = template.as_str();
+            while let Some((before, after)) = rest.split_once('{') {
+                let (key, after) = after.split_once('}')?;
+                text.push_str(before);
+                match context.properties.get(key) {
+                    Some(Value::String(value)) => text.push_str(value),
+                    Some(Value::Null) | None => {}
+                    Some(value) => text.push_str(&value.to_string()),
+                }
+                rest = after;
+            }
+            text.push_str(rest);
+            text
+        }
+        expression => match evaluate(expression, context) {
+            Value::String(text) => text,
+            Value::Null => return None,
+            value => value.to_string(),
+        },
+    };
+    let text = match text_transform {
+        Some("uppercase") => text.to_uppercase(),
+        Some("lowercase") => text.to_lowercase(),
+        _ => text,
+    };
+    let text = text.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// What expressions and filters get evaluated against.
+pub struct EvalContext<'a> {
+    pub zoom: f32,
+    pub geometry_type: &'a str,
+    pub properties: &'a HashMap<String, Value>,
+}
+
+/// Turns the properties of a vector tile feature into json values so expressions can work with them.
+pub fn feature_properties(properties: &Option<HashMap<String, MvtValue>>) -> HashMap<String, Value> {
+    let Some(properties) = properties else {
+        return HashMap::new();
+    };
+    properties
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                MvtValue::String(s) => Value::from(s.clone()),
+                MvtValue::Float(f) => Value::from(*f as f64),
+                MvtValue::Double(f) => Value::from(*f),
+                MvtValue::Int(i) | MvtValue::SInt(i) => Value::from(*i),
+                MvtValue::UInt(u) => Value::from(*u),
+                MvtValue::Bool(b) => Value::from(*b),
+                MvtValue::Null => Value::Null,
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// The name filters and `geometry-type` use for the geometry.
+pub fn geometry_type(geometry: &geo::Geometry<f32>) -> &'static str {
+    match geometry {
+        geo::Geometry::Point(_) | geo::Geometry::MultiPoint(_) => "Point",
+        geo::Geometry::Line(_) | geo::Geometry::LineString(_) | geo::Geometry::MultiLineString(_) => "LineString",
+        _ => "Polygon",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StyleColor {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl StyleColor {
+    pub const BLACK: StyleColor = StyleColor { r: 0.0, g: 0.0, b: 0.0, a: 1.0 };
+
+    pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Parses css colours, like `#fff`, `#a0b0c0`, `rgba(10, 20, 30, 0.5)`, `hsl(120, 50%, 50%)` or `white`.
+    pub fn parse(color: &str) -> Option<Self> {
+        let color = color.trim().to_lowercase();
+        if let Some(hex) = color.strip_prefix('#').filter(|hex| hex.is_ascii()) {
+            let digit = |i: usize, len: usize| u8::from_str_radix(&hex[i..i + len], 16).ok();
+            return match hex.len() {
+                3 | 4 => {
+                    let channel = |i| digit(i, 1).map(|v| (v * 17) as f32 / 255.0);
+                    Some(Self::new(channel(0)?, channel(1)?, channel(2)?, if hex.len() == 4 { channel(3)? } else { 1.0 }))
+                }
+                6 | 8 => {
+                    let channel = |i| digit(i, 2).map(|v| v as f32 / 255.0);
+                    Some(Self::new(channel(0)?, channel(2)?, channel(4)?, if hex.len() == 8 { channel(6)? } else { 1.0 }))
+                }
+                _ => None,
+            };
+        }
+
+        if let Some((function, args)) = color.strip_suffix(')').and_then(|color| color.split_once('(')) {
+            let args: Vec<&str> = args.split(',').map(|arg| arg.trim()).collect();
+            let number = |i: usize| -> Option<f32> {
+                let arg: &str = args.get(i)?;
+                match arg.strip_suffix('%') {
+                    Some(percent) => percent.parse::<f32>().ok().map(|p| p / 100.0),
+                    None => arg.parse().ok(),
+                }
+            };
+            let alpha = if args.len() > 3 { number(3)? } else { 1.0 };
+            return match function {
+                "rgb" | "rgba" => {
+                    // Percentages have already been turned into 0-1, plain numbers are 0-255
+                    let channel = |i: usize| number(i).map(|v| if args[i].ends_with('%') { v } else { v / 255.0 });
+                    Some(Self::new(channel(0)?, channel(1)?, channel(2)?, alpha))
+                }
+                "hsl" | "hsla" => Some(Self::from_hsl(number(0)?, number(1)?, number(2)?, alpha)),
+                _ => None,
+            };
+        }
+
+        let (r, g, b, a) = match color.as_str() {
+            "black" => (0, 0, 0, 1.0),
+            "white" => (255, 255, 255, 1.0),
+            "transparent" => (0, 0, 0, 0.0),
+            "red" => (255, 0, 0, 1.0),
+            "green" => (0, 128, 0, 1.0),
+            "lime" => (0, 255, 0, 1.0),
+            "blue" => (0, 0, 255, 1.0),
+            "yellow" => (255, 255, 0, 1.0),
+            "orange" => (255, 165, 0, 1.0),
+            "purple" => (128, 0, 128, 1.0),
+            "gray" | "grey" => (128, 128, 128, 1.0),
+            "silver" => (192, 192, 192, 1.0),
+            "brown" => (165, 42, 42, 1.0),
+            "navy" => (0, 0, 128, 1.0),
+            "teal" => (0, 128, 128, 1.0),
+            "aqua" | "cyan" => (0, 255, 255, 1.0),
+            "magenta" | "fuchsia" => (255, 0, 255, 1.0),
+            _ => return None,
+        };
+        Some(Self::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, a))
+    }
+
+    fn from_hsl(hue: f32, saturation: f32, lightness: f32, alpha: f32) -> Self {
+        let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = lightness - c / 2.0;
+        Self::new(r + m, g + m, b + m, alpha)
+    }
+
+    /// raqote wants premultiplied colours
+    pub fn to_source(&self, opacity: f32) -> SolidSource {
+        let channel = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        SolidSource::from_unpremultiplied_argb(channel(self.a * opacity), channel(self.r), channel(self.g), channel(self.b))
+    }
+}
+
+/// Something which can be the value of a paint property.
+pub trait StyleValue: Sized + Clone {
+    fn from_json(value: &Value) -> Option<Self>;
+    fn interpolate(from: &Self, to: &Self, t: f32) -> Self;
+}
+
+impl StyleValue for f32 {
+    fn from_json(value: &Value) -> Option<Self> {
+        value.as_f64().map(|v| v as f32)
+    }
+
+    fn interpolate(from: &Self, to: &Self, t: f32) -> Self {
+        from + (to - from) * t
+    }
+}
+
+impl StyleValue for StyleColor {
+    fn from_json(value: &Value) -> Option<Self> {
+        StyleColor::parse(value.as_str()?)
+    }
+
+    fn interpolate(from: &Self, to: &Self, t: f32) -> Self {
+        StyleColor::new(
+            f32::interpolate(&from.r, &to.r, t),
+            f32::interpolate(&from.g, &to.g, t),
+            f32::interpolate(&from.b, &to.b, t),
+            f32::interpolate(&from.a, &to.a, t),
+        )
+    }
+}
+
+impl StyleValue for Vec<f32> {
+    fn from_json(value: &Value) -> Option<Self> {
+        value.as_array()?.iter().map(f32::from_json).collect()
+    }
+
+    // Dashes can't really be blended, so they just switch half way
+    fn interpolate(from: &Self, to: &Self, t: f32) -> Self {
+        if t < 0.5 { from.clone() } else { to.clone() }
+    }
+}
+
+/// A paint property, either a plain value or a function/expression which depends on the zoom or the feature.
+#[derive(Debug, Clone)]
+pub enum Property<T> {
+    Constant(T),
+    Dynamic { value: Value, default: T },
+}
+
+impl<T: StyleValue> Property<T> {
+    pub fn parse(value: &Value, default: T) -> Self {
+        if value.is_null() {
+            return Property::Constant(default);
+        }
+        match T::from_json(value) {
+            Some(constant) => Property::Constant(constant),
+            None => Property::Dynamic { value: value.clone(), default },
+        }
+    }
+
+    pub fn evaluate(&self, context: &EvalContext) -> T {
+        match self {
+            Property::Constant(value) => value.clone(),
+            Property::Dynamic { value, default } => evaluate_typed(value, context).unwrap_or_else(|| default.clone()),
+        }
+    }
+}
+
+fn evaluate_typed<T: StyleValue>(value: &Value, context: &EvalContext) -> Option<T> {
+    // The old style of zoom and property functions
+    if let Some(function) = value.as_object() {
+        let input = match function.get("property").and_then(|p| p.as_str()) {
+            Some(property) => context.properties.get(property)?.clone(),
+            None => Value::from(context.zoom),
+        };
+        // Identity functions don't have any stops
+        if function.get("type").and_then(|t| t.as_str()) == Some("identity") {
+            return T::from_json(&input);
+        }
+        let stops = function.get("stops")?.as_array()?;
+        let stops: Vec<(&Value, &Value)> = stops.iter().filter_map(|stop| Some((stop.get(0)?, stop.get(1)?))).collect();
+        return match function.get("type").and_then(|t| t.as_str()) {
+            Some("categorical") => T::from_json(stops.iter().find(|(stop, _)| values_equal(stop, &input))?.1),
+            Some("interval") => T::from_json(step(input.as_f64()? as f32, &stops)?),
+            _ => {
+                let base = function.get("base").and_then(|b| b.as_f64()).unwrap_or(1.0) as f32;
+                let stops = stops.iter().filter_map(|(stop, out)| Some((stop.as_f64()? as f32, T::from_json(out)?))).collect::<Vec<_>>();
+                interpolate(input.as_f64()? as f32, base, &stops)
+            }
+        };
+    }
+
+    let Some(args) = value.as_array() else {
+        return T::from_json(value);
+    };
+    match args.first().and_then(|op| op.as_str()) {
+        Some("interpolate") => {
+            let base = match args.get(1)?.as_array()?.as_slice() {
+                [kind, base] if kind == "exponential" => base.as_f64()? as f32,
+                _ => 1.0,
+            };
+            let input = evaluate(args.get(2)?, context).as_f64()? as f32;
+            let stops = args[3..]
+                .chunks(2)
+                .filter_map(|stop| Some((stop.first()?.as_f64()? as f32, evaluate_typed(stop.get(1)?, context)?)))
+                .collect::<Vec<_>>();
+            interpolate(input, base, &stops)
+        }
+        Some("step") => {
+            let input = evaluate(args.get(1)?, context).as_f64()? as f32;
+            let mut output = args.get(2)?;
+            for stop in args[3..].chunks(2) {
+                if input >= stop.first()?.as_f64()? as f32 {
+                    output = stop.get(1)?;
+                }
+            }
+            evaluate_typed(output, context)
+        }
+        Some("match") => evaluate_typed(match_branch(args, context)?, context),
+        Some("case") => evaluate_typed(case_branch(args, context)?, context),
+        Some("coalesce") => args[1..].iter().find_map(|arg| evaluate_typed(arg, context)),
+        Some(_) => T::from_json(&evaluate(value, context)),
+        // A plain array, like a dash array
+        None => T::from_json(value),
+    }
+}
+
+fn step<'a>(input: f32, stops: &[(&Value, &'a Value)]) -> Option<&'a Value> {
+    let mut output = stops.first()?.1;
+    for (stop, out) in stops {
+        if input >= stop.as_f64()? as f32 {
+            output = out;
+        }
+    }
+    Some(output)
+}
+
+fn interpolate<T: StyleValue>(input: f32, base: f32, stops: &[(f32, T)]) -> Option<T> {
+    let first = stops.first()?;
+    let last = stops.last()?;
+    if input <= first.0 {
+        return Some(first.1.clone());
+    }
+    if input >= last.0 {
+        return Some(last.1.clone());
+    }
+    let i = stops.iter().position(|(stop, _)| *stop > input)?;
+    let (from, to) = (&stops[i - 1], &stops[i]);
+    let range = to.0 - from.0;
+    let progress = input - from.0;
+    let t = if base == 1.0 {
+        progress / range
+    } else {
+        (base.powf(progress) - 1.0) / (base.powf(range) - 1.0)
+    };
+    Some(T::interpolate(&from.1, &to.1, t))
+}
+
+fn match_branch<'a>(args: &'a [Value], context: &EvalContext) -> Option<&'a Value> {
+    let input = evaluate(args.get(1)?, context);
+    let branches = args.get(2..).filter(|branches| !branches.is_empty())?;
+    for branch in branches[..branches.len() - 1].chunks(2) {
+        let matched = match &branch[0] {
+            Value::Array(labels) => labels.iter().any(|label| values_equal(label, &input)),
+            label => values_equal(label, &input),
+        };
+        if matched {
+            return branch.get(1);
+        }
+    }
+    branches.last()
+}
+
+fn case_branch<'a>(args: &'a [Value], context: &EvalContext) -> Option<&'a Value> {
+    let branches = args.get(1..).filter(|branches| !branches.is_empty())?;
+    for branch in branches[..branches.len() - 1].chunks(2) {
+        if truthy(&evaluate(&branch[0], context)) {
+            return branch.get(1);
+        }
+    }
+    branches.last()
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Null => false,
+        _ => true,
+    }
+}
+
+/// Evaluates the parts of the expression language which don't need to know what type they produce.
+/// https://maplibre.org/maplibre-style-spec/expressions/
+pub fn evaluate(value: &Value, context: &EvalContext) -> Value {
+    let Some(args) = value.as_array() else {
+        return value.clone();
+    };
+    let Some(op) = args.first().and_then(|op| op.as_str()) else {
+        return value.clone();
+    };
+    let arg = |i: usize| args.get(i).map(|arg| evaluate(arg, context)).unwrap_or(Value::Null);
+
+    match op {
+        "literal" => args.get(1).cloned().unwrap_or(Value::Null),
+        "get" => arg(1).as_str().and_then(|key| context.properties.get(key)).cloned().unwrap_or(Value::Null),
+        "has" => Value::from(arg(1).as_str().is_some_and(|key| context.properties.contains_key(key))),
+        "zoom" => Value::from(context.zoom),
+        "geometry-type" => Value::from(context.geometry_type),
+        "!" => Value::from(!truthy(&arg(1))),
+        "all" => Value::from(args[1..].iter().all(|arg| truthy(&evaluate(arg, context)))),
+        "any" => Value::from(args[1..].iter().any(|arg| truthy(&evaluate(arg, context)))),
+        "==" => Value::from(values_equal(&arg(1), &arg(2))),
+        "!=" => Value::from(!values_equal(&arg(1), &arg(2))),
+        "<" => Value::from(compare(&arg(1), &arg(2)).is_some_and(|o| o.is_lt())),
+        "<=" => Value::from(compare(&arg(1), &arg(2)).is_some_and(|o| o.is_le())),
+        ">" => Value::from(compare(&arg(1), &arg(2)).is_some_and(|o| o.is_gt())),
+        ">=" => Value::from(compare(&arg(1), &arg(2)).is_some_and(|o| o.is_ge())),
+        "in" => {
+            let needle = arg(1);
+            Value::from(match arg(2) {
+                Value::Array(haystack) => haystack.iter().any(|item| values_equal(item, &needle)),
+                Value::String(haystack) => needle.as_str().is_some_and(|needle| haystack.contains(needle)),
+                _ => false,
+            })
+        }
+        "match" => match_branch(args, context).map(|branch| evaluate(branch, context)).unwrap_or(Value::Null),
+        "case" => case_branch(args, context).map(|branch| evaluate(branch, context)).unwrap_or(Value::Null),
+        "coalesce" => args[1..].iter().map(|arg| evaluate(arg, context)).find(|v| !v.is_null()).unwrap_or(Value::Null),
+        "to-number" => match arg(1) {
+            Value::String(s) => s.parse::<f64>().map(Value::from).unwrap_or(Value::Null),
+            Value::Bool(b) => Value::from(b as u8),
+            v => v,
+        },
+        "to-string" => match arg(1) {
+            Value::String(s) => Value::from(s),
+            Value::Null => Value::from(""),
+            v => Value::from(v.to_string()),
+        },
+        "to-boolean" => Value::from(truthy(&arg(1))),
+        "interpolate" | "step" => evaluate_typed::<f32>(value, context).map(Value::from).unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+/// Filters can be written either as expressions or in the old (legacy) filter syntax, like `["==", "class", "park"]`.
+pub fn filter_matches(filter: &Value, context: &EvalContext) -> bool {
+    let Some(args) = filter.as_array() else {
+        return truthy(filter);
+    };
+    let op = args.first().and_then(|op| op.as_str()).unwrap_or_default();
+    match op {
+        "all" => args[1..].iter().all(|f| filter_matches(f, context)),
+        "any" => args[1..].iter().any(|f| filter_matches(f, context)),
+        "none" => !args[1..].iter().any(|f| filter_matches(f, context)),
+        // Legacy filters name the property with a plain string and only compare it with plain values,
+        // anything else like `["in", "park", ["get", "class"]]` is an expression
+        "==" | "!=" | "<" | "<=" | ">" | ">=" | "in" | "!in" | "has" | "!has"
+            if args.get(1).is_some_and(|key| key.is_string()) && !args[2..].iter().any(|v| v.is_array()) => {
+            let key = args[1].as_str().unwrap_or_default();
+            let value = match key {
+                "$type" => Value::from(context.geometry_type),
+                _ => context.properties.get(key).cloned().unwrap_or(Value::Null),
+            };
+            let operand = args.get(2).unwrap_or(&Value::Null);
+            match op {
+                "==" => values_equal(&value, operand),
+                "!=" => !values_equal(&value, operand),
+                "<" => compare(&value, operand).is_some_and(|o| o.is_lt()),
+                "<=" => compare(&value, operand).is_some_and(|o| o.is_le()),
+                ">" => compare(&value, operand).is_some_and(|o| o.is_gt()),
+                ">=" => compare(&value, operand).is_some_and(|o| o.is_ge()),
+                "in" => args[2..].iter().any(|v| values_equal(&value, v)),
+                "!in" => !args[2..].iter().any(|v| values_equal(&value, v)),
+                "has" => key == "$type" || context.properties.contains_key(key),
+                _ => !context.properties.contains_key(key),
+            }
+        }
+        _ => truthy(&evaluate(filter, context)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn properties() -> HashMap<String, Value> {
+        HashMap::from([
+            ("class".to_string(), json!("park")),
+            ("rank".to_string(), json!(3)),
+            ("name".to_string(), json!("Mill Road")),
+        ])
+    }
+
+    fn context(properties: &HashMap<String, Value>) -> EvalContext<'_> {
+        EvalContext { zoom: 10.0, geometry_type: "Polygon", properties }
+    }
+
+    fn color(r: f32, g: f32, b: f32, a: f32) -> StyleColor {
+        StyleColor::new(r, g, b, a)
+    }
+
+    fn assert_color_eq(actual: Option<StyleColor>, expected: Option<StyleColor>, what: &str) {
+        match (actual, expected) {
+            (Some(a), Some(e)) => assert!(
+                [a.r - e.r, a.g - e.g, a.b - e.b, a.a - e.a].iter().all(|d| d.abs() < 0.002),
+                "{}: {:?} isn't {:?}", what, a, e
+            ),
+            (a, e) => assert_eq!(a, e, "{}", what),
+        }
+    }
+
+    #[test]
+    fn colors() {
+        let cases = [
+            ("#fff", Some(color(1.0, 1.0, 1.0, 1.0))),
+            ("#0f08", Some(color(0.0, 1.0, 0.0, 0.533))),
+            ("#a0b0c0", Some(color(160.0 / 255.0, 176.0 / 255.0, 192.0 / 255.0, 1.0))),
+            ("#FF000080", Some(color(1.0, 0.0, 0.0, 128.0 / 255.0))),
+            ("rgb(255, 0, 51)", Some(color(1.0, 0.0, 0.2, 1.0))),
+            ("rgba(10, 20, 30, 0.5)", Some(color(10.0 / 255.0, 20.0 / 255.0, 30.0 / 255.0, 0.5))),
+            ("rgb(100%, 0%, 50%)", Some(color(1.0, 0.0, 0.5, 1.0))),
+            ("hsl(120, 100%, 50%)", Some(color(0.0, 1.0, 0.0, 1.0))),
+            ("hsla(0, 100%, 50%, 0.25)", Some(color(1.0, 0.0, 0.0, 0.25))),
+            ("hsl(240, 100%, 25%)", Some(color(0.0, 0.0, 0.5, 1.0))),
+            (" White ", Some(color(1.0, 1.0, 1.0, 1.0))),
+            ("transparent", Some(color(0.0, 0.0, 0.0, 0.0))),
+            ("grey", Some(color(128.0 / 255.0, 128.0 / 255.0, 128.0 / 255.0, 1.0))),
+            ("#ggg", None),
+            ("#12345", None),
+            ("#ééé", None),
+            ("rgb(1, 2)", None),
+            ("cmyk(0, 0, 0, 0)", None),
+            ("notacolour", None),
+        ];
+        for (text, expected) in cases {
+            assert_color_eq(StyleColor::parse(text), expected, text);
+        }
+    }
+
+    #[test]
+    fn typed_expressions() {
+        let properties = properties();
+        let context = context(&properties);
+        let cases = [
+            // interpolate
+            (json!(["interpolate", ["linear"], ["zoom"], 5, 0, 15, 10]), Some(5.0)),
+            (json!(["interpolate", ["linear"], ["zoom"], 12, 1, 14, 3]), Some(1.0)),
+            (json!(["interpolate", ["linear"], ["zoom"], 2, 1, 4, 3]), Some(3.0)),
+            (json!(["interpolate", ["exponential", 2], ["zoom"], 8, 0, 12, 15]), Some(3.0)),
+            (json!(["interpolate", ["linear"], ["get", "rank"], 0, 0, 10, 100]), Some(30.0)),
+            (json!(["interpolate", ["linear"], ["zoom"]]), None),
+            // step
+            (json!(["step", ["zoom"], 1, 8, 2, 10, 3, 12, 4]), Some(3.0)),
+            (json!(["step", ["zoom"], 1, 11, 2]), Some(1.0)),
+            // match
+            (json!(["match", ["get", "class"], "park", 1, ["wood", "forest"], 2, 0]), Some(1.0)),
+            (json!(["match", ["get", "class"], ["wood", "park"], 2, 0]), Some(2.0)),
+            (json!(["match", ["get", "class"], "wood", 1, 0]), Some(0.0)),
+            (json!(["match", ["get", "rank"], 3, 7, 0]), Some(7.0)),
+            // case
+            (json!(["case", ["==", ["get", "class"], "wood"], 1, [">", ["get", "rank"], 2], 2, 3]), Some(2.0)),
+            (json!(["case", false, 1, 3]), Some(3.0)),
+            // coalesce
+            (json!(["coalesce", ["get", "missing"], ["get", "rank"]]), Some(3.0)),
+            (json!(["coalesce", ["get", "missing"]]), None),
+            // the old style of functions
+            (json!({ "stops": [[5, 1], [15, 11]] }), Some(6.0)),
+            (json!({ "base": 2, "stops": [[8, 0], [12, 15]] }), Some(3.0)),
+            (json!({ "type": "interval", "stops": [[0, 1], [10, 2], [12, 3]] }), Some(2.0)),
+            (json!({ "property": "class", "type": "categorical", "stops": [["wood", 5], ["park", 6]] }), Some(6.0)),
+            (json!({ "property": "rank", "type": "identity" }), Some(3.0)),
+            (json!({ "property": "missing", "type": "identity" }), None),
+        ];
+        for (expression, expected) in cases {
+            let actual = evaluate_typed::<f32>(&expression, &context);
+            assert!(
+                actual.zip(expected).map_or(actual == expected, |(a, e)| (a - e).abs() < 1e-4),
+                "{} gave {:?} not {:?}", expression, actual, expected
+            );
+        }
+    }
+
+    #[test]
+    fn colors_are_interpolated() {
+        let properties = properties();
+        let context = context(&properties);
+        let gray = evaluate_typed::<StyleColor>(&json!(["interpolate", ["linear"], ["zoom"], 5, "#000000", 15, "#ffffff"]), &context);
+        assert_color_eq(gray, Some(color(0.5, 0.5, 0.5, 1.0)), "interpolate");
+        let picked = evaluate_typed::<StyleColor>(&json!(["match", ["get", "class"], "park", "green", "red"]), &context);
+        assert_color_eq(picked, Some(color(0.0, 128.0 / 255.0, 0.0, 1.0)), "match");
+
+        // A property falls back to its default when the expression doesn't give a colour
+        let property = Property::parse(&json!(["get", "missing"]), StyleColor::BLACK);
+        assert_eq!(property.evaluate(&context), StyleColor::BLACK);
+    }
+
+    #[test]
+    fn untyped_expressions() {
+        let properties = properties();
+        let context = context(&properties);
+        let cases = [
+            (json!(["get", "class"]), json!("park")),
+            (json!(["get", "missing"]), Value::Null),
+            (json!(["has", "name"]), json!(true)),
+            (json!(["zoom"]), json!(10.0)),
+            (json!(["geometry-type"]), json!("Polygon")),
+            (json!(["literal", [1, 2]]), json!([1, 2])),
+            (json!(["!", ["has", "missing"]]), json!(true)),
+            (json!(["all", ["==", ["get", "class"], "park"], ["<", ["get", "rank"], 5]]), json!(true)),
+            (json!(["any", ["==", ["get", "class"], "wood"], ["!=", ["get", "rank"], 3]]), json!(false)),
+            (json!(["<=", ["get", "rank"], 3]), json!(true)),
+            (json!([">", ["get", "name"], "A"]), json!(true)),
+            (json!([">=", ["get", "name"], 3]), json!(false)),
+            (json!(["in", ["get", "class"], ["literal", ["wood", "park"]]]), json!(true)),
+            (json!(["in", "Road", ["get", "name"]]), json!(true)),
+            (json!(["match", ["get", "class"], "park", "green", "red"]), json!("green")),
+            (json!(["coalesce", ["get", "missing"], ["get", "name"]]), json!("Mill Road")),
+            (json!(["to-number", "4.5"]), json!(4.5)),
+            (json!(["to-string", ["get", "rank"]]), json!("3")),
+            (json!(["to-boolean", ["get", "missing"]]), json!(false)),
+            (json!(["step", ["zoom"], 1, 8, 2]), json!(2.0)),
+            (json!(["not-an-operator", 1]), Value::Null),
+        ];
+        for (expression, expected) in cases {
+            assert_eq!(evaluate(&expression, &context), expected, "{}", expression);
+        }
+    }
+
+    #[test]
+    fn legacy_filters() {
+        let properties = properties();
+        let context = context(&properties);
+        let cases = [
+            (json!(["==", "class", "park"]), true),
+            (json!(["!=", "class", "park"]), false),
+            (json!(["<", "rank", 5]), true),
+            (json!(["<=", "rank", 3]), true),
+            (json!([">", "rank", 3]), false),
+            (json!([">=", "rank", 4]), false),
+            (json!(["in", "class", "wood", "park"]), true),
+            (json!(["!in", "class", "wood", "park"]), false),
+            (json!(["has", "name"]), true),
+            (json!(["!has", "name"]), false),
+            (json!(["has", "$type"]), true),
+            (json!(["==", "$type", "Polygon"]), true),
+            (json!(["==", "missing", "park"]), false),
+            (json!(["all", ["==", "class", "park"], [">", "rank", 1]]), true),
+            (json!(["any", ["==", "class", "wood"], ["has", "name"]]), true),
+            (json!(["none", ["==", "class", "park"]]), false),
+            (json!(["none"]), true),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(filter_matches(&filter, &context), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn expression_filters() {
+        let properties = properties();
+        let context = context(&properties);
+        let cases = [
+            (json!(["==", ["get", "class"], "park"]), true),
+            (json!(["!=", ["get", "class"], "park"]), false),
+            (json!(["==", ["geometry-type"], "Polygon"]), true),
+            (json!(["in", ["get", "class"], ["literal", ["wood", "park"]]]), true),
+            (json!(["!", ["has", "name"]]), false),
+            (json!(["all", ["==", ["get", "class"], "park"], ["<", ["zoom"], 11]]), true),
+            (json!(["any", ["==", ["get", "class"], "wood"], [">", ["zoom"], 11]]), false),
+            (json!(true), true),
+            (json!(false), false),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(filter_matches(&filter, &context), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn filters_starting_with_a_string_can_still_be_expressions() {
+        let properties = properties();
+        let context = context(&properties);
+        // A legacy filter would look for a property called "park", as an expression it looks for "park" in the class
+        assert!(filter_matches(&json!(["in", "park", ["get", "class"]]), &context));
+        assert!(filter_matches(&json!(["==", "park", ["get", "class"]]), &context));
+        // While this is the legacy "class is one of these"
+        assert!(filter_matches(&json!(["in", "class", "park"]), &context));
+        assert!(!filter_matches(&json!(["in", "park", "class"]), &context));
+    }
+}
@@ -0,0 +1,369 @@
+//! Helpers for building the fixtures the tests need, without having to check in binary files.
+
+use std::{collections::{HashMap, VecDeque}, io::{BufRead, BufReader, Cursor, Write}, net::{TcpListener, TcpStream}, path::PathBuf, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use crate::{http::{FetchError, HttpClient, HttpConfig}, tile_cache::{TileCache, DEFAULT_CACHE_SIZE}, tile_source::{TileKind, TileSource, UrlTileSource}};
+
+/// Geometry in tile units (0 to 4096), rings don't need to repeat their first point.
+pub enum TestGeometry {
+    Polygon(Vec<Vec<(i32, i32)>>),
+}
+
+pub struct TestFeature {
+    pub geometry: TestGeometry,
+    pub properties: Vec<(&'static str, &'static str)>,
+}
+
+impl TestFeature {
+    pub fn new(geometry: TestGeometry) -> Self {
+        Self { geometry, properties: Vec::new() }
+    }
+}
+
+/// A square ring going clockwise on screen, like the outside of a polygon.
+pub fn square(min: i32, max: i32) -> Vec<(i32, i32)> {
+    vec![(min, min), (max, min), (max, max), (min, max)]
+}
+
+/// A square ring going anticlockwise on screen, like a hole.
+pub fn square_hole(min: i32, max: i32) -> Vec<(i32, i32)> {
+    let mut ring = square(min, max);
+    ring.reverse();
+    ring
+}
+
+/// Encodes the layers as a Mapbox Vector Tile with an extent of 4096.
+/// https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+pub fn encode_mvt(layers: &[(&str, Vec<TestFeature>)]) -> Vec<u8> {
+    let mut tile = Vec::new();
+    for (name, features) in layers {
+        let mut keys: Vec<&str> = Vec::new();
+        let mut values: Vec<&str> = Vec::new();
+
+        let mut layer = Vec::new();
+        write_field(&mut layer, 15, 0);
+        write_varint(&mut layer, 2);
+        write_bytes(&mut layer, 1, name.as_bytes());
+        for feature in features {
+            let mut tags = Vec::new();
+            for (key, value) in &feature.properties {
+                tags.push(index_of(&mut keys, key));
+                tags.push(index_of(&mut values, value));
+            }
+            let (geometry_type, geometry) = encode_geometry(&feature.geometry);
+
+            let mut encoded = Vec::new();
+            write_packed(&mut encoded, 2, &tags);
+            write_field(&mut encoded, 3, 0);
+            write_varint(&mut encoded, geometry_type);
+            write_packed(&mut encoded, 4, &geometry);
+            write_bytes(&mut layer, 2, &encoded);
+        }
+        for key in keys {
+            write_bytes(&mut layer, 3, key.as_bytes());
+        }
+        for value in values {
+            let mut encoded = Vec::new();
+            write_bytes(&mut encoded, 1, value.as_bytes());
+            write_bytes(&mut layer, 4, &encoded);
+        }
+        write_field(&mut layer, 5, 0);
+        write_varint(&mut layer, 4096);
+
+        write_bytes(&mut tile, 3, &layer);
+    }
+    tile
+}
+
+fn index_of<'a>(list: &mut Vec<&'a str>, item: &'a str) -> u64 {
+    match list.iter().position(|existing| *existing == item) {
+        Some(i) => i as u64,
+        None => {
+            list.push(item);
+            list.len() as u64 - 1
+        }
+    }
+}
+
+fn encode_geometry(geometry: &TestGeometry) -> (u64, Vec<u64>) {
+    let mut commands = Vec::new();
+    let mut cursor = (0, 0);
+    let mut trace = |commands: &mut Vec<u64>, points: &[(i32, i32)], close: bool| {
+        commands.push(command(1, 1));
+        push_point(commands, &mut cursor, points[0]);
+        if points.len() > 1 {
+            commands.push(command(2, points.len() as u64 - 1));
+            for point in &points[1..] {
+                push_point(commands, &mut cursor, *point);
+            }
+        }
+        if close {
+            commands.push(command(7, 1));
+        }
+    };
+    match geometry {
+        TestGeometry::Polygon(rings) => {
+            for ring in rings {
+                trace(&mut commands, ring, true);
+            }
+            (3, commands)
+        }
+    }
+}
+
+fn command(id: u64, count: u64) -> u64 {
+    (id & 0x7) | (count << 3)
+}
+
+fn push_point(commands: &mut Vec<u64>, cursor: &mut (i32, i32), point: (i32, i32)) {
+    commands.push(zigzag(point.0 - cursor.0));
+    commands.push(zigzag(point.1 - cursor.1));
+    *cursor = point;
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+/// Protobuf style varint, which PMTiles directories use too.
+pub fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_field(buffer: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buffer, (field << 3) | wire_type);
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_field(buffer, field, 2);
+    write_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_packed(buffer: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for value in values {
+        write_varint(&mut packed, *value);
+    }
+    write_bytes(buffer, field, &packed);
+}
+
+/// A png of a single colour.
+pub fn solid_png(size: u32, color: [u8; 4]) -> Vec<u8> {
+    let image = image::RgbaImage::from_pixel(size, size, image::Rgba(color));
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+    png
+}
+
+/// A raster source which gives back the same tile for everything, after taking `delay` to do it like a slow server.
+#[derive(Default)]
+pub struct TestTileSource {
+    pub tile: Vec<u8>,
+    pub delay: Duration,
+    /// How many times a tile has been asked for
+    pub fetched: AtomicUsize,
+}
+
+impl TileSource for TestTileSource {
+    fn name(&self) -> &str {
+        "test"
+    }
+
+    fn kind(&self) -> TileKind {
+        TileKind::Raster
+    }
+
+    fn max_zoom(&self) -> u32 {
+        19
+    }
+
+    fn attribution(&self) -> &str {
+        ""
+    }
+
+    fn get_tile(&self, _x: u64, _y: u64, _zoom: u64) -> Result<Vec<u8>, FetchError> {
+        self.fetched.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(self.delay);
+        Ok(self.tile.clone())
+    }
+}
+
+/// An empty directory in the temp dir which no other test is using.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bevy-ofm-viewer-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A raster source which gets `/{z}/{x}/{y}.png` from the mock server, with its own empty cache and
+/// retries which don't keep the tests waiting.
+pub fn mock_tile_source(server: &MockTileServer, name: &str) -> UrlTileSource {
+    UrlTileSource {
+        cache: Arc::new(TileCache::new(&test_dir(name), "mock", DEFAULT_CACHE_SIZE)),
+        http: Arc::new(HttpClient::new(HttpConfig { timeout: Duration::from_millis(500), backoff: Duration::from_millis(1), ..Default::default() })),
+        ..UrlTileSource::new("mock".to_string(), TileKind::Raster, server.url("/{z}/{x}/{y}.png"))
+    }
+}
+
+/// A vector source which gets `/{z}/{x}/{y}.pbf` from the mock server, otherwise the same as `mock_tile_source`.
+pub fn mock_vector_tile_source(server: &MockTileServer, name: &str) -> UrlTileSource {
+    UrlTileSource {
+        kind: TileKind::Vector,
+        url_template: server.url("/{z}/{x}/{y}.pbf"),
+        ..mock_tile_source(server, name)
+    }
+}
+
+/// Gzips a tile, like a lot of vector tile servers send them.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// What the mock server answers with.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// How long to wait before answering
+    pub delay: Duration,
+    /// Say the body is longer than it is and hang up part way through it
+    pub truncated: bool,
+}
+
+impl MockResponse {
+    pub fn ok(body: Vec<u8>) -> Self {
+        Self { status: 200, headers: Vec::new(), body, delay: Duration::ZERO, truncated: false }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Self { status, ..Self::ok(Vec::new()) }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn delay(self, delay: Duration) -> Self {
+        Self { delay, ..self }
+    }
+
+    pub fn truncated(self) -> Self {
+        Self { truncated: true, ..self }
+    }
+}
+
+/// A request the mock server got, the header names are lowercase.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub path: String,
+    pub headers: HashMap<String, String>,
+}
+
+/// A tile server on localhost for tests which shouldn't need the internet. Each path answers with
+/// its responses in order, and keeps giving the last one once it gets there. Anything else is a 404.
+pub struct MockTileServer {
+    port: u16,
+    routes: Arc<Mutex<HashMap<String, VecDeque<MockResponse>>>>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockTileServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Self {
+            port: listener.local_addr().unwrap().port(),
+            routes: Arc::default(),
+            requests: Arc::default(),
+            stopped: Arc::default(),
+        };
+
+        let (routes, requests, stopped) = (server.routes.clone(), server.requests.clone(), server.stopped.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (routes, requests) = (routes.clone(), requests.clone());
+                // Each on its own thread so a slow response doesn't hold up the others
+                thread::spawn(move || {
+                    let _ = answer(stream, &routes, &requests);
+                });
+            }
+        });
+        server
+    }
+
+    pub fn route(&self, path: &str, responses: Vec<MockResponse>) {
+        self.routes.lock().unwrap().insert(path.to_string(), responses.into());
+    }
+
+    /// The full url for a path on the server, which can be a url template.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub fn requests(&self, path: &str) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().iter().filter(|request| request.path == path).cloned().collect()
+    }
+}
+
+impl Drop for MockTileServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the listener up so it sees it's been stopped
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+fn answer(stream: TcpStream, routes: &Mutex<HashMap<String, VecDeque<MockResponse>>>, requests: &Mutex<Vec<MockRequest>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    requests.lock().unwrap().push(MockRequest { path: path.clone(), headers });
+
+    let response = {
+        let mut routes = routes.lock().unwrap();
+        match routes.get_mut(&path) {
+            Some(responses) if responses.len() > 1 => responses.pop_front().unwrap(),
+            Some(responses) => responses[0].clone(),
+            None => MockResponse::status(404),
+        }
+    };
+    thread::sleep(response.delay);
+
+    let mut stream = stream;
+    let length = if response.truncated { response.body.len() * 2 + 1 } else { response.body.len() };
+    let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, length);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
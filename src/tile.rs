@@ -1,102 +1,150 @@
-use std::{f64::consts::PI, ops::{AddAssign, DivAssign, MulAssign, SubAssign}};
-use bevy::math::Vec2;
+use std::f64::consts::PI;
+use bevy::{math::{DVec2, Vec2}, prelude::Deref};
 
+/// Half way round the earth in web mercator meters, the map goes from minus this to this in both directions.
+pub const MERCATOR_EXTENT: f64 = 20037508.342789244;
+
+// There are a few different ways of saying where something is, and they each get their own type so they can't
+// be mixed up. Anything going from one to another has to go through one of the conversions in here.
+
+/// A place on earth in degrees.
+///
+/// All the maths in here is done in f64, f32 is only good to about a meter this far from the reference at the
+/// deeper zoom levels, which shows up as seams between tiles. Things only get turned into a `Vec2` for bevy.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Coord {
-    pub lat: f32,
-    pub long: f32,
+pub struct LatLon {
+    pub lat: f64,
+    pub long: f64,
 }
 
-impl Coord {
-    pub const fn new(lat: f32, long: f32) -> Self {
+/// Web mercator meters from where the equator crosses the prime meridian, with y going north.
+#[derive(Debug, Clone, Copy, PartialEq, Deref)]
+pub struct WebMercator(pub DVec2);
+
+/// Somewhere on the slippy map tile grid at a zoom level, in tiles from the top left corner of the world with
+/// y going south. The whole part is which tile it's in, and the rest is how far across that tile it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileXY {
+    pub x: f64,
+    pub y: f64,
+    pub zoom: u32,
+}
+
+/// Pixels from the reference at a zoom level with y going north, the same way round as bevy.
+/// This is what the world is laid out in, at `WORLD_ZOOM`.
+#[derive(Debug, Clone, Copy, PartialEq, Deref)]
+pub struct WorldPx(pub DVec2);
+
+impl LatLon {
+    pub const fn new(lat: f64, long: f64) -> Self {
         Self {
             lat,
             long,
         }
     }
 
-    pub fn to_tuple(&self) -> (f32, f32) {
-        (self.lat, self.long)
+    /// Longitude as x and latitude as y, the same way round as the R-tree and everything else which draws maps.
+    pub fn to_xy(&self) -> DVec2 {
+        DVec2::new(self.long, self.lat)
     }
 
     pub fn to_vec2(&self) -> Vec2 {
-        Vec2::new(self.lat, self.long)
+        self.to_xy().as_vec2()
     }
 
-    pub fn to_tile_coords(&self, zoom: u32) -> Tile {
-        let x = ((self.long + 180.0) / 360.0 * (2_i32.pow(zoom) as f32)).floor() as i32;
-        let y = ((1.0 - (self.lat.to_radians().tan() + 1.0 / self.lat.to_radians().cos()).ln() / std::f32::consts::PI) / 2.0 * (2_i32.pow(zoom) as f32)).floor() as i32;
-        Tile {
-            x,
-            y,
-            zoom
+    pub fn to_tile_xy(&self, zoom: u32) -> TileXY {
+        let n = 2f64.powi(zoom as i32);
+        let lat = self.lat.to_radians();
+        TileXY {
+            x: (self.long + 180.0) / 360.0 * n,
+            y: (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n,
+            zoom,
         }
     }
 
-    pub fn to_mercator(&self) -> Vec2 {
-        let lon_rad = self.long.to_radians() as f64;
-        let lat_rad = self.lat.to_radians() as f64;
-        let x = lon_rad * 20037508.34 / std::f64::consts::PI;
-        let y = lat_rad.tan().asinh() * 20037508.34 / std::f64::consts::PI;
+    pub fn to_tile_coords(&self, zoom: u32) -> Tile {
+        self.to_tile_xy(zoom).tile()
+    }
 
-        Vec2::new(x as f32, y as f32)
+    pub fn to_mercator(&self) -> WebMercator {
+        let x = self.long.to_radians() * MERCATOR_EXTENT / PI;
+        let y = self.lat.to_radians().tan().asinh() * MERCATOR_EXTENT / PI;
+        WebMercator(DVec2::new(x, y))
     }
-    
-    pub fn to_game_coords(&self, reference: Coord, zoom: u32, tile_quality: f64) -> Vec2 {
-        let mut ref_coords = Vec2 {x: 1., y: 1.};
-        if reference.lat != 0. && reference.long != 0. {
-            ref_coords = reference.to_mercator();
-        }
-        
-        let meters_per_tile = 20037508.34 * 2.0 / (2.0_f64.powi(zoom as i32)); // At zoom level N
-        let scale = (meters_per_tile / tile_quality) as f32;
 
-        let x = self.long * 20037508.34 / 180.0;
-        let y = (self.lat.to_radians().tan() + 1.0 / self.lat.to_radians().cos()).ln() * 20037508.34 / std::f32::consts::PI;
+    /// Where this is in the world, in pixels at `zoom` from the reference.
+    pub fn to_world(&self, reference: LatLon, zoom: u32, tile_quality: f64) -> WorldPx {
+        WorldPx((*self.to_mercator() - *reference.to_mercator()) / meters_per_pixel(zoom, tile_quality))
+    }
 
-        let x_offset = (x - ref_coords.x) / scale;
-        let y_offset = (y - ref_coords.y) / scale;
+    pub fn to_game_coords(&self, reference: LatLon, zoom: u32, tile_quality: f64) -> Vec2 {
+        self.to_world(reference, zoom, tile_quality).as_vec2()
+    }
+}
 
-        Vec2 {x: x_offset, y: y_offset}
+impl WebMercator {
+    /// The longitude isn't wrapped round, so it goes past 180 for anything past the edge of the map.
+    pub fn to_lat_lon(&self) -> LatLon {
+        let long = self.x / MERCATOR_EXTENT * 180.0;
+        let lat = (self.y / MERCATOR_EXTENT * PI).sinh().atan().to_degrees();
+        LatLon::new(lat, long)
     }
 }
 
-impl SubAssign for Coord {
-    fn sub_assign(&mut self, rhs: Self) {
-        self.lat -= rhs.lat;
-        self.long -= rhs.long;
+impl TileXY {
+    pub fn to_lat_lon(&self) -> LatLon {
+        tile_to_coords(*self)
+    }
+
+    /// Which tile this is in.
+    pub fn tile(&self) -> Tile {
+        Tile::new(self.x.floor() as i32, self.y.floor() as i32, self.zoom)
     }
 }
 
-impl AddAssign for Coord {
-    fn add_assign(&mut self, rhs: Self) {
-        self.lat += rhs.lat;
-        self.long += rhs.long;
+impl From<Tile> for TileXY {
+    /// The top left corner of the tile.
+    fn from(tile: Tile) -> Self {
+        TileXY {
+            x: tile.x as f64,
+            y: tile.y as f64,
+            zoom: tile.zoom,
+        }
     }
 }
 
-impl MulAssign for Coord {
-    fn mul_assign(&mut self, rhs: Self) {
-        self.lat *= rhs.lat;
-        self.long *= rhs.long;
+impl WorldPx {
+    pub fn new(x: f64, y: f64) -> Self {
+        Self(DVec2::new(x, y))
+    }
+
+    /// Only for handing to bevy, once it's relative to the reference it's small enough for f32.
+    pub fn as_vec2(&self) -> Vec2 {
+        self.0.as_vec2()
     }
 }
 
-impl DivAssign for Coord {
-    fn div_assign(&mut self, rhs: Self) {
-        self.lat /= rhs.lat;
-        self.long /= rhs.long;
+impl From<Vec2> for WorldPx {
+    fn from(world: Vec2) -> Self {
+        Self(world.as_dvec2())
     }
 }
 
-pub fn tile_to_coords(x: i32, y: i32, zoom: u32) -> Coord {
-    let n = 2_i32.pow(zoom) as f32;
-    let lon = x as f32 / n * 360.0 - 180.0;
-    let lat_rad = (std::f32::consts::PI * (1.0 - 2.0 * y as f32 / n)).sinh().atan();
+/// How many web mercator meters one pixel covers when tiles at `zoom` are `tile_quality` pixels across.
+fn meters_per_pixel(zoom: u32, tile_quality: f64) -> f64 {
+    let meters_per_tile = MERCATOR_EXTENT * 2.0 / 2f64.powi(zoom as i32);
+    meters_per_tile / tile_quality
+}
+
+pub fn tile_to_coords(tile: TileXY) -> LatLon {
+    let n = 2f64.powi(tile.zoom as i32);
+    let lon = tile.x / n * 360.0 - 180.0;
+    let lat_rad = (PI * (1.0 - 2.0 * tile.y / n)).sinh().atan();
     let lat = lat_rad.to_degrees();
-    Coord::new(lat, lon)
+    LatLon::new(lat, lon)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Tile {
     pub x: i32,
     pub y: i32,
@@ -116,78 +164,97 @@ impl Tile {
         Vec2::new(self.x as f32, self.y as f32)
     }
 
-    pub fn to_lat_long(&self) -> Coord {
-        let n = 2.0f64.powi(self.zoom as i32);
-        let lon_deg = self.x as f64 / n * 360.0 - 180.0;
-        let lat_deg = (PI * (1.0 - 2.0 * self.y as f64  / n)).sinh().atan().to_degrees();
-        Coord::new(lat_deg as f32, normalize_longitude(lon_deg) as f32)
+    /// The top left corner of the tile.
+    pub fn to_lat_long(&self) -> LatLon {
+        let coord = tile_to_coords((*self).into());
+        LatLon::new(coord.lat, normalize_longitude(coord.long))
+    }
+
+    /// Tiles past the edge of the map are on the copies of the world either side of it, so they aren't wrapped round.
+    pub fn to_world(&self, offset: LatLon, zoom: u32, tile_quality: f64) -> WorldPx {
+        TileXY::from(*self).to_lat_lon().to_world(offset, zoom, tile_quality)
     }
 
-    pub fn to_game_coords(&self, offset: Coord, zoom: u32, tile_quality: f64) -> Vec2 {
-        self.to_lat_long().to_game_coords(offset, zoom, tile_quality)
+    pub fn to_game_coords(&self, offset: LatLon, zoom: u32, tile_quality: f64) -> Vec2 {
+        self.to_world(offset, zoom, tile_quality).as_vec2()
     }
 
-    pub fn to_mercator(&self) -> Vec2 {
+    pub fn to_mercator(&self) -> WebMercator {
         self.to_lat_long().to_mercator()
     }
+
+    /// The same tile on the copy of the world which the source has, the map repeats sideways forever.
+    pub fn wrapped(&self) -> Tile {
+        Tile::new(self.x.rem_euclid(1 << self.zoom), self.y, self.zoom)
+    }
 }
 
-pub fn level_to_tile_width(level: u32) -> f32 {
-    360.0 / (2_i32.pow(level) as f32)
+/// Moves `x` along by whole worlds, each `world_width` wide, to the copy of it closest to `near`.
+pub fn nearest_copy(x: f64, near: f64, world_width: f64) -> f64 {
+    x - ((x - near) / world_width).round() * world_width
 }
 
-pub fn world_mercator_to_lat_lon(
-    x_offset: f64,
-    y_offset: f64,
-    reference: Coord, 
-    zoom: u32,
-    quality: f32,
-) -> Coord {
-    // Convert reference point to Web Mercator
-    let refrence = reference.to_mercator();
+/// Past this there are more tiles across the map than fit in the i32s tiles are numbered with.
+pub const MAX_ZOOM: u32 = 30;
 
-    // Calculate meters per pixel (adjust for your tile setup)
-    let meters_per_tile = 20037508.34 * 2.0 / (2.0_f64.powi(zoom as i32)); // At zoom level N
-    let scale = meters_per_tile / quality as f64;
+/// Parses the zoom level given with `flag`.
+pub fn parse_zoom(zoom: &str, flag: &str) -> Result<u32, String> {
+    zoom.trim().parse().ok().filter(|zoom| *zoom <= MAX_ZOOM).ok_or(format!("{} needs a zoom from 0 to {}", flag, MAX_ZOOM))
+}
 
-    // Apply offsets with corrected scale
-    let global_x = refrence.x as f64 + (x_offset * scale);
-    let global_y = refrence.y as f64 + (y_offset * scale);
- 
+/// Parses a `<west>,<south>,<east>,<north>` box, like `--bbox` takes, into its north west and south east corners.
+pub fn parse_bbox(bbox: &str) -> Result<(LatLon, LatLon), String> {
+    let bounds: Vec<f64> = bbox.split(',').map(|bound| bound.trim().parse().ok()).collect::<Option<_>>()
+        .ok_or("--bbox needs four numbers, <west>,<south>,<east>,<north>")?;
+    let [west, south, east, north] = bounds[..] else {
+        return Err("--bbox needs four numbers, <west>,<south>,<east>,<north>".to_string());
+    };
+    if !bounds.iter().all(|bound| bound.is_finite()) {
+        return Err("--bbox needs four numbers, <west>,<south>,<east>,<north>".to_string());
+    }
+    if ![west, east].iter().all(|long| (-180.0..=180.0).contains(long)) || ![south, north].iter().all(|lat| (-90.0..=90.0).contains(lat)) {
+        return Err("--bbox needs longitudes between -180 and 180 and latitudes between -90 and 90".to_string());
+    }
+    if west >= east || south >= north {
+        return Err("--bbox needs west to be less than east and south to be less than north".to_string());
+    }
+    // Web mercator stops short of the poles, so anything past that is the edge of the map
+    let max_lat = TileXY::from(Tile::new(0, 0, 0)).to_lat_lon().lat;
+    Ok((LatLon::new(north.min(max_lat), west), LatLon::new(south.max(-max_lat), east)))
+}
 
-    // Inverse Mercator to convert back to lat/lon
-    let lon = (global_x / 20037508.34) * 180.0;
-    let lat = (global_y / 20037508.34 * 180.0).to_radians();
-    let lat = 2.0 * lat.exp().atan() - std::f64::consts::FRAC_PI_2;
-    let lat = lat.to_degrees();
-   
-    Coord::new(lat as f32, normalize_longitude(lon) as f32)
+pub fn level_to_tile_width(level: u32) -> f64 {
+    360.0 / 2f64.powi(level as i32)
 }
 
-pub fn lat_lon_to_world_mercator_with_offset(
-    lat: f64,
-    lon: f64,
-    reference: Coord,
+pub fn world_mercator_to_lat_lon(
+    world: WorldPx,
+    reference: LatLon,
     zoom: u32,
-    quality: u32,
-) -> (f64, f64) {
-    // Convert reference point to Web Mercator
-    
-    let refrence = reference.to_mercator();
-
-    // Calculate meters per pixel (adjust for your tile setup)
-    let meters_per_tile = 20037508.34 * 2.0 / (2.0_f64.powi(zoom as i32)); // At zoom level N
-    let scale = meters_per_tile / quality as f64;
-
-    // Convert lat/lon to world mercator coordinates
-    let x = lon * 20037508.34 / 180.0;
-    let y = (lat.to_radians().tan() + 1.0 / lat.to_radians().cos()).ln() * 20037508.34 / std::f64::consts::PI;
+    quality: f64,
+) -> LatLon {
+    let coord = world_to_unwrapped_lat_lon(world, reference, zoom, quality);
+    LatLon::new(coord.lat, normalize_longitude(coord.long))
+}
 
-    // Apply offsets with corrected scale
-    let x_offset = (x - refrence.x as f64) / scale;
-    let y_offset = (y - refrence.y as f64) / scale;
+/// Same as `world_mercator_to_lat_lon`, but the longitude isn't wrapped round, so somewhere just past the
+/// antimeridian from the reference stays just past it rather than jumping to the other side of the world.
+pub fn world_to_unwrapped_lat_lon(
+    world: WorldPx,
+    reference: LatLon,
+    zoom: u32,
+    quality: f64,
+) -> LatLon {
+    WebMercator(*reference.to_mercator() + *world * meters_per_pixel(zoom, quality)).to_lat_lon()
+}
 
-    (x_offset, y_offset)
+pub fn lat_lon_to_world_mercator_with_offset(
+    coord: LatLon,
+    reference: LatLon,
+    zoom: u32,
+    quality: f64,
+) -> WorldPx {
+    coord.to_world(reference, zoom, quality)
 }
 
 fn normalize_longitude(lon: f64) -> f64 {
@@ -199,4 +266,130 @@ fn normalize_longitude(lon: f64) -> f64 {
         lon += 360.0;
     }
     lon
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // About a centimetre
+    const TOLERANCE_DEGREES: f64 = 1e-7;
+
+    /// Spread over the whole map, web mercator stops at about 85 degrees north and south.
+    fn places() -> impl Iterator<Item = LatLon> {
+        (-8..=8).flat_map(|lat| (-17..=17).map(move |long| LatLon::new(lat as f64 * 10.5 + 0.123_456_789, long as f64 * 10.5 + 0.987_654_321)))
+    }
+
+    fn assert_close(a: LatLon, b: LatLon) {
+        assert!((a.lat - b.lat).abs() < TOLERANCE_DEGREES && (a.long - b.long).abs() < TOLERANCE_DEGREES, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn lat_lon_to_world_and_back_at_every_zoom() {
+        let reference = LatLon::new(52.2, 0.18);
+        for zoom in 0..=19 {
+            for place in places() {
+                let world = lat_lon_to_world_mercator_with_offset(place, reference, zoom, 256.0);
+                assert_close(world_mercator_to_lat_lon(world, reference, zoom, 256.0), place);
+            }
+        }
+    }
+
+    #[test]
+    fn lat_lon_to_tile_to_world_and_back_at_every_zoom() {
+        let reference = LatLon::new(52.2, 0.18);
+        for zoom in 0..=19 {
+            for place in places() {
+                let tile = place.to_tile_coords(zoom);
+                // The tile's corner is north west of the place, and the place is less than a tile away from it
+                let corner = tile.to_world(reference, zoom, 256.0);
+                let world = place.to_world(reference, zoom, 256.0);
+                let inside = *world - *corner;
+                assert!(inside.x >= -1e-6 && inside.x < 256.0 && inside.y <= 1e-6 && inside.y > -256.0, "{:?} isn't in {:?}", place, tile);
+
+                assert_close(world_mercator_to_lat_lon(corner, reference, zoom, 256.0), tile.to_lat_long());
+                assert_close(place.to_tile_xy(zoom).to_lat_lon(), place);
+            }
+        }
+    }
+
+    #[test]
+    fn deep_zoom_is_accurate_far_from_the_reference() {
+        // A place on the other side of the world from the reference, at the deepest zoom
+        let reference = LatLon::new(52.2, 0.18);
+        let place = LatLon::new(-33.856_784, 151.215_297);
+        let world = place.to_world(reference, 19, 256.0);
+        let next_to_it = world_mercator_to_lat_lon(WorldPx::new(world.x + 1.0, world.y), reference, 19, 256.0);
+        // A pixel at zoom 19 is about 30cm, which still has to be a different place
+        assert!(next_to_it.long - place.long > 1e-6);
+        assert_close(world_mercator_to_lat_lon(world, reference, 19, 256.0), place);
+    }
+
+    #[test]
+    fn each_space_has_the_right_origin_and_direction() {
+        let null_island = LatLon::new(0.0, 0.0);
+        assert_eq!(null_island.to_mercator(), WebMercator(DVec2::ZERO));
+        assert_eq!(null_island.to_tile_xy(1), TileXY { x: 1.0, y: 1.0, zoom: 1 });
+
+        // North east is up and right in mercator and the world, but the tile grid goes down from the top
+        let north_east = LatLon::new(10.0, 10.0);
+        assert!(north_east.to_mercator().x > 0.0 && north_east.to_mercator().y > 0.0);
+        assert!(north_east.to_world(null_island, 3, 256.0).x > 0.0 && north_east.to_world(null_island, 3, 256.0).y > 0.0);
+        assert_eq!(north_east.to_tile_coords(1), Tile::new(1, 0, 1));
+
+        // The top left and top right of the whole map
+        assert_close(TileXY::from(Tile::new(0, 0, 4)).to_lat_lon(), LatLon::new(85.051_128_779_806_59, -180.0));
+        assert_close(WebMercator(DVec2::splat(MERCATOR_EXTENT)).to_lat_lon(), LatLon::new(85.051_128_779_806_59, 180.0));
+    }
+
+    #[test]
+    fn tiles_repeat_past_the_antimeridian() {
+        assert_eq!(Tile::new(-1, 3, 3).wrapped(), Tile::new(7, 3, 3));
+        assert_eq!(Tile::new(8, 3, 3).wrapped(), Tile::new(0, 3, 3));
+        assert_eq!(Tile::new(21, 3, 3).wrapped(), Tile::new(5, 3, 3));
+        assert_eq!(Tile::new(5, 3, 3).wrapped(), Tile::new(5, 3, 3));
+
+        // The copy just past the edge carries straight on from the last tile
+        let reference = LatLon::new(0.0, 179.9);
+        let last = Tile::new(7, 3, 3).to_world(reference, 3, 256.0);
+        let past_the_edge = Tile::new(8, 3, 3).to_world(reference, 3, 256.0);
+        assert!((*past_the_edge - *last - DVec2::new(256.0, 0.0)).length() < 1e-6);
+        // and the unwrapped longitude goes on past 180
+        let just_past = LatLon::new(0.0, 180.1).to_world(reference, 3, 256.0);
+        assert_close(world_to_unwrapped_lat_lon(just_past, reference, 3, 256.0), LatLon::new(0.0, 180.1));
+        assert_close(world_mercator_to_lat_lon(just_past, reference, 3, 256.0), LatLon::new(0.0, -179.9));
+    }
+
+    #[test]
+    fn nearest_copy_of_the_world() {
+        assert_eq!(nearest_copy(10.0, 0.0, 100.0), 10.0);
+        assert_eq!(nearest_copy(90.0, 0.0, 100.0), -10.0);
+        assert_eq!(nearest_copy(-290.0, 0.0, 100.0), 10.0);
+        assert_eq!(nearest_copy(10.0, 480.0, 100.0), 510.0);
+    }
+
+    #[test]
+    fn x_is_longitude() {
+        let cambridge = LatLon::new(52.2, 0.18);
+        assert_eq!(cambridge.to_xy(), DVec2::new(0.18, 52.2));
+        assert_eq!(cambridge.to_vec2(), Vec2::new(0.18, 52.2));
+    }
+
+    #[test]
+    fn bbox_is_west_south_east_north() {
+        assert_eq!(parse_bbox("-0.2, 51.4, 0.1, 51.6"), Ok((LatLon::new(51.6, -0.2), LatLon::new(51.4, 0.1))));
+        assert!(parse_bbox("0.1,51.4,-0.2,51.6").is_err());
+        assert!(parse_bbox("-0.2,51.6,0.1,51.4").is_err());
+        assert!(parse_bbox("-0.2,51.4,0.1").is_err());
+        assert!(parse_bbox("-0.2,51.4,0.1,north").is_err());
+        assert!(parse_bbox("NaN,0,1,1").is_err());
+        assert!(parse_bbox("0,0,inf,1").is_err());
+        assert!(parse_bbox("-500,-100,500,100").is_err());
+        assert!(parse_bbox("-180,-10,180.5,10").is_err());
+        assert!(parse_bbox("-10,-90.5,10,10").is_err());
+        // Past the edge of the map is the edge of the map
+        let (north_west, south_east) = parse_bbox("-180,-90,180,90").unwrap();
+        assert_close(north_west, LatLon::new(85.051_128_779_806_59, -180.0));
+        assert_close(south_east, LatLon::new(-85.051_128_779_806_59, 180.0));
+    }
+}
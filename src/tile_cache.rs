@@ -0,0 +1,443 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use bevy::log::warn;
+
+pub const CACHE_DIR: &str = "cache";
+pub const DEFAULT_CACHE_SIZE: u64 = 512 * 1024 * 1024;
+// How long a tile is good for when the server doesn't say, OSM asks for at least a week
+const DEFAULT_FRESHNESS: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// When we go over the budget, clear out a bit extra so we aren't evicting on every single write
+const EVICT_TO: f64 = 0.9;
+
+/// The parts of a response which say how long it can be kept and how to check if it's changed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CacheHeaders {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Seconds since the unix epoch after which the tile has to be revalidated with the server
+    pub expires: u64,
+}
+
+/// What we know about a cached tile, kept next to it in a `.meta` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Seconds since the unix epoch after which the tile has to be revalidated with the server
+    pub expires: u64,
+    /// crc32 of the tile, so we can tell if it got corrupted on disk
+    pub checksum: u32,
+    pub length: u64,
+}
+
+impl CacheMeta {
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+            "expires": self.expires,
+            "checksum": self.checksum,
+            "length": self.length,
+        }).to_string()
+    }
+
+    fn from_json(json: &str) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_str(json).ok()?;
+        Some(Self {
+            etag: value["etag"].as_str().map(|etag| etag.to_string()),
+            last_modified: value["last_modified"].as_str().map(|last_modified| last_modified.to_string()),
+            expires: value["expires"].as_u64()?,
+            checksum: value["checksum"].as_u64()? as u32,
+            length: value["length"].as_u64()?,
+        })
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        unix_now() < self.expires
+    }
+}
+
+pub enum CacheLookup {
+    Fresh(Vec<u8>),
+    /// We have it but it's expired, so it should be revalidated with the server before being used
+    Stale(Vec<u8>, CacheMeta),
+    Missing,
+}
+
+/// The tiles for one source, stored as `cache/<namespace>/<z>/<x>/<y>.<ext>` with a `.meta` file next to each.
+/// The least recently used tiles are thrown away once the namespace goes over `max_bytes`.
+#[derive(Debug)]
+pub struct TileCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    // Built the first time it's needed, as it has to look at every file in the cache
+    index: Mutex<Option<CacheIndex>>,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    /// Size and when it was last used, for every tile in the cache
+    entries: HashMap<PathBuf, (u64, SystemTime)>,
+    total_bytes: u64,
+}
+
+impl TileCache {
+    pub fn new(root: &Path, namespace: &str, max_bytes: u64) -> Self {
+        Self {
+            dir: root.join(namespace),
+            max_bytes,
+            index: Mutex::new(None),
+        }
+    }
+
+    /// The cache for a source in the usual cache directory.
+    pub fn for_source(name: &str, url_template: &str, max_bytes: u64) -> Self {
+        Self::new(Path::new(CACHE_DIR), &cache_namespace(name, url_template), max_bytes)
+    }
+
+    fn tile_path(&self, x: u64, y: u64, zoom: u64, extension: &str) -> PathBuf {
+        self.dir.join(zoom.to_string()).join(x.to_string()).join(format!("{}.{}", y, extension))
+    }
+
+    pub fn get(&self, x: u64, y: u64, zoom: u64, extension: &str) -> CacheLookup {
+        let path = self.tile_path(x, y, zoom, extension);
+        let Ok(data) = fs::read(&path) else {
+            return CacheLookup::Missing;
+        };
+        let meta = fs::read_to_string(meta_path(&path)).ok().and_then(|json| CacheMeta::from_json(&json));
+        let Some(meta) = meta.filter(|meta| meta.length == data.len() as u64 && meta.checksum == checksum(&data)) else {
+            warn!("Cached tile {} is corrupt, throwing it away", path.display());
+            self.remove(&path);
+            return CacheLookup::Missing;
+        };
+
+        self.touch(&path);
+        if meta.is_fresh() {
+            CacheLookup::Fresh(data)
+        } else {
+            CacheLookup::Stale(data, meta)
+        }
+    }
+
+    pub fn put(&self, x: u64, y: u64, zoom: u64, extension: &str, data: &[u8], headers: CacheHeaders) -> io::Result<()> {
+        let path = self.tile_path(x, y, zoom, extension);
+        let meta = CacheMeta {
+            etag: headers.etag,
+            last_modified: headers.last_modified,
+            expires: headers.expires,
+            checksum: checksum(data),
+            length: data.len() as u64,
+        };
+        let meta_json = meta.to_json();
+        // The tile goes first, so there's never a meta file which says a half written tile is fine
+        write_atomically(&path, data)?;
+        write_atomically(&meta_path(&path), meta_json.as_bytes())?;
+
+        let mut index = self.index.lock().unwrap();
+        let index = index.get_or_insert_with(|| CacheIndex::scan(&self.dir));
+        // The meta file takes up room too, which adds up with lots of small or empty tiles
+        index.insert(path, (data.len() + meta_json.len()) as u64, SystemTime::now());
+        if index.total_bytes > self.max_bytes {
+            index.evict((self.max_bytes as f64 * EVICT_TO) as u64);
+        }
+        Ok(())
+    }
+
+    /// The server said our copy is still good, so it just needs a new expiry.
+    pub fn revalidated(&self, x: u64, y: u64, zoom: u64, extension: &str, mut meta: CacheMeta, headers: CacheHeaders) -> io::Result<()> {
+        meta.expires = headers.expires;
+        meta.etag = headers.etag.or(meta.etag);
+        meta.last_modified = headers.last_modified.or(meta.last_modified);
+        let path = self.tile_path(x, y, zoom, extension);
+        let meta_json = meta.to_json();
+        write_atomically(&meta_path(&path), meta_json.as_bytes())?;
+        // The new meta file might not be the same size as the old one
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            index.insert(path, meta.length + meta_json.len() as u64, SystemTime::now());
+        }
+        Ok(())
+    }
+
+    fn touch(&self, path: &Path) {
+        let now = SystemTime::now();
+        // The modified time is how we remember what was used recently between runs
+        if let Ok(file) = fs::File::options().write(true).open(path) {
+            let _ = file.set_modified(now);
+        }
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            if let Some(entry) = index.entries.get_mut(path) {
+                entry.1 = now;
+            }
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(meta_path(path));
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            index.remove(path);
+        }
+    }
+}
+
+impl CacheIndex {
+    fn scan(dir: &Path) -> Self {
+        let mut index = Self::default();
+        let mut to_visit = vec![dir.to_path_buf()];
+        while let Some(dir) = to_visit.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    to_visit.push(path);
+                } else if !path.to_string_lossy().ends_with(".meta") && !path.to_string_lossy().contains(".tmp") {
+                    let meta_size = fs::metadata(meta_path(&path)).map(|meta| meta.len()).unwrap_or(0);
+                    index.insert(path, metadata.len() + meta_size, metadata.modified().unwrap_or(UNIX_EPOCH));
+                }
+            }
+        }
+        index
+    }
+
+    fn insert(&mut self, path: PathBuf, size: u64, last_used: SystemTime) {
+        if let Some((old_size, _)) = self.entries.insert(path, (size, last_used)) {
+            self.total_bytes -= old_size;
+        }
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((size, _)) = self.entries.remove(path) {
+            self.total_bytes -= size;
+        }
+    }
+
+    /// Throws away the least recently used tiles until there's no more than `target` bytes left.
+    fn evict(&mut self, target: u64) {
+        let mut by_age: Vec<(PathBuf, SystemTime)> = self.entries.iter().map(|(path, (_, last_used))| (path.clone(), *last_used)).collect();
+        by_age.sort_by_key(|(_, last_used)| *last_used);
+        for (path, _) in by_age {
+            if self.total_bytes <= target {
+                break;
+            }
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(meta_path(&path));
+            self.remove(&path);
+        }
+    }
+}
+
+fn meta_path(path: &Path) -> PathBuf {
+    let mut meta = path.as_os_str().to_owned();
+    meta.push(".meta");
+    PathBuf::from(meta)
+}
+
+/// Writes to a temporary file and renames it into place, so a crash or another thread reading can never see half a file.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".tmp{}-{}", std::process::id(), NEXT_TEMP.fetch_add(1, Ordering::Relaxed)));
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Source names can be anything, so only keep the characters which are safe in a directory name,
+/// and add a hash of the url so two sources with the same name don't share tiles.
+pub fn cache_namespace(name: &str, url_template: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    format!("{}-{:08x}", name, checksum(url_template.as_bytes()))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+/// Works out when a response stops being fresh from its `Cache-Control` and `Expires` headers,
+/// `None` if it shouldn't be cached at all.
+pub fn expiry_from_headers(cache_control: Option<&str>, expires: Option<&str>, now: u64) -> Option<u64> {
+    if let Some(cache_control) = cache_control {
+        let mut max_age = None;
+        for directive in cache_control.split(',').map(|directive| directive.trim().to_ascii_lowercase()) {
+            if directive == "no-store" {
+                return None;
+            } else if directive == "no-cache" {
+                // Can be kept, but has to be checked every time
+                max_age = Some(0);
+            } else if let Some(seconds) = directive.strip_prefix("max-age=") {
+                max_age = max_age.or(seconds.trim_matches('"').parse().ok());
+            }
+        }
+        if let Some(max_age) = max_age {
+            return Some(now.saturating_add(max_age));
+        }
+    }
+    if let Some(expires) = expires.and_then(parse_http_date) {
+        return Some(expires);
+    }
+    Some(now + DEFAULT_FRESHNESS.as_secs())
+}
+
+/// Parses the date format HTTP uses, like `Sun, 06 Nov 1994 08:49:37 GMT`, into seconds since the unix epoch.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"].iter().position(|name| name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok().filter(|year| *year >= 1970)?;
+    let time: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let [hours, minutes, seconds] = time.as_slice() else {
+        return None;
+    };
+    // 60 seconds for a leap second
+    if *hours > 23 || *minutes > 59 || *seconds > 60 {
+        return None;
+    }
+
+    // Days since 1970 from the civil calendar, http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era.checked_mul(146097)?.checked_add(day_of_era)?.checked_sub(719468)?;
+    days.checked_mul(86400)?.checked_add(hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_dir;
+
+    fn test_cache(name: &str, max_bytes: u64) -> TileCache {
+        TileCache::new(&test_dir(name), "source", max_bytes)
+    }
+
+    fn fresh() -> CacheHeaders {
+        CacheHeaders { expires: unix_now() + 60, ..Default::default() }
+    }
+
+    #[test]
+    fn tiles_come_back_out_fresh_until_they_expire() {
+        let cache = test_cache("fresh", 1024);
+        cache.put(1, 2, 3, "png", b"tile", CacheHeaders { etag: Some("\"abc\"".to_string()), last_modified: None, expires: unix_now() + 60 }).unwrap();
+        assert!(matches!(cache.get(1, 2, 3, "png"), CacheLookup::Fresh(data) if data == b"tile"));
+
+        cache.put(1, 2, 3, "png", b"tile", CacheHeaders { etag: Some("\"abc\"".to_string()), last_modified: None, expires: unix_now() - 1 }).unwrap();
+        let CacheLookup::Stale(data, meta) = cache.get(1, 2, 3, "png") else {
+            panic!("Expired tile should be stale");
+        };
+        assert_eq!(data, b"tile");
+        assert_eq!(meta.etag.as_deref(), Some("\"abc\""));
+        assert!(matches!(cache.get(2, 2, 3, "png"), CacheLookup::Missing));
+    }
+
+    #[test]
+    fn corrupt_tiles_are_thrown_away() {
+        let cache = test_cache("corrupt", 1024);
+        cache.put(1, 2, 3, "png", b"tile", fresh()).unwrap();
+        fs::write(cache.tile_path(1, 2, 3, "png"), b"tide").unwrap();
+        assert!(matches!(cache.get(1, 2, 3, "png"), CacheLookup::Missing));
+        assert!(!cache.tile_path(1, 2, 3, "png").exists());
+    }
+
+    #[test]
+    fn least_recently_used_tiles_are_evicted() {
+        // Room for two tiles and their meta files but not three
+        let meta_size = fs::metadata(meta_path(&put_one("evict-meta-size"))).unwrap().len();
+        let cache = test_cache("evict", (10 + meta_size) * 5 / 2);
+        cache.put(0, 0, 1, "png", &[0; 10], fresh()).unwrap();
+        cache.put(1, 0, 1, "png", &[1; 10], fresh()).unwrap();
+        // Using the first one makes the second one the oldest
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(cache.get(0, 0, 1, "png"), CacheLookup::Fresh(_)));
+        cache.put(0, 1, 1, "png", &[2; 10], fresh()).unwrap();
+
+        assert!(matches!(cache.get(0, 0, 1, "png"), CacheLookup::Fresh(_)));
+        assert!(matches!(cache.get(1, 0, 1, "png"), CacheLookup::Missing));
+        assert!(matches!(cache.get(0, 1, 1, "png"), CacheLookup::Fresh(_)));
+    }
+
+    /// Puts a 10 byte tile in a cache of its own, and gives back where it went.
+    fn put_one(name: &str) -> PathBuf {
+        let cache = test_cache(name, 1000);
+        cache.put(0, 0, 1, "png", &[0; 10], fresh()).unwrap();
+        cache.tile_path(0, 0, 1, "png")
+    }
+
+    #[test]
+    fn meta_files_count_towards_the_size() {
+        let dir = test_dir("evict-empty");
+        let cache = TileCache::new(&dir, "source", 1000);
+        for x in 0..50 {
+            cache.put(x, 0, 7, "png", &[], fresh()).unwrap();
+        }
+        let mut on_disk = 0;
+        let mut to_visit = vec![dir];
+        while let Some(dir) = to_visit.pop() {
+            for entry in fs::read_dir(dir).unwrap().flatten() {
+                let metadata = entry.metadata().unwrap();
+                if metadata.is_dir() {
+                    to_visit.push(entry.path());
+                } else {
+                    on_disk += metadata.len();
+                }
+            }
+        }
+        assert!(on_disk > 0 && on_disk <= 1000, "{} bytes on disk", on_disk);
+        // and a fresh index adds them up the same way
+        assert_eq!(CacheIndex::scan(&cache.dir).total_bytes, on_disk);
+    }
+
+    #[test]
+    fn sources_with_the_same_name_get_their_own_namespace() {
+        assert_ne!(cache_namespace("custom_raster", "https://a.example/{z}/{x}/{y}.png"), cache_namespace("custom_raster", "https://b.example/{z}/{x}/{y}.png"));
+        assert!(cache_namespace("my/../source", "").starts_with("my____source-"));
+    }
+
+    #[test]
+    fn cache_headers_set_the_expiry() {
+        assert_eq!(expiry_from_headers(Some("public, max-age=3600"), None, 1000), Some(4600));
+        assert_eq!(expiry_from_headers(Some("no-cache"), None, 1000), Some(1000));
+        assert_eq!(expiry_from_headers(Some("no-store"), None, 1000), None);
+        assert_eq!(expiry_from_headers(None, Some("Sun, 06 Nov 1994 08:49:37 GMT"), 0), Some(784111777));
+        assert_eq!(expiry_from_headers(None, None, 1000), Some(1000 + DEFAULT_FRESHNESS.as_secs()));
+        assert_eq!(expiry_from_headers(Some("max-age=18446744073709551615"), None, 1000), Some(u64::MAX));
+    }
+
+    #[test]
+    fn malformed_http_dates_are_ignored() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Sun, 00 Mar 2020 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Mar 2020 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 0000 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1969 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 99999999999999999 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 99999999999999999:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:60:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:61 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+    }
+}
@@ -0,0 +1,606 @@
+use std::{cmp::Ordering, collections::BinaryHeap, fmt, sync::{atomic::{self, AtomicUsize}, Arc}};
+
+use bevy::{prelude::*, tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, IoTaskPool, Task}};
+
+use crate::{http::{CancelFlag, FetchError}, labels::{LabelRenderer, MapLabels}, ofm_api::decode_tile_data, style::{MapStyle, Style}, tile::Tile, tile_map::{ChunkManager, LoadedTile, TileData, ZoomManager}, tile_mesh::{ofm_to_mesh, RenderMode}, tile_source::{ActiveTileSource, TileKind, TileSource}};
+
+// Enough to keep things moving without hammering the tile server, OSM asks for no more than a couple per client
+const MAX_IN_FLIGHT: usize = 4;
+
+/// A tile which a chunk is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileRequest {
+    pub chunk_pos: IVec2,
+    pub tile: Tile,
+    /// The `ChunkManager` generation when it was asked for
+    pub generation: u32,
+}
+
+#[derive(Debug)]
+struct QueuedRequest {
+    request: TileRequest,
+    /// How many chunks away from the camera it is, closer ones are loaded first
+    distance: f32,
+    /// Requests which are the same distance away are loaded in the order they came in
+    sequence: u64,
+}
+
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedRequest {}
+
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedRequest {
+    // BinaryHeap pops the biggest first, so the closest and oldest requests are the biggest
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance).then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Requests waiting for a worker, nearest the camera first.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    heap: BinaryHeap<QueuedRequest>,
+    next_sequence: u64,
+}
+
+impl RequestQueue {
+    pub fn push(&mut self, request: TileRequest, camera_chunk_pos: IVec2) {
+        let distance = request.chunk_pos.as_vec2().distance(camera_chunk_pos.as_vec2());
+        self.heap.push(QueuedRequest { request, distance, sequence: self.next_sequence });
+        self.next_sequence += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<TileRequest> {
+        self.heap.pop().map(|queued| queued.request)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Re-sorts the queue around where the camera is now, and cancels anything from another zoom level or
+    /// further than `range` chunks away. Gives back the cancelled requests.
+    pub fn reprioritize(&mut self, zoom: u32, camera_chunk_pos: IVec2, range: i32) -> Vec<TileRequest> {
+        let mut cancelled = Vec::new();
+        let mut kept = BinaryHeap::with_capacity(self.heap.len());
+        for mut queued in self.heap.drain() {
+            if !queued.request.is_wanted(zoom, camera_chunk_pos, range) {
+                cancelled.push(queued.request);
+                continue;
+            }
+            queued.distance = queued.request.chunk_pos.as_vec2().distance(camera_chunk_pos.as_vec2());
+            kept.push(queued);
+        }
+        self.heap = kept;
+        cancelled
+    }
+
+    /// Cancels everything, gives back what was cancelled.
+    pub fn clear(&mut self) -> Vec<TileRequest> {
+        self.heap.drain().map(|queued| queued.request).collect()
+    }
+}
+
+/// Tiles which are waiting to be loaded. Loading happens in tasks on Bevy's task pools, with each tile
+/// being loaded having its own entity, so despawning the entity cancels it.
+#[derive(Resource, Default)]
+pub struct TileLoader {
+    queue: RequestQueue,
+    /// Fetches which haven't finished yet, including cancelled ones which are still waiting on the server
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl TileLoader {
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn request(&mut self, request: TileRequest, camera_chunk_pos: IVec2) {
+        self.queue.push(request, camera_chunk_pos);
+    }
+
+    /// Re-sorts the queue around the camera and cancels everything, queued or being loaded, which is from
+    /// another zoom level or has gone out of range. Gives back what was cancelled.
+    pub fn reprioritize(
+        &mut self,
+        commands: &mut Commands,
+        pending_query: &Query<(Entity, &PendingTile)>,
+        zoom: u32,
+        camera_chunk_pos: IVec2,
+        range: i32,
+    ) -> Vec<TileRequest> {
+        let mut cancelled = self.queue.reprioritize(zoom, camera_chunk_pos, range);
+        for (entity, pending) in pending_query.iter() {
+            if !pending.0.is_wanted(zoom, camera_chunk_pos, range) {
+                commands.entity(entity).despawn();
+                cancelled.push(pending.0);
+            }
+        }
+        cancelled
+    }
+}
+
+impl TileRequest {
+    fn is_wanted(&self, zoom: u32, camera_chunk_pos: IVec2, range: i32) -> bool {
+        let offset = (self.chunk_pos - camera_chunk_pos).abs();
+        self.tile.zoom == zoom && offset.x <= range && offset.y <= range
+    }
+}
+
+/// Why a tile couldn't be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileError {
+    Fetch(FetchError),
+    /// The bytes weren't an image we could read
+    Image(String),
+    /// The bytes weren't a vector tile we could read
+    Vector(String),
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::Fetch(e) => write!(f, "couldn't fetch it, {}", e),
+            TileError::Image(e) => write!(f, "couldn't decode the image, {}", e),
+            TileError::Vector(e) => write!(f, "couldn't decode the vector tile, {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TileError {}
+
+impl From<FetchError> for TileError {
+    fn from(e: FetchError) -> Self {
+        TileError::Fetch(e)
+    }
+}
+
+/// A tile which is being loaded.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct PendingTile(pub TileRequest);
+
+/// Getting the bytes can block on the network or the disk so that happens on the io pool,
+/// then drawing them happens on the compute pool.
+#[derive(Component)]
+pub enum TileTask {
+    Fetching(FetchTask),
+    Decoding(Task<Result<TileData, TileError>>),
+}
+
+/// A tile being fetched on the io pool. Dropping it tells the fetch to give up, but one which is already
+/// waiting on the server carries on until it gets an answer or times out.
+pub struct FetchTask {
+    task: Task<Result<Vec<u8>, FetchError>>,
+    cancel: CancelFlag,
+}
+
+/// Counts a fetch as in flight until it's finished or been dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, atomic::Ordering::SeqCst);
+        Self(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+impl FetchTask {
+    pub fn spawn(source: Arc<dyn TileSource>, tile: Tile, in_flight: &Arc<AtomicUsize>) -> Self {
+        let cancel = CancelFlag::default();
+        let flag = cancel.clone();
+        let in_flight = InFlight::start(in_flight);
+        let task = IoTaskPool::get().spawn(async move {
+            let _in_flight = in_flight;
+            source.get_tile_cancellable(tile.x as u64, tile.y as u64, tile.zoom as u64, &flag)
+        });
+        Self { task, cancel }
+    }
+
+    /// The fetched tile once it's done.
+    pub fn poll(&mut self) -> Option<Result<Vec<u8>, FetchError>> {
+        block_on(future::poll_once(&mut self.task))
+    }
+}
+
+impl Drop for FetchTask {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Starts loading the most important tiles in the queue, as long as there aren't too many being loaded already.
+/// Cancelled fetches still count until they've finished, so panning about can't pile up requests to the server.
+pub fn start_tile_tasks(
+    mut commands: Commands,
+    mut tile_loader: ResMut<TileLoader>,
+    tile_source: Res<ActiveTileSource>,
+) {
+    while tile_loader.in_flight() < MAX_IN_FLIGHT {
+        let Some(request) = tile_loader.queue.pop() else {
+            break;
+        };
+        let task = FetchTask::spawn(tile_source.0.clone(), request.tile, &tile_loader.in_flight);
+        commands.spawn((PendingTile(request), TileTask::Fetching(task)));
+    }
+}
+
+/// Moves fetched tiles on to being decoded, and hands decoded tiles to the tile map.
+#[allow(clippy::too_many_arguments)]
+pub fn poll_tile_tasks(
+    mut commands: Commands,
+    mut task_query: Query<(Entity, &PendingTile, &mut TileTask)>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+    tile_source: Res<ActiveTileSource>,
+    style: Res<MapStyle>,
+    labels: Res<MapLabels>,
+    render_mode: Res<RenderMode>,
+) {
+    for (entity, pending, mut task) in task_query.iter_mut() {
+        match &mut *task {
+            TileTask::Fetching(fetching) => {
+                let Some(fetched) = fetching.poll() else {
+                    continue;
+                };
+                let request = pending.0;
+                let data = match fetched {
+                    Ok(data) => data,
+                    Err(e) => {
+                        commands.entity(entity).despawn();
+                        chunk_manager.receive(LoadedTile { chunk_pos: request.chunk_pos, tile: request.tile, generation: request.generation, data: Err(e.into()) });
+                        continue;
+                    }
+                };
+                let (kind, style, labels, render_mode, tile_size) = (tile_source.kind(), style.clone(), labels.clone(), *render_mode, zoom_manager.tile_size as u32);
+                let decoding = AsyncComputeTaskPool::get().spawn(async move {
+                    decode_tile(kind, data, &style, &labels, render_mode, &request.tile, tile_size)
+                });
+                *task = TileTask::Decoding(decoding);
+            }
+            TileTask::Decoding(decoding) => {
+                let Some(data) = block_on(future::poll_once(decoding)) else {
+                    continue;
+                };
+                commands.entity(entity).despawn();
+                let request = pending.0;
+                // Nowhere wants it any more, so the labels it placed won't ever be seen
+                if !chunk_manager.receive(LoadedTile { chunk_pos: request.chunk_pos, tile: request.tile, generation: request.generation, data }) {
+                    labels.forget_tile(&request.tile);
+                }
+            }
+        }
+    }
+}
+
+/// Turns the bytes from the source into whatever the render mode draws with.
+fn decode_tile(kind: TileKind, data: Vec<u8>, style: &Style, labels: &LabelRenderer, render_mode: RenderMode, tile: &Tile, tile_size: u32) -> Result<TileData, TileError> {
+    let (x, y, zoom) = (tile.x as u64, tile.y as u64, tile.zoom as u64);
+    if data.is_empty() {
+        return Ok(TileData::Empty);
+    }
+    // Raster sources can only ever be images
+    if render_mode == RenderMode::Mesh && kind == TileKind::Vector {
+        return Ok(TileData::Mesh(ofm_to_mesh(data, tile_size, zoom as u32, style)?));
+    }
+    Ok(TileData::Image(decode_tile_data(kind, data, style, labels, x, y, zoom, tile_size)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{args::Args, http::{HttpClient, HttpConfig}, labels::labels_from_args, style::style_from_args, test_support::{encode_mvt, gzip, mock_tile_source, mock_vector_tile_source, solid_png, square, MockResponse, MockTileServer, TestFeature, TestGeometry, TestTileSource}, tile_source::{TileSource, UrlTileSource}};
+
+    use super::*;
+
+    fn request(x: i32, y: i32, zoom: u32) -> TileRequest {
+        TileRequest { chunk_pos: IVec2::new(x, y), tile: Tile::new(x, y, zoom), generation: 0 }
+    }
+
+    #[test]
+    fn closest_tiles_load_first() {
+        let mut queue = RequestQueue::default();
+        queue.push(request(4, 4, 14), IVec2::ZERO);
+        queue.push(request(1, 0, 14), IVec2::ZERO);
+        queue.push(request(0, 2, 14), IVec2::ZERO);
+        queue.push(request(0, 1, 14), IVec2::ZERO);
+        assert_eq!(queue.pop(), Some(request(1, 0, 14)));
+        assert_eq!(queue.pop(), Some(request(0, 1, 14)));
+        assert_eq!(queue.pop(), Some(request(0, 2, 14)));
+        assert_eq!(queue.pop(), Some(request(4, 4, 14)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn moving_the_camera_changes_the_order_and_cancels_what_went_out_of_range() {
+        let mut queue = RequestQueue::default();
+        queue.push(request(0, 0, 14), IVec2::ZERO);
+        queue.push(request(3, 0, 14), IVec2::ZERO);
+        queue.push(request(5, 0, 14), IVec2::ZERO);
+        queue.push(request(5, 1, 13), IVec2::ZERO);
+
+        let cancelled = queue.reprioritize(14, IVec2::new(6, 0), 4);
+        assert_eq!(cancelled.len(), 2);
+        assert!(cancelled.contains(&request(0, 0, 14)));
+        assert!(cancelled.contains(&request(5, 1, 13)));
+        assert_eq!(queue.pop(), Some(request(5, 0, 14)));
+        assert_eq!(queue.pop(), Some(request(3, 0, 14)));
+        assert!(queue.is_empty());
+    }
+
+    fn headless_app(delay: Duration) -> App {
+        headless_app_with(TestTileSource { tile: solid_png(256, [255, 0, 0, 255]), delay, ..Default::default() })
+    }
+
+    fn headless_app_with(source: impl TileSource + 'static) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(ActiveTileSource(Arc::new(source)))
+            .insert_resource(MapStyle(Arc::new(style_from_args(&Args::default()).unwrap())))
+            .insert_resource(MapLabels(Arc::new(labels_from_args(&Args::default()).unwrap())))
+            .insert_resource(RenderMode::Raster)
+            .insert_resource(ZoomManager::default())
+            .insert_resource(ChunkManager::default())
+            .init_resource::<TileLoader>()
+            .add_systems(Update, (start_tile_tasks, poll_tile_tasks).chain());
+        app
+    }
+
+    /// Asks for the tile the same way `spawn_chunks_around_camera` does.
+    fn ask_for(app: &mut App, request: TileRequest) {
+        app.world_mut().resource_mut::<ChunkManager>().loading.insert(request.chunk_pos, request.tile);
+        app.world_mut().resource_mut::<TileLoader>().request(request, IVec2::ZERO);
+    }
+
+    /// Same as `detect_zoom_level` does to the chunks when the zoom changes.
+    fn forget_chunks(app: &mut App) {
+        let mut chunk_manager = app.world_mut().resource_mut::<ChunkManager>();
+        chunk_manager.loading.clear();
+        chunk_manager.generation += 1;
+    }
+
+    fn update_until_nothing_is_pending(app: &mut App) {
+        for _ in 0..1000 {
+            app.update();
+            if app.world_mut().query::<&PendingTile>().iter(app.world()).count() == 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Tiles never finished loading");
+    }
+
+    #[test]
+    fn tiles_load_on_the_task_pools_without_a_window() {
+        let mut app = headless_app(Duration::ZERO);
+        for x in 0..6 {
+            ask_for(&mut app, request(x, 0, 14));
+        }
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        assert_eq!(chunk_manager.to_spawn_chunks.len(), 6);
+        let Some(LoadedTile { data: Ok(TileData::Image(data)), .. }) = chunk_manager.to_spawn_chunks.get(&IVec2::new(3, 0)) else {
+            panic!("Tile wasn't loaded as an image");
+        };
+        assert_eq!(data.len(), 256 * 256 * 4);
+        assert_eq!(&data[0..4], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn corrupt_tile_comes_back_as_an_error() {
+        let mut app = headless_app_with(TestTileSource { tile: b"<html>Not Found</html>".to_vec(), ..Default::default() });
+        ask_for(&mut app, request(0, 0, 14));
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        let Some(LoadedTile { data: Err(TileError::Image(_)), .. }) = chunk_manager.to_spawn_chunks.get(&IVec2::new(0, 0)) else {
+            panic!("Corrupt tile should have failed to decode");
+        };
+    }
+
+    #[test]
+    fn tiles_load_from_a_tile_server() {
+        let server = MockTileServer::start();
+        server.route("/14/0/0.png", vec![MockResponse::ok(solid_png(256, [0, 0, 255, 255]))]);
+        let mut app = headless_app_with(mock_tile_source(&server, "tile-loader"));
+        ask_for(&mut app, request(0, 0, 14));
+        ask_for(&mut app, request(1, 0, 14));
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        let Some(LoadedTile { data: Ok(TileData::Image(data)), .. }) = chunk_manager.to_spawn_chunks.get(&IVec2::new(0, 0)) else {
+            panic!("Tile wasn't loaded from the server");
+        };
+        assert_eq!(&data[0..4], &[0, 0, 255, 255]);
+        // Nothing at that address, which just means there's nothing to draw
+        assert!(matches!(chunk_manager.to_spawn_chunks.get(&IVec2::new(1, 0)), Some(LoadedTile { data: Ok(TileData::Empty), .. })));
+    }
+
+    /// Two tiles with a lake in the top left quarter, the second one gzipped. The server has to be kept until they've loaded.
+    fn vector_app(render_mode: RenderMode, name: &str) -> (App, MockTileServer) {
+        let server = MockTileServer::start();
+        let lake = encode_mvt(&[("water", vec![TestFeature::new(TestGeometry::Polygon(vec![square(0, 2048)]))])]);
+        server.route("/14/0/0.pbf", vec![MockResponse::ok(lake.clone())]);
+        server.route("/14/1/0.pbf", vec![MockResponse::ok(gzip(&lake))]);
+        let mut app = headless_app_with(mock_vector_tile_source(&server, name));
+        app.insert_resource(render_mode);
+        (app, server)
+    }
+
+    #[test]
+    fn vector_tiles_are_drawn_into_images() {
+        let (mut app, _server) = vector_app(RenderMode::Raster, "tile-loader-vector-raster");
+        ask_for(&mut app, request(0, 0, 14));
+        ask_for(&mut app, request(1, 0, 14));
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        for x in 0..2 {
+            let Some(LoadedTile { data: Ok(TileData::Image(data)), .. }) = chunk_manager.to_spawn_chunks.get(&IVec2::new(x, 0)) else {
+                panic!("Vector tile {} wasn't drawn", x);
+            };
+            let pixel = |x: usize, y: usize| &data[(y * 256 + x) * 4..(y * 256 + x) * 4 + 4];
+            // Water in the top left, nothing in the bottom right
+            assert!(pixel(32, 32)[2] > 0 && pixel(32, 32)[0] == 0, "{:?}", pixel(32, 32));
+            assert_eq!(pixel(200, 200)[3], 0);
+        }
+    }
+
+    #[test]
+    fn vector_tiles_are_made_into_meshes() {
+        let (mut app, _server) = vector_app(RenderMode::Mesh, "tile-loader-vector-mesh");
+        ask_for(&mut app, request(0, 0, 14));
+        ask_for(&mut app, request(1, 0, 14));
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        for x in 0..2 {
+            let Some(LoadedTile { data: Ok(TileData::Mesh(mesh)), .. }) = chunk_manager.to_spawn_chunks.get(&IVec2::new(x, 0)) else {
+                panic!("Vector tile {} wasn't made into a mesh", x);
+            };
+            assert!(mesh.count_vertices() >= 4);
+            assert!(mesh.indices().is_some_and(|indices| indices.len() >= 6));
+        }
+    }
+
+    #[test]
+    fn missing_tile_is_empty() {
+        let mut app = headless_app_with(TestTileSource::default());
+        ask_for(&mut app, request(0, 0, 14));
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        assert!(matches!(chunk_manager.to_spawn_chunks.get(&IVec2::new(0, 0)), Some(LoadedTile { data: Ok(TileData::Empty), .. })));
+    }
+
+    #[test]
+    fn despawning_the_entity_cancels_the_load() {
+        let mut app = headless_app(Duration::ZERO);
+        ask_for(&mut app, request(0, 0, 14));
+        app.update();
+
+        let pending: Vec<Entity> = app.world_mut().query_filtered::<Entity, With<PendingTile>>().iter(app.world()).collect();
+        assert_eq!(pending.len(), 1);
+        app.world_mut().despawn(pending[0]);
+        for _ in 0..20 {
+            app.update();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(app.world().resource::<ChunkManager>().to_spawn_chunks.is_empty());
+    }
+
+    #[test]
+    fn despawning_the_entity_stops_the_retries() {
+        let server = MockTileServer::start();
+        server.route("/14/0/0.png", vec![MockResponse::status(503)]);
+        let source = UrlTileSource {
+            http: Arc::new(HttpClient::new(HttpConfig { backoff: Duration::from_millis(50), max_retries: 20, ..Default::default() })),
+            ..mock_tile_source(&server, "tile-loader-cancel")
+        };
+        let mut app = headless_app_with(source);
+        ask_for(&mut app, request(0, 0, 14));
+        app.update();
+        for _ in 0..1000 {
+            if !server.requests("/14/0/0.png").is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let pending: Vec<Entity> = app.world_mut().query_filtered::<Entity, With<PendingTile>>().iter(app.world()).collect();
+        app.world_mut().despawn(pending[0]);
+        std::thread::sleep(Duration::from_millis(500));
+        // Without being told to stop it would have gone on asking every 50-100ms
+        assert!(server.requests("/14/0/0.png").len() <= 2);
+    }
+
+    #[test]
+    fn cancelled_fetches_count_until_they_finish() {
+        let mut app = headless_app(Duration::from_millis(300));
+        for x in 0..4 {
+            ask_for(&mut app, request(x, 0, 14));
+        }
+        app.update();
+        // Panned away, so they're all cancelled, but any which have started are still waiting on the source
+        let pending: Vec<Entity> = app.world_mut().query_filtered::<Entity, With<PendingTile>>().iter(app.world()).collect();
+        assert_eq!(pending.len(), 4);
+        for entity in pending {
+            app.world_mut().despawn(entity);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        for x in 4..8 {
+            ask_for(&mut app, request(x, 0, 14));
+        }
+        app.update();
+
+        let started = app.world_mut().query::<&PendingTile>().iter(app.world()).count();
+        assert!(started < 4);
+        assert!(app.world().resource::<TileLoader>().in_flight() <= MAX_IN_FLIGHT);
+        // and the held back ones start once they have
+        for _ in 0..1000 {
+            app.update();
+            if app.world().resource::<ChunkManager>().to_spawn_chunks.len() == 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(app.world().resource::<ChunkManager>().to_spawn_chunks.len(), 4);
+        assert_eq!(app.world().resource::<TileLoader>().in_flight(), 0);
+    }
+
+    #[test]
+    fn slow_tile_from_before_a_zoom_change_is_dropped() {
+        let mut app = headless_app(Duration::from_millis(50));
+        ask_for(&mut app, request(0, 0, 14));
+        app.update();
+
+        // Zoomed in while it was loading, so the chunk wants a zoom 15 tile now
+        forget_chunks(&mut app);
+        ask_for(&mut app, TileRequest { chunk_pos: IVec2::ZERO, tile: Tile::new(0, 0, 15), generation: 1 });
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        assert_eq!(chunk_manager.to_spawn_chunks.len(), 1);
+        assert_eq!(chunk_manager.to_spawn_chunks[&IVec2::ZERO].tile, Tile::new(0, 0, 15));
+        assert!(chunk_manager.loading.is_empty());
+    }
+
+    #[test]
+    fn slow_tile_is_moved_to_the_chunk_which_wants_it_now() {
+        let mut app = headless_app(Duration::from_millis(50));
+        ask_for(&mut app, TileRequest { chunk_pos: IVec2::ZERO, tile: Tile::new(5, 5, 14), generation: 0 });
+        app.update();
+
+        // Zoomed out and back in while it was loading, and the same tile is now in a different chunk
+        forget_chunks(&mut app);
+        ask_for(&mut app, TileRequest { chunk_pos: IVec2::new(2, 3), tile: Tile::new(5, 5, 14), generation: 1 });
+        update_until_nothing_is_pending(&mut app);
+
+        let chunk_manager = app.world().resource::<ChunkManager>();
+        assert_eq!(chunk_manager.to_spawn_chunks.len(), 1);
+        let loaded = &chunk_manager.to_spawn_chunks[&IVec2::new(2, 3)];
+        assert_eq!(loaded.tile, Tile::new(5, 5, 14));
+        assert_eq!(loaded.generation, 1);
+    }
+}
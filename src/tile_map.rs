@@ -1,28 +1,32 @@
-use std::thread;
+use std::collections::BTreeMap;
 
 // Thank you for the example: https://github.com/StarArawn/bevy_ecs_tilemap/blob/main/examples/chunking.rs
-use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use bevy::{math::I64Vec2, prelude::*, utils::{HashMap, HashSet}, window::PrimaryWindow};
 use bevy_ecs_tilemap::{map::{TilemapGridSize, TilemapId, TilemapTexture, TilemapTileSize}, tiles::{TileBundle, TilePos, TileStorage}, TilemapBundle, TilemapPlugin};
-use crossbeam_channel::{bounded, Receiver, Sender};
 
-use crate::{ofm_api::{buffer_to_bevy_image, get_rasta_data}, tile::{world_mercator_to_lat_lon, Coord}, STARTING_DISPLACEMENT, STARTING_LONG_LAT, TILE_QUALITY};
+use crate::{labels::MapLabels, ofm_api::buffer_to_bevy_image, tile::{world_to_unwrapped_lat_lon, LatLon, Tile, MAX_ZOOM}, tile_loader::{poll_tile_tasks, start_tile_tasks, PendingTile, TileError, TileLoader, TileRequest}, tile_mesh::TileMeshMaterial, tile_source::ActiveTileSource, STARTING_DISPLACEMENT, STARTING_LONG_LAT, TILE_QUALITY, WORLD_ZOOM};
 
-// For this example, don't choose too large a chunk size.
+// Each chunk is exactly one tile from the source
 const CHUNK_SIZE: UVec2 = UVec2 { x: 1, y: 1 };
+// How many chunks either side of the camera are loaded
+const CHUNK_RANGE: i32 = 4;
 
 pub struct TileMapPlugin;
 
 impl Plugin for TileMapPlugin {
     fn build(&self, app: &mut App) {
-        let (tx, rx): (Sender<(IVec2, Vec<u8>)>, Receiver<(IVec2, Vec<u8>)>) = bounded(10);
-        app.insert_resource(ChunkReceiver(rx))  // Store receiver globally
-            .insert_resource(ChunkSender(tx))
-            .add_plugins(TilemapPlugin)
+        app.add_plugins(TilemapPlugin)
             .insert_resource(ChunkManager::default())
             .insert_resource(ZoomManager::default())
-            .add_systems(Update, (spawn_chunks_around_camera, spawn_to_needed_chunks))
-            .add_systems(Update, detect_zoom_level)
-            .add_systems(FixedUpdate, (despawn_outofrange_chunks, read_map_receiver));
+            .init_resource::<TileMeshMaterial>()
+            .init_resource::<DecodedTiles>()
+            .init_resource::<TileLoader>()
+            .init_resource::<ErrorTileImage>()
+            .add_systems(Update, (spawn_chunks_around_camera, start_tile_tasks, poll_tile_tasks, spawn_to_needed_chunks).chain())
+            .add_systems(Update, (detect_zoom_level, retry_failed_tiles))
+            // After the camera has moved for this frame, but before the transforms are passed on to the renderer
+            .add_systems(PostUpdate, rebase_origin.before(TransformSystem::TransformPropagate))
+            .add_systems(FixedUpdate, (despawn_outofrange_chunks, despawn_stale_chunks));
     }
 }
 
@@ -37,7 +41,7 @@ pub struct ZoomManager {
 impl Default for ZoomManager {
     fn default() -> Self {
         Self {
-            zoom_level: 14,
+            zoom_level: WORLD_ZOOM,
             last_zoom_level: 0,
             last_projection_level: 0.0,
             tile_size: TILE_QUALITY as f32
@@ -48,9 +52,18 @@ impl Default for ZoomManager {
 #[derive(Debug, Resource)]
 pub struct ChunkManager {
     pub spawned_chunks: HashSet<IVec2>,
-    pub to_spawn_chunks: HashMap<IVec2, Vec<u8>>, // Store raw image data
+    pub to_spawn_chunks: HashMap<IVec2, LoadedTile>,
+    /// Chunks which have been asked for but haven't arrived yet, and the tile each one is waiting for
+    pub loading: HashMap<IVec2, Tile>,
+    /// What's being shown in place of the chunks which are still loading
+    pub placeholders: HashMap<IVec2, Entity>,
     pub update: bool, // Store raw image data
-    pub refrence_long_lat: Coord,
+    /// What the world is measured from, this gets moved to wherever the camera is when the camera gets far
+    /// away from it, so the world coordinates stay small enough for f32 anywhere on earth
+    pub refrence_long_lat: LatLon,
+    /// Goes up every time the chunks stop meaning what they did, like when the zoom level changes,
+    /// so we can tell which requests were made for the chunks as they are now.
+    pub generation: u32,
 }
 
 impl Default for ChunkManager {
@@ -58,15 +71,36 @@ impl Default for ChunkManager {
         Self {
             spawned_chunks: HashSet::default(),
             to_spawn_chunks: HashMap::default(),
+            loading: HashMap::default(),
+            placeholders: HashMap::default(),
             update: true,
             refrence_long_lat: STARTING_LONG_LAT,
+            generation: 0,
         }
     }
 }
 
+impl ChunkManager {
+    /// Takes a tile which has finished loading, as long as a chunk still wants it. Tiles which were asked for
+    /// before the chunks changed go wherever that tile is wanted now, or get dropped if nowhere wants them.
+    pub fn receive(&mut self, mut loaded: LoadedTile) -> bool {
+        let wanted_here = loaded.generation == self.generation && self.loading.get(&loaded.chunk_pos) == Some(&loaded.tile);
+        if !wanted_here {
+            let Some(chunk_pos) = self.loading.iter().find(|(_, tile)| **tile == loaded.tile).map(|(chunk_pos, _)| *chunk_pos) else {
+                return false;
+            };
+            loaded.chunk_pos = chunk_pos;
+            loaded.generation = self.generation;
+        }
+        self.loading.remove(&loaded.chunk_pos);
+        self.to_spawn_chunks.insert(loaded.chunk_pos, loaded);
+        true
+    }
+}
+
 #[derive(Resource, Debug, Clone, Copy, PartialEq)]
 pub struct Location {
-    pub location: Coord,
+    pub location: LatLon,
 }
 
 impl Default for Location {
@@ -77,69 +111,291 @@ impl Default for Location {
     }
 }
 
+/// The most zoomed out level we load tiles for.
+const MIN_ZOOM: u32 = 3;
+
+/// How big a tile at this zoom level is in world units, which are pixels at `WORLD_ZOOM`.
+pub fn world_tile_size(tile_size: f32, zoom: u32) -> f32 {
+    tile_size * 2f32.powi(WORLD_ZOOM as i32 - zoom as i32)
+}
+
+/// Works out which level of tiles to load from how far the camera is zoomed. The world doesn't change size
+/// when we change level, so the tiles which are already there just get scaled up or down by the camera
+/// and stay put underneath until the new level's tiles have loaded over them.
 fn detect_zoom_level(
+    mut commands: Commands,
     mut chunk_manager: ResMut<ChunkManager>,
     mut zoom_manager: ResMut<ZoomManager>,
-    mut ortho_projection_query: Query<&mut OrthographicProjection, With<Camera>>,
-    chunk_query: Query<(Entity, &TileMarker)>,
-    mut camera_query: Query<&mut Transform, With<Camera>>,
-    commands: Commands,
-    location_manager: ResMut<Location>,
+    ortho_projection_query: Query<&OrthographicProjection, With<Camera>>,
+    mut chunk_query: Query<(Entity, &mut Transform, &MapChunk), Without<StaleChunk>>,
+    tile_source: Res<ActiveTileSource>,
 ) {
-    if let Ok(mut projection) = ortho_projection_query.get_single_mut() {
-        if let Ok(mut camera) = camera_query.get_single_mut() {
-            if projection.scale != zoom_manager.last_projection_level {
-                zoom_manager.last_projection_level = projection.scale;
-                if projection.scale > 1. && projection.scale != 0. && zoom_manager.zoom_level > 3 {
-                    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
-                    zoom_manager.zoom_level -= 1;
-
-                    despawn_all_chunks(commands, chunk_query);
-                    chunk_manager.spawned_chunks.clear();
-                    chunk_manager.to_spawn_chunks.clear();
-
-                    // This ensures that the tile size stays correct
-                    chunk_manager.refrence_long_lat *= Coord {lat: 2., long: 2.};
-
-                    camera.translation = location_manager.location.to_game_coords(chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size.into()).extend(1.0);
-                    
-                    projection.scale = 1.0;
-                } else if projection.scale < 1. && projection.scale != 0. && zoom_manager.zoom_level < 19 {
-                    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
-                    zoom_manager.zoom_level += 1;
-
-                    despawn_all_chunks(commands, chunk_query);
-                    chunk_manager.spawned_chunks.clear();
-                    chunk_manager.to_spawn_chunks.clear();
-
-                    // This ensures that the tile size stays correct
-                    chunk_manager.refrence_long_lat /= Coord {lat: 2., long: 2.};
-                    
-                    camera.translation = location_manager.location.to_game_coords(chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size.into()).extend(1.0);
-
-                    projection.scale = 1.0;
-                }
-                chunk_manager.update = true;
+    let Ok(projection) = ortho_projection_query.get_single() else {
+        return;
+    };
+    if projection.scale == zoom_manager.last_projection_level || projection.scale <= 0.0 {
+        return;
+    }
+    zoom_manager.last_projection_level = projection.scale;
+    // More of the map might be on screen now
+    chunk_manager.update = true;
+
+    // The scale is 1 at WORLD_ZOOM and halves every time we zoom in a level
+    let zoom = WORLD_ZOOM as f32 - projection.scale.log2();
+    // Past the source's max zoom we keep showing its most detailed tiles, just bigger. A file can say it goes
+    // further than we can number the tiles though
+    let zoom_level = (zoom.round().max(MIN_ZOOM as f32) as u32).min(tile_source.max_zoom()).min(MAX_ZOOM);
+    if zoom_level == zoom_manager.zoom_level {
+        return;
+    }
+    zoom_manager.last_zoom_level = zoom_manager.zoom_level;
+    zoom_manager.zoom_level = zoom_level;
+
+    for (entity, mut transform, chunk) in chunk_query.iter_mut() {
+        // Below the new level's tiles, with the more detailed old levels on top of the less detailed ones
+        transform.translation.z = chunk.zoom as f32 / 100.0 - 1.0;
+        commands.entity(entity).insert(StaleChunk);
+    }
+    // What's still loading for the old level gets cancelled the next time chunks are spawned
+    chunk_manager.spawned_chunks.clear();
+    chunk_manager.to_spawn_chunks.clear();
+    chunk_manager.loading.clear();
+    chunk_manager.generation += 1;
+    // The placeholders have been made stale along with everything else
+    chunk_manager.placeholders.clear();
+}
+
+// How far the camera can get from the origin, in pixels on screen, before the origin is moved to it. f32 has
+// about 7 significant digits, so this keeps everything on screen accurate to well under a pixel at any zoom.
+const REBASE_DISTANCE: f32 = 4096.0;
+
+/// Keeps the numbers in the world small wherever on earth we are. Once the camera gets far enough from the
+/// reference, the reference is moved to it and everything in the world is moved back by the same amount.
+/// The chunks are numbered by their tiles, so they don't care where the reference is.
+fn rebase_origin(
+    mut camera_query: Query<(&mut Transform, &OrthographicProjection), With<Camera>>,
+    mut chunk_query: Query<&mut Transform, (With<MapChunk>, Without<Camera>)>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+) {
+    let Ok((mut camera_transform, projection)) = camera_query.get_single_mut() else {
+        return;
+    };
+    let camera_pos = camera_transform.translation.xy();
+    if camera_pos.abs().max_element() < REBASE_DISTANCE * projection.scale {
+        return;
+    }
+    // Whole pixels, so taking it off everything doesn't lose anything to rounding
+    let shift = camera_pos.round();
+
+    // Not wrapped round, or things on the other side of the antimeridian would jump a whole world away
+    let reference = chunk_manager.refrence_long_lat;
+    chunk_manager.refrence_long_lat = world_to_unwrapped_lat_lon(shift.into(), reference, WORLD_ZOOM, zoom_manager.tile_size.into());
+
+    camera_transform.translation -= shift.extend(0.0);
+    for mut transform in chunk_query.iter_mut() {
+        transform.translation -= shift.extend(0.0);
+    }
+}
+
+// Past this a placeholder would be cut out of less than 8 pixels of its ancestor, which isn't worth showing
+const MAX_PLACEHOLDER_DEPTH: u32 = 5;
+// Enough for a few screens worth of tiles at a few zoom levels, a 256 pixel tile takes up 256KiB
+const DECODED_TILES_BUDGET: usize = 256 * 1024 * 1024;
+
+/// Tiles which we've already decoded into images, so chunks can be put straight back when we pan back over them,
+/// and a part of them can be shown in place of their children while the children load.
+#[derive(Resource)]
+pub struct DecodedTiles {
+    /// The image for each (source, tile), how many bytes it takes up and when it was last used
+    images: HashMap<(String, Tile), (Handle<Image>, usize, u64)>,
+    // Keyed by when they were last used, so the least recently used is first and we know which to forget when we're over budget
+    order: BTreeMap<u64, (String, Tile)>,
+    // Goes up every time a tile is used
+    clock: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Default for DecodedTiles {
+    fn default() -> Self {
+        Self::new(DECODED_TILES_BUDGET)
+    }
+}
+
+impl DecodedTiles {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            images: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Gives back the tiles which were forgotten to make room for it.
+    pub fn insert(&mut self, source: &str, tile: Tile, image: Handle<Image>, bytes: usize) -> Vec<(String, Tile)> {
+        let key = (source.to_string(), tile);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        if let Some((_, old_bytes, last_used)) = self.images.insert(key, (image, bytes, self.clock)) {
+            self.bytes -= old_bytes;
+            self.order.remove(&last_used);
+        }
+        self.bytes += bytes;
+        let mut forgotten = Vec::new();
+        while self.bytes > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, bytes, _)) = self.images.remove(&oldest) {
+                self.bytes -= bytes;
+                forgotten.push(oldest);
             }
         }
+        forgotten
+    }
+
+    pub fn get(&mut self, source: &str, tile: &Tile) -> Option<Handle<Image>> {
+        let key = (source.to_string(), *tile);
+        self.clock += 1;
+        let (image, _, last_used) = self.images.get_mut(&key)?;
+        let used_before = std::mem::replace(last_used, self.clock);
+        let image = image.clone();
+        self.order.remove(&used_before);
+        self.order.insert(self.clock, key);
+        Some(image)
+    }
+
+    /// Finds the closest ancestor we have an image for, and the part of that image which covers the tile.
+    pub fn nearest_ancestor(&mut self, source: &str, tile: &Tile, tile_size: f32) -> Option<(Handle<Image>, Rect)> {
+        (1..=MAX_PLACEHOLDER_DEPTH.min(tile.zoom)).find_map(|depth| {
+            let ancestor = Tile::new(tile.x >> depth, tile.y >> depth, tile.zoom - depth);
+            let image = self.get(source, &ancestor)?;
+            // Which of the ancestor's 2^depth by 2^depth pieces the tile is
+            let piece = IVec2::new(tile.x - (ancestor.x << depth), tile.y - (ancestor.y << depth)).as_vec2();
+            let piece_size = tile_size / (1 << depth) as f32;
+            Some((image, Rect::from_corners(piece * piece_size, (piece + 1.0) * piece_size)))
+        })
     }
 }
 
-#[derive(Resource, Deref)]
-pub struct ChunkReceiver(Receiver<(IVec2, Vec<u8>)>); // Use Vec<u8> for raw image data
+/// What a loaded tile has turned into, depending on the render mode.
+#[derive(Debug)]
+pub enum TileData {
+    /// Raw rgba data
+    Image(Vec<u8>),
+    Mesh(Mesh),
+    /// The source doesn't have this tile, so there's nothing to draw
+    Empty,
+}
 
-#[derive(Resource, Deref)]
-pub struct ChunkSender(Sender<(IVec2, Vec<u8>)>);
+/// A tile which has finished loading, and the chunk it was loaded for.
+#[derive(Debug)]
+pub struct LoadedTile {
+    pub chunk_pos: IVec2,
+    pub tile: Tile,
+    /// The `ChunkManager` generation when it was asked for
+    pub generation: u32,
+    pub data: Result<TileData, TileError>,
+}
 
 #[derive(Component)]
 pub struct TileMarker;
 
+/// Which chunk of which zoom level a tilemap or mesh entity is showing.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct MapChunk {
+    pub pos: IVec2,
+    pub zoom: u32,
+}
+
+/// A chunk from a zoom level we've moved away from, which is kept around until the current level has loaded.
+#[derive(Component)]
+pub struct StaleChunk;
+
+/// A chunk showing the error tile because its tile failed to load, right clicking it tries again.
+#[derive(Component, Debug, Clone)]
+pub struct FailedTile {
+    pub tile: Tile,
+    pub error: TileError,
+}
+
+/// What's shown in place of a tile which failed to load.
+#[derive(Resource, Clone, Deref)]
+pub struct ErrorTileImage(pub Handle<Image>);
+
+impl FromWorld for ErrorTileImage {
+    fn from_world(world: &mut World) -> Self {
+        let tile_size = world.get_resource::<ZoomManager>().map(|zoom_manager| zoom_manager.tile_size).unwrap_or(TILE_QUALITY as f32) as u32;
+        let image = buffer_to_bevy_image(error_tile_pixels(tile_size), tile_size);
+        Self(world.resource_mut::<Assets<Image>>().add(image))
+    }
+}
+
+/// A dark tile with a red border and a red cross through it, so it's obvious something went wrong.
+fn error_tile_pixels(size: u32) -> Vec<u8> {
+    let border = (size / 64).max(1);
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let on_border = x < border || y < border || x >= size - border || y >= size - border;
+            let on_cross = x.abs_diff(y) < border || (x + y).abs_diff(size - 1) < border;
+            pixels.extend_from_slice(if on_border || on_cross { &[0xc0, 0x30, 0x30, 0xff] } else { &[0x2a, 0x2a, 0x2a, 0xff] });
+        }
+    }
+    pixels
+}
+
+/// Where the middle of the tile is in the world, the tilemap and the meshes are both centred on their transform.
+fn tile_world_center(tile: &Tile, reference: LatLon, tile_size: f32) -> Vec2 {
+    let corner = tile.to_game_coords(reference, WORLD_ZOOM, tile_size.into());
+    let size = world_tile_size(tile_size, tile.zoom);
+    corner + Vec2::new(size / 2.0, -size / 2.0)
+}
+
+/// Tiles are always drawn at the same resolution, and scaled to the size of their level in the world.
+fn tile_transform(tile: &Tile, reference: LatLon, tile_size: f32, z: f32) -> Transform {
+    Transform::from_translation(tile_world_center(tile, reference, tile_size).extend(z))
+        .with_scale(Vec3::splat(world_tile_size(1.0, tile.zoom)))
+}
+
+/// Chunks go where their tile would be on whichever copy of the world they're in.
+fn chunk_transform(chunk: &MapChunk, reference: LatLon, tile_size: f32, z: f32) -> Transform {
+    tile_transform(&Tile::new(chunk.pos.x, chunk.pos.y, chunk.zoom), reference, tile_size, z)
+}
+
+/// Shows part of an ancestor tile, scaled up, where a tile is going to be. It goes above the stale chunks
+/// but below the real tiles.
+fn spawn_placeholder(
+    commands: &mut Commands,
+    image: Handle<Image>,
+    rect: Rect,
+    chunk: MapChunk,
+    reference: LatLon,
+    tile_size: f32,
+) -> Entity {
+    commands.spawn((
+        Sprite {
+            image,
+            rect: Some(rect),
+            custom_size: Some(Vec2::splat(tile_size)),
+            ..default()
+        },
+        chunk_transform(&chunk, reference, tile_size, -0.5),
+        TileMarker,
+        chunk,
+    )).id()
+}
+
 fn spawn_chunk(
     commands: &mut Commands,
     tile: Handle<Image>,
-    chunk_pos: IVec2,
+    transform: Transform,
+    chunk: MapChunk,
     tile_size: f32,
-) {
+) -> Entity {
     let tilemap_entity = commands.spawn_empty().id();
     let mut tile_storage = TileStorage::empty(CHUNK_SIZE.into());
 
@@ -155,12 +411,6 @@ fn spawn_chunk(
     commands.entity(tilemap_entity).add_child(tile_entity);
     tile_storage.set(&tile_pos, tile_entity);
 
-    let transform = Transform::from_translation(Vec3::new(
-        chunk_pos.x as f32 * CHUNK_SIZE.x as f32 * tile_size,
-        chunk_pos.y as f32 * CHUNK_SIZE.y as f32 * tile_size,
-        0.0,
-    ));
-
     commands.entity(tilemap_entity).insert(TilemapBundle {
         grid_size: TilemapGridSize::from(TilemapTileSize { x: tile_size, y: tile_size }),
         size: CHUNK_SIZE.into(),
@@ -169,64 +419,98 @@ fn spawn_chunk(
         tile_size: TilemapTileSize { x: tile_size, y: tile_size },
         transform,
         ..Default::default()
-    }).insert(TileMarker);
+    }).insert((TileMarker, chunk));
+    tilemap_entity
 }
 
-fn camera_pos_to_chunk_pos(camera_pos: &Vec2, tile_size: f32) -> IVec2 {
-    let chunk_size = Vec2::new(
-        CHUNK_SIZE.x as f32 * tile_size,
-        CHUNK_SIZE.y as f32 * tile_size,
-    );
-    let camera_pos = Vec2::new(camera_pos.x, camera_pos.y) / chunk_size;
-    camera_pos.floor().as_ivec2()
+/// Mesh tiles don't need the tilemap, they go in the same place its tile would.
+fn spawn_mesh_chunk(
+    commands: &mut Commands,
+    mesh: Handle<Mesh>,
+    material: Handle<ColorMaterial>,
+    transform: Transform,
+    chunk: MapChunk,
+) {
+    commands.spawn((
+        Mesh2d(mesh),
+        MeshMaterial2d(material),
+        transform,
+        TileMarker,
+        chunk,
+    ));
 }
 
+/// A chunk's position is the x and y of its tile at the zoom level, so the chunks line up with the tiles
+/// exactly and each one only ever shows its own tile, wherever the reference is.
+fn camera_pos_to_chunk_pos(camera_pos: Vec2, reference: LatLon, zoom: u32, tile_size: f32) -> IVec2 {
+    // Not wrapped round, the copies of the world either side carry on counting
+    let tile = world_to_unwrapped_lat_lon(camera_pos.into(), reference, WORLD_ZOOM, tile_size.into()).to_tile_coords(zoom);
+    IVec2::new(tile.x, tile.y)
+}
 
-fn chunk_pos_to_world_pos(chunk_pos: IVec2, tile_size: f32) -> Vec2 {
-    let chunk_size = Vec2::new(
-        CHUNK_SIZE.x as f32 * tile_size,
-        CHUNK_SIZE.y as f32 * tile_size,
-    );
-    Vec2::new(
-        chunk_pos.x as f32 * chunk_size.x,
-        chunk_pos.y as f32 * chunk_size.y,
-    )
+/// Which of the source's tiles a chunk shows. The map repeats sideways, so chunks past the antimeridian show
+/// the tiles from the other side of it, but there aren't any tiles past the top and bottom of the map.
+fn chunk_pos_to_tile(chunk_pos: IVec2, zoom: u32) -> Option<Tile> {
+    (0..1 << zoom).contains(&chunk_pos.y).then_some(Tile::new(chunk_pos.x, chunk_pos.y, zoom).wrapped())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_chunks_around_camera(
+    mut commands: Commands,
     camera_query: Query<&Transform, With<Camera>>,
-    chunk_sender: Res<ChunkSender>,  // Use the stored sender
     mut chunk_manager: ResMut<ChunkManager>,
     zoom_manager: Res<ZoomManager>,
+    mut tile_loader: ResMut<TileLoader>,
+    pending_query: Query<(Entity, &PendingTile)>,
+    mut decoded_tiles: ResMut<DecodedTiles>,
+    tile_source: Res<ActiveTileSource>,
 ) {
     if chunk_manager.update {
         chunk_manager.update = false;
         for transform in camera_query.iter() {
-            let camera_chunk_pos = camera_pos_to_chunk_pos(&transform.translation.xy(), zoom_manager.tile_size);
-            let range = 4;
+            let camera_chunk_pos = camera_pos_to_chunk_pos(transform.translation.xy(), chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size);
 
-            for y in (camera_chunk_pos.y - range)..=(camera_chunk_pos.y + range) {
-                for x in (camera_chunk_pos.x - range)..=(camera_chunk_pos.x + range) {
+            // Anything still waiting which has gone off screen isn't worth loading any more
+            for cancelled in tile_loader.reprioritize(&mut commands, &pending_query, zoom_manager.zoom_level, camera_chunk_pos, CHUNK_RANGE) {
+                // Chunks from before a zoom change have already been forgotten, and the chunk at that position now is something else
+                if cancelled.generation != chunk_manager.generation {
+                    continue;
+                }
+                chunk_manager.spawned_chunks.remove(&cancelled.chunk_pos);
+                chunk_manager.loading.remove(&cancelled.chunk_pos);
+                if let Some(placeholder) = chunk_manager.placeholders.remove(&cancelled.chunk_pos) {
+                    if let Some(mut entity) = commands.get_entity(placeholder) {
+                        entity.despawn();
+                    }
+                }
+            }
+
+            for y in (camera_chunk_pos.y - CHUNK_RANGE)..=(camera_chunk_pos.y + CHUNK_RANGE) {
+                for x in (camera_chunk_pos.x - CHUNK_RANGE)..=(camera_chunk_pos.x + CHUNK_RANGE) {
                     let chunk_pos = IVec2::new(x, y);
                     if !chunk_manager.spawned_chunks.contains(&chunk_pos) {
-                        let tx = chunk_sender.clone(); // Clone existing sender
-                        let zoom_manager = zoom_manager.clone();
-                        let world_pos = chunk_pos_to_world_pos(chunk_pos, zoom_manager.tile_size);
-                        let position = world_mercator_to_lat_lon(world_pos.x.into(), world_pos.y.into(), chunk_manager.refrence_long_lat, zoom_manager.zoom_level, zoom_manager.tile_size);
-
-                        thread::spawn(move || {
-                            let tile_coords = position.to_tile_coords(zoom_manager.zoom_level);
-
-                            
-                            
-                            // let tile_image = get_mvt_data(tile_coords.x as u64, tile_coords.y as u64, zoom_manager.zoom_level as u64, zoom_manager.tile_size as u32);
-                            let tile_image = get_rasta_data(tile_coords.x as u64, tile_coords.y as u64, zoom_manager.zoom_level as u64);
-                            if let Err(e) = tx.send((chunk_pos, tile_image)) {
-                                eprintln!("Failed to send chunk data: {:?}", e);
-                            }
-                        });
-
+                        let Some(tile_coords) = chunk_pos_to_tile(chunk_pos, zoom_manager.zoom_level) else {
+                            continue;
+                        };
+
+                        let chunk = MapChunk { pos: chunk_pos, zoom: tile_coords.zoom };
+
+                        // We've seen this one before, so it can go straight back without loading it again
+                        if let Some(image) = decoded_tiles.get(tile_source.name(), &tile_coords) {
+                            let transform = chunk_transform(&chunk, chunk_manager.refrence_long_lat, zoom_manager.tile_size, 0.0);
+                            spawn_chunk(&mut commands, image, transform, chunk, zoom_manager.tile_size);
+                            chunk_manager.spawned_chunks.insert(chunk_pos);
+                            continue;
+                        }
+
+                        tile_loader.request(TileRequest { chunk_pos, tile: tile_coords, generation: chunk_manager.generation }, camera_chunk_pos);
+
+                        if let Some((image, rect)) = decoded_tiles.nearest_ancestor(tile_source.name(), &tile_coords, zoom_manager.tile_size) {
+                            let placeholder = spawn_placeholder(&mut commands, image, rect, chunk, chunk_manager.refrence_long_lat, zoom_manager.tile_size);
+                            chunk_manager.placeholders.insert(chunk_pos, placeholder);
+                        }
                         chunk_manager.spawned_chunks.insert(chunk_pos);
+                        chunk_manager.loading.insert(chunk_pos, tile_coords);
                     }
                 }
             }
@@ -234,64 +518,344 @@ fn spawn_chunks_around_camera(
     }
 }
 
-fn read_map_receiver(
-    map_receiver: Res<ChunkReceiver>,
+#[allow(clippy::too_many_arguments)]
+fn spawn_to_needed_chunks(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mesh_material: Res<TileMeshMaterial>,
     mut chunk_manager: ResMut<ChunkManager>,
+    mut decoded_tiles: ResMut<DecodedTiles>,
+    zoom_manager: Res<ZoomManager>,
+    tile_source: Res<ActiveTileSource>,
+    error_image: Res<ErrorTileImage>,
+    labels: Res<MapLabels>,
 ) {
-    let mut new_chunks = Vec::new();
+    let to_spawn_chunks: Vec<LoadedTile> = chunk_manager.to_spawn_chunks.drain().map(|(_, loaded)| loaded).collect();
+    for loaded in to_spawn_chunks {
+        // The real tile is here, so the stand in can go
+        if let Some(placeholder) = chunk_manager.placeholders.remove(&loaded.chunk_pos) {
+            if let Some(mut entity) = commands.get_entity(placeholder) {
+                entity.despawn();
+            }
+        }
+
+        let chunk = MapChunk { pos: loaded.chunk_pos, zoom: loaded.tile.zoom };
+        let transform = chunk_transform(&chunk, chunk_manager.refrence_long_lat, zoom_manager.tile_size, 0.0);
+        match loaded.data {
+            Ok(TileData::Image(raw_image_data)) => {
+                let bytes = raw_image_data.len();
+                let tile_handle = images.add(buffer_to_bevy_image(raw_image_data, zoom_manager.tile_size as u32));
+                // The labels are drawn into the image, so once it's gone they're not on the map any more
+                for (_, forgotten) in decoded_tiles.insert(tile_source.name(), loaded.tile, tile_handle.clone(), bytes) {
+                    labels.forget_tile(&forgotten);
+                }
+                spawn_chunk(&mut commands, tile_handle, transform, chunk, zoom_manager.tile_size);
+            }
+            Ok(TileData::Mesh(mesh)) => {
+                spawn_mesh_chunk(&mut commands, meshes.add(mesh), mesh_material.0.clone(), transform, chunk);
+            }
+            Ok(TileData::Empty) => {}
+            Err(error) => {
+                warn!("Tile {}/{}/{} failed to load: {}", loaded.tile.zoom, loaded.tile.x, loaded.tile.y, error);
+                let entity = spawn_chunk(&mut commands, error_image.0.clone(), transform, chunk, zoom_manager.tile_size);
+                commands.entity(entity).insert(FailedTile { tile: loaded.tile, error });
+            }
+        }
+        chunk_manager.spawned_chunks.insert(loaded.chunk_pos);
+    }
+}
 
-    while let Ok((chunk_pos, raw_image_data)) = map_receiver.try_recv() {
-        if !chunk_manager.to_spawn_chunks.contains_key(&chunk_pos) {
-            new_chunks.push((chunk_pos, raw_image_data));
+/// Right clicking a tile which failed to load throws it away, so it gets asked for again.
+fn retry_failed_tiles(
+    mut commands: Commands,
+    buttons: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    failed_query: Query<(Entity, &Transform, &MapChunk), With<FailedTile>>,
+    mut chunk_manager: ResMut<ChunkManager>,
+    zoom_manager: Res<ZoomManager>,
+) {
+    if !buttons.just_pressed(MouseButton::Right) {
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(|window| window.cursor_position()) else {
+        return;
+    };
+    let Some(world_pos) = camera_query.iter().find_map(|(camera, transform)| camera.viewport_to_world_2d(transform, cursor).ok()) else {
+        return;
+    };
+
+    for (entity, transform, chunk) in failed_query.iter() {
+        let half_size = world_tile_size(zoom_manager.tile_size, chunk.zoom) / 2.0;
+        if (world_pos - transform.translation.xy()).abs().max_element() > half_size {
+            continue;
+        }
+        commands.entity(entity).despawn_recursive();
+        if chunk.zoom == zoom_manager.zoom_level {
+            chunk_manager.spawned_chunks.remove(&chunk.pos);
+            chunk_manager.update = true;
         }
     }
+}
 
-    for (pos, data) in new_chunks {
-        chunk_manager.to_spawn_chunks.insert(pos, data);
+/// The chunks of `zoom` which cover the same bit of the map as `chunk`, as the corners of a box of chunk positions
+/// (the max isn't included). It's in i64 as a low level chunk can cover more of a high level's chunks than fit in an i32.
+fn chunk_footprint(chunk: &MapChunk, zoom: u32) -> (I64Vec2, I64Vec2) {
+    let pos = chunk.pos.as_i64vec2();
+    if zoom >= chunk.zoom {
+        let depth = zoom - chunk.zoom;
+        (pos << depth, (pos + 1) << depth)
+    } else {
+        // Shifting rounds down, so the chunks left of the antimeridian still find their parent
+        let parent = pos >> (chunk.zoom - zoom);
+        (parent, parent + 1)
     }
 }
 
-fn spawn_to_needed_chunks(
+/// An old level's chunk can go once the current level's chunks over the top of it have loaded, so the old levels
+/// don't pile up underneath while we're panning around and there's always something new loading somewhere.
+fn despawn_stale_chunks(
     mut commands: Commands,
-    mut images: ResMut<Assets<Image>>,
-    mut chunk_manager: ResMut<ChunkManager>,
+    chunk_manager: Res<ChunkManager>,
     zoom_manager: Res<ZoomManager>,
+    stale_query: Query<(Entity, &MapChunk), With<StaleChunk>>,
 ) {
-    let to_spawn_chunks: Vec<(IVec2, Vec<u8>)> = chunk_manager.to_spawn_chunks.iter().map(|(pos, data)| (*pos, data.clone())).collect();
-    for (chunk_pos, raw_image_data) in to_spawn_chunks {
-        let tile_handle = images.add(buffer_to_bevy_image(raw_image_data, zoom_manager.tile_size as u32));
-        spawn_chunk(&mut commands, tile_handle, chunk_pos, zoom_manager.tile_size);
-        chunk_manager.spawned_chunks.insert(chunk_pos);
+    for (entity, chunk) in stale_query.iter() {
+        let (min, max) = chunk_footprint(chunk, zoom_manager.zoom_level);
+        let covers = |pos: &IVec2| {
+            let pos = pos.as_i64vec2();
+            pos.cmpge(min).all() && pos.cmplt(max).all()
+        };
+        // Nothing has been asked for over it yet, so it's all we've got to show there
+        if !chunk_manager.spawned_chunks.iter().any(covers) {
+            continue;
+        }
+        if chunk_manager.loading.keys().chain(chunk_manager.to_spawn_chunks.keys()).any(covers) {
+            continue;
+        }
+        commands.entity(entity).despawn_recursive();
     }
-    chunk_manager.to_spawn_chunks.clear();
 }
 
 fn despawn_outofrange_chunks(
     mut commands: Commands,
     camera_query: Query<&Transform, With<Camera>>,
-    chunks_query: Query<(Entity, &Transform, &TileMarker)>,
+    chunks_query: Query<(Entity, &Transform, &MapChunk)>,
     mut chunk_manager: ResMut<ChunkManager>,
     zoom_manager: Res<ZoomManager>,
 ) {
     for camera_transform in camera_query.iter() {
-        for (entity, chunk_transform, _) in chunks_query.iter() {
-            let chunk_pos = chunk_transform.translation.xy();
-            let distance = camera_transform.translation.xy().distance(chunk_pos);
-            if distance > zoom_manager.tile_size * 10.{
-                let x = (chunk_pos.x / (CHUNK_SIZE.x as f32 * zoom_manager.tile_size)).floor() as i32;
-                let y = (chunk_pos.y / (CHUNK_SIZE.y as f32 * zoom_manager.tile_size)).floor() as i32;
-                chunk_manager.spawned_chunks.remove(&IVec2::new(x, y));
+        for (entity, chunk_transform, chunk) in chunks_query.iter() {
+            let distance = camera_transform.translation.xy().distance(chunk_transform.translation.xy());
+            if distance > world_tile_size(zoom_manager.tile_size, chunk.zoom) * 10. {
+                if chunk.zoom == zoom_manager.zoom_level {
+                    chunk_manager.spawned_chunks.remove(&chunk.pos);
+                }
                 commands.entity(entity).despawn_recursive();
             }
         }
     }
 }
 
-fn despawn_all_chunks(
-    mut commands: Commands,
-    chunks_query: Query<(Entity, &TileMarker)>,
-) {
-    for (entity, _) in chunks_query.iter() {
-        commands.entity(entity).despawn_recursive();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::world_mercator_to_lat_lon;
+
+    #[test]
+    fn placeholder_is_the_right_quarter_of_the_parent() {
+        let mut decoded_tiles = DecodedTiles::default();
+        decoded_tiles.insert("osm", Tile::new(8, 5, 4), Handle::default(), 1);
+        let (_, rect) = decoded_tiles.nearest_ancestor("osm", &Tile::new(17, 10, 5), 256.0).unwrap();
+        assert_eq!(rect, Rect::new(128.0, 0.0, 256.0, 128.0));
+    }
+
+    #[test]
+    fn placeholder_comes_from_the_nearest_ancestor() {
+        let mut decoded_tiles = DecodedTiles::default();
+        decoded_tiles.insert("osm", Tile::new(2, 1, 3), Handle::default(), 1);
+        decoded_tiles.insert("osm", Tile::new(4, 2, 4), Handle::default(), 1);
+        // Grandchild of the zoom 4 tile, which is 4x4 pieces of it
+        let (_, rect) = decoded_tiles.nearest_ancestor("osm", &Tile::new(19, 11, 6), 256.0).unwrap();
+        assert_eq!(rect, Rect::new(192.0, 192.0, 256.0, 256.0));
+        assert!(decoded_tiles.nearest_ancestor("osm", &Tile::new(0, 0, 6), 256.0).is_none());
+    }
+
+    #[test]
+    fn oldest_decoded_tiles_are_forgotten() {
+        let mut decoded_tiles = DecodedTiles::new(10 * 100);
+        for x in 0..=10 {
+            decoded_tiles.insert("osm", Tile::new(x, 0, 10), Handle::default(), 100);
+        }
+        assert!(decoded_tiles.get("osm", &Tile::new(0, 0, 10)).is_none());
+        assert!(decoded_tiles.get("osm", &Tile::new(1, 0, 10)).is_some());
+    }
+
+    #[test]
+    fn recently_used_tiles_are_kept() {
+        let mut decoded_tiles = DecodedTiles::new(2 * 100);
+        decoded_tiles.insert("osm", Tile::new(0, 0, 10), Handle::default(), 100);
+        decoded_tiles.insert("osm", Tile::new(1, 0, 10), Handle::default(), 100);
+        decoded_tiles.get("osm", &Tile::new(0, 0, 10));
+        decoded_tiles.insert("osm", Tile::new(2, 0, 10), Handle::default(), 100);
+        assert!(decoded_tiles.get("osm", &Tile::new(0, 0, 10)).is_some());
+        assert!(decoded_tiles.get("osm", &Tile::new(1, 0, 10)).is_none());
+    }
+
+    #[test]
+    fn decoded_again_counts_as_used() {
+        let mut decoded_tiles = DecodedTiles::new(2 * 100);
+        decoded_tiles.insert("osm", Tile::new(0, 0, 10), Handle::default(), 100);
+        decoded_tiles.insert("osm", Tile::new(1, 0, 10), Handle::default(), 100);
+        decoded_tiles.insert("osm", Tile::new(0, 0, 10), Handle::default(), 100);
+        let forgotten = decoded_tiles.insert("osm", Tile::new(2, 0, 10), Handle::default(), 100);
+        assert_eq!(forgotten, vec![("osm".to_string(), Tile::new(1, 0, 10))]);
+        assert_eq!((decoded_tiles.images.len(), decoded_tiles.order.len(), decoded_tiles.bytes), (2, 2, 200));
+    }
+
+    fn app_with_camera_at(camera_pos: Vec2) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(ChunkManager::default())
+            .insert_resource(ZoomManager::default())
+            .init_resource::<TileLoader>()
+            .add_systems(Update, rebase_origin);
+        app.world_mut().spawn((Camera::default(), OrthographicProjection::default_2d(), Transform::from_translation(camera_pos.extend(1.0))));
+        app
+    }
+
+    fn camera_pos(app: &mut App) -> Vec2 {
+        app.world_mut().query_filtered::<&Transform, With<Camera>>().single(app.world()).translation.xy()
+    }
+
+    fn camera_lat_long(app: &mut App) -> LatLon {
+        let camera_pos = camera_pos(app);
+        let reference = app.world().resource::<ChunkManager>().refrence_long_lat;
+        world_mercator_to_lat_lon(camera_pos.into(), reference, WORLD_ZOOM, TILE_QUALITY.into())
+    }
+
+    #[test]
+    fn origin_follows_the_camera_and_takes_the_tiles_with_it() {
+        // About 60km away at zoom 14, a long way to pan but nowhere near the other side of the world
+        let mut app = app_with_camera_at(Vec2::new(400_000.25, -250_000.25));
+        let chunk_pos = camera_pos_to_chunk_pos(Vec2::new(400_000.25, -250_000.25), STARTING_LONG_LAT, WORLD_ZOOM, 256.0);
+        let tile_coords = chunk_pos_to_tile(chunk_pos, WORLD_ZOOM).unwrap();
+        let tile = app.world_mut().spawn((tile_transform(&tile_coords, STARTING_LONG_LAT, 256.0, 0.0), TileMarker, MapChunk { pos: chunk_pos, zoom: WORLD_ZOOM })).id();
+        let before = camera_lat_long(&mut app);
+
+        app.update();
+
+        let camera = camera_pos(&mut app);
+        assert_eq!(camera, Vec2::new(0.25, -0.25));
+        let after = camera_lat_long(&mut app);
+        assert!((before.lat - after.lat).abs() < 1e-9 && (before.long - after.long).abs() < 1e-9);
+
+        // The camera is over the same tile, and the tile is where it would be spawned now
+        let reference = app.world().resource::<ChunkManager>().refrence_long_lat;
+        assert_eq!(camera_pos_to_chunk_pos(camera, reference, WORLD_ZOOM, 256.0), chunk_pos);
+        // Give or take what f32 lost when the tile was spawned that far out
+        let moved = app.world().get::<Transform>(tile).unwrap().translation;
+        assert!(moved.distance(tile_transform(&tile_coords, reference, 256.0, 0.0).translation) < 0.1);
+    }
+
+    #[test]
+    fn chunks_line_up_with_the_tiles_at_any_latitude() {
+        for (lat, long) in [(0.001, 0.18), (52.2, 0.18), (-33.86, 151.21), (64.14, -21.94), (78.22, 15.65), (-54.8, -68.3)] {
+            let place = LatLon::new(lat, long);
+            // A reference which isn't on a tile corner, like after the origin has moved
+            let reference = LatLon::new(lat + 0.012_345, long - 0.023_456);
+            let camera = place.to_game_coords(reference, WORLD_ZOOM, 256.0);
+            for zoom in [10, 14, 17] {
+                let camera_chunk_pos = camera_pos_to_chunk_pos(camera, reference, zoom, 256.0);
+                assert_eq!(chunk_pos_to_tile(camera_chunk_pos, zoom), Some(place.to_tile_coords(zoom)));
+
+                let size = world_tile_size(256.0, zoom);
+                for y in -2..=2 {
+                    for x in -2..=2 {
+                        let tile = chunk_pos_to_tile(camera_chunk_pos + IVec2::new(x, y), zoom).unwrap();
+                        // The middle of the square is in the tile it's showing
+                        let center = tile_world_center(&tile, reference, 256.0);
+                        assert_eq!(world_mercator_to_lat_lon(center.into(), reference, WORLD_ZOOM, 256.0).to_tile_coords(zoom), tile);
+
+                        // and it's right next to its neighbours, with no gaps or overlaps
+                        let right = tile_world_center(&Tile::new(tile.x + 1, tile.y, zoom), reference, 256.0);
+                        let below = tile_world_center(&Tile::new(tile.x, tile.y + 1, zoom), reference, 256.0);
+                        assert!((right - center - Vec2::new(size, 0.0)).abs().max_element() < 1e-2, "{:?} at {}", tile, lat);
+                        assert!((below - center - Vec2::new(0.0, -size)).abs().max_element() < 1e-2, "{:?} at {}", tile, lat);
+                    }
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn chunks_past_the_antimeridian_show_the_tiles_from_the_other_side() {
+        let reference = LatLon::new(10.0, 179.0);
+        // Panned east over the antimeridian, so the longitude carries on past 180
+        let camera = LatLon::new(10.0, 181.0).to_game_coords(reference, WORLD_ZOOM, 256.0);
+        let camera_chunk_pos = camera_pos_to_chunk_pos(camera, reference, 3, 256.0);
+        assert_eq!(camera_chunk_pos.x, 8);
+        assert_eq!(chunk_pos_to_tile(camera_chunk_pos, 3), Some(Tile::new(0, camera_chunk_pos.y, 3)));
+        assert_eq!(chunk_pos_to_tile(IVec2::new(-1, 3), 3), Some(Tile::new(7, 3, 3)));
+
+        // and it goes right next to the last tile, not back on the other side of the world
+        let last = chunk_transform(&MapChunk { pos: camera_chunk_pos - IVec2::X, zoom: 3 }, reference, 256.0, 0.0);
+        let wrapped = chunk_transform(&MapChunk { pos: camera_chunk_pos, zoom: 3 }, reference, 256.0, 0.0);
+        assert!((wrapped.translation.x - last.translation.x - world_tile_size(256.0, 3)).abs() < 1.0);
+        assert!((wrapped.translation.x - camera.x).abs() < world_tile_size(256.0, 3));
+    }
+
+    #[test]
+    fn no_chunks_past_the_top_or_bottom_of_the_map() {
+        assert_eq!(chunk_pos_to_tile(IVec2::new(3, -1), 4), None);
+        assert_eq!(chunk_pos_to_tile(IVec2::new(3, 16), 4), None);
+        assert_eq!(chunk_pos_to_tile(IVec2::new(3, 15), 4), Some(Tile::new(3, 15, 4)));
+    }
+
+    #[test]
+    fn origin_stays_put_while_the_camera_is_close() {
+        let mut app = app_with_camera_at(Vec2::new(1000.0, -3000.0));
+        app.update();
+        assert_eq!(camera_pos(&mut app), Vec2::new(1000.0, -3000.0));
+        assert_eq!(app.world().resource::<ChunkManager>().refrence_long_lat, STARTING_LONG_LAT);
+    }
+
+    #[test]
+    fn stale_chunks_go_once_the_chunks_over_them_have_loaded() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(ChunkManager::default())
+            .insert_resource(ZoomManager { zoom_level: 5, ..Default::default() })
+            .add_systems(Update, despawn_stale_chunks);
+        let mut chunk_manager = app.world_mut().resource_mut::<ChunkManager>();
+        chunk_manager.spawned_chunks.extend([IVec2::new(2, 2), IVec2::new(3, 2), IVec2::new(2, 3), IVec2::new(3, 3), IVec2::new(-1, 0)]);
+        chunk_manager.loading.insert(IVec2::new(3, 3), Tile::new(3, 3, 5));
+
+        // Zoomed in from 4, so it's under the four zoom 5 chunks from (2, 2) to (3, 3)
+        let parent = app.world_mut().spawn((StaleChunk, MapChunk { pos: IVec2::new(1, 1), zoom: 4 })).id();
+        // Zoomed out from 6, it only needs (2, 2) and (-1, 0) which have both loaded
+        let child = app.world_mut().spawn((StaleChunk, MapChunk { pos: IVec2::new(4, 5), zoom: 6 })).id();
+        let past_antimeridian = app.world_mut().spawn((StaleChunk, MapChunk { pos: IVec2::new(-1, 1), zoom: 6 })).id();
+        // Nothing at zoom 5 has been asked for over this one yet
+        let elsewhere = app.world_mut().spawn((StaleChunk, MapChunk { pos: IVec2::new(10, 10), zoom: 4 })).id();
+
+        app.update();
+        assert!(app.world().get_entity(parent).is_ok());
+        assert!(app.world().get_entity(child).is_err());
+        assert!(app.world().get_entity(past_antimeridian).is_err());
+        assert!(app.world().get_entity(elsewhere).is_ok());
+
+        app.world_mut().resource_mut::<ChunkManager>().loading.clear();
+        app.update();
+        assert!(app.world().get_entity(parent).is_err());
+        assert!(app.world().get_entity(elsewhere).is_ok());
+    }
+
+    #[test]
+    fn tiles_from_different_sources_are_kept_apart() {
+        let mut decoded_tiles = DecodedTiles::default();
+        decoded_tiles.insert("osm", Tile::new(0, 0, 10), Handle::default(), 100);
+        assert!(decoded_tiles.get("openfreemap", &Tile::new(0, 0, 10)).is_none());
+    }
+}
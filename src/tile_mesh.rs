@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use bevy::{asset::RenderAssetUsages, prelude::*, render::mesh::{Indices, PrimitiveTopology}};
+use lyon::{math::{point, Point}, path::Path as LyonPath, tessellation::{BuffersBuilder, FillOptions, FillRule, FillTessellator, FillVertex, LineCap, LineJoin, StrokeOptions, StrokeTessellator, StrokeVertex, VertexBuffers}};
+use mvt_reader::Reader;
+
+use crate::{args::{Args, Flag}, ofm_api::{decode_layers, decompress_tile, ring_area, STYLE_TILE_SIZE}, style::{geometry_type, EvalContext, LayerKind, Style, StyleColor}, tile_loader::TileError};
+
+/// How vector tiles are turned into something we can show.
+#[derive(Debug, Resource, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Drawn into an image on the cpu, this is the only mode which draws labels.
+    Raster,
+    /// Tessellated into triangles, which stay sharp while zooming and can be restyled without redrawing anything.
+    Mesh,
+}
+
+/// `--render raster` (the default) or `--render mesh`.
+pub const RENDER_MODE_FLAGS: &[Flag] = &[Flag::value("--render")];
+
+pub fn render_mode_from_args(args: &Args) -> Result<RenderMode, String> {
+    match args.get("--render") {
+        None | Some("raster") => Ok(RenderMode::Raster),
+        Some("mesh") => Ok(RenderMode::Mesh),
+        Some(mode) => Err(format!("Unknown render mode: {}", mode)),
+    }
+}
+
+/// Every tile mesh uses the same material, the colours come from the vertices.
+#[derive(Resource, Clone, Deref)]
+pub struct TileMeshMaterial(pub Handle<ColorMaterial>);
+
+impl FromWorld for TileMeshMaterial {
+    fn from_world(world: &mut World) -> Self {
+        Self(world.resource_mut::<Assets<ColorMaterial>>().add(ColorMaterial::default()))
+    }
+}
+
+/// Triangles for a whole tile, everything in one mesh so the layers are drawn in the order they were added.
+#[derive(Default)]
+struct TileMeshBuilder {
+    size: f32,
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl TileMeshBuilder {
+    fn add(&mut self, buffers: VertexBuffers<Point, u32>, color: StyleColor, opacity: f32) {
+        let color = LinearRgba::from(Srgba::new(color.r, color.g, color.b, color.a * opacity)).to_f32_array();
+        let offset = self.positions.len() as u32;
+        // Tiles are drawn with y going down and centred on their transform, like the tilemap tiles are
+        self.positions.extend(buffers.vertices.iter().map(|vertex| [vertex.x - self.size / 2.0, self.size / 2.0 - vertex.y, 0.0]));
+        self.colors.extend(std::iter::repeat_n(color, buffers.vertices.len()));
+        self.indices.extend(buffers.indices.iter().map(|index| index + offset));
+    }
+
+    fn build(self) -> Mesh {
+        let uvs: Vec<[f32; 2]> = self.positions.iter().map(|position| [position[0] / self.size + 0.5, 0.5 - position[1] / self.size]).collect();
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors)
+            .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+/// Like `ofm_to_data_image` but makes triangles rather than pixels. Symbol layers are left out,
+/// the text would need its own mesh for every glyph.
+pub fn ofm_to_mesh(data: Vec<u8>, size: u32, zoom: u32, style: &Style) -> Result<Mesh, TileError> {
+    let tile = Reader::new(decompress_tile(data)).map_err(|e| TileError::Vector(e.to_string()))?;
+    let layers = decode_layers(&tile, size)?;
+
+    let mut builder = TileMeshBuilder {
+        size: size as f32,
+        ..Default::default()
+    };
+    let mut fill_tessellator = FillTessellator::new();
+    let mut stroke_tessellator = StrokeTessellator::new();
+    let pixel_ratio = size as f32 / STYLE_TILE_SIZE;
+    let zoom = zoom as f32;
+    let no_properties = HashMap::new();
+    for style_layer in style.layers.iter().filter(|layer| layer.visible_at(zoom)) {
+        if let LayerKind::Background { color, opacity } = &style_layer.kind {
+            let context = EvalContext { zoom, geometry_type: "Polygon", properties: &no_properties };
+            let mut buffers = VertexBuffers::new();
+            buffers.vertices.extend([point(0.0, 0.0), point(size as f32, 0.0), point(size as f32, size as f32), point(0.0, size as f32)]);
+            buffers.indices.extend([0, 1, 2, 0, 2, 3]);
+            builder.add(buffers, color.evaluate(&context), opacity.evaluate(&context));
+            continue;
+        }
+
+        let Some((scale, features)) = style_layer.source_layer.as_ref().and_then(|name| layers.get(name)) else {
+            continue;
+        };
+        for (feature, properties) in features {
+            let context = EvalContext { zoom, geometry_type: geometry_type(&feature.geometry), properties };
+            if !style_layer.matches(&context) {
+                continue;
+            }
+            tessellate_feature(&mut builder, &mut fill_tessellator, &mut stroke_tessellator, &style_layer.kind, &feature.geometry, *scale, pixel_ratio, &context);
+        }
+    }
+    Ok(builder.build())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tessellate_feature(
+    builder: &mut TileMeshBuilder,
+    fill_tessellator: &mut FillTessellator,
+    stroke_tessellator: &mut StrokeTessellator,
+    kind: &LayerKind,
+    geometry: &geo::Geometry<f32>,
+    scale: f32,
+    pixel_ratio: f32,
+    context: &EvalContext,
+) {
+    match kind {
+        LayerKind::Fill { color, opacity, outline_color } => {
+            if context.geometry_type != "Polygon" {
+                return;
+            }
+            let path = geometry_to_lyon_path(geometry, scale);
+            // Holes are wound the other way round to the outside, so non-zero leaves them empty
+            let options = FillOptions::default().with_fill_rule(FillRule::NonZero);
+            let opacity = opacity.evaluate(context);
+            let mut buffers = VertexBuffers::new();
+            if fill_tessellator.tessellate_path(&path, &options, &mut BuffersBuilder::new(&mut buffers, |vertex: FillVertex| vertex.position())).is_ok() {
+                builder.add(buffers, color.evaluate(context), opacity);
+            }
+            if let Some(outline_color) = outline_color {
+                if let Some(buffers) = stroke(stroke_tessellator, &path, &StrokeOptions::default().with_line_width(1.0)) {
+                    builder.add(buffers, outline_color.evaluate(context), opacity);
+                }
+            }
+        }
+        LayerKind::Line { color, width, opacity, dash_array, cap, join } => {
+            if context.geometry_type == "Point" {
+                return;
+            }
+            let width = width.evaluate(context) * pixel_ratio;
+            let options = StrokeOptions::default()
+                .with_line_width(width)
+                .with_line_cap(match cap {
+                    raqote::LineCap::Round => LineCap::Round,
+                    raqote::LineCap::Square => LineCap::Square,
+                    raqote::LineCap::Butt => LineCap::Butt,
+                })
+                .with_line_join(match join {
+                    raqote::LineJoin::Round => LineJoin::Round,
+                    raqote::LineJoin::Miter => LineJoin::Miter,
+                    raqote::LineJoin::Bevel => LineJoin::Bevel,
+                })
+                .with_miter_limit(10.0);
+            // Dashes are given in line widths
+            let dashes: Vec<f32> = dash_array.evaluate(context).iter().map(|dash| dash * width).collect();
+            let path = if dashes.is_empty() {
+                geometry_to_lyon_path(geometry, scale)
+            } else {
+                dashed_path(geometry, scale, &dashes)
+            };
+            if let Some(buffers) = stroke(stroke_tessellator, &path, &options) {
+                builder.add(buffers, color.evaluate(context), opacity.evaluate(context));
+            }
+        }
+        LayerKind::Circle { color, radius, opacity, stroke_color, stroke_width } => {
+            let points: Vec<geo::Point<f32>> = match geometry {
+                geo::Geometry::Point(point) => vec![*point],
+                geo::Geometry::MultiPoint(multi_point) => multi_point.0.clone(),
+                _ => return,
+            };
+            let radius = radius.evaluate(context) * pixel_ratio;
+            let stroke_width = stroke_width.evaluate(context) * pixel_ratio;
+            let opacity = opacity.evaluate(context);
+            for center in points {
+                let center = point(center.x() * scale, center.y() * scale);
+                let mut buffers = VertexBuffers::new();
+                if fill_tessellator.tessellate_circle(center, radius, &FillOptions::default(), &mut BuffersBuilder::new(&mut buffers, |vertex: FillVertex| vertex.position())).is_ok() {
+                    builder.add(buffers, color.evaluate(context), opacity);
+                }
+                if stroke_width > 0.0 {
+                    let mut buffers = VertexBuffers::new();
+                    let options = StrokeOptions::default().with_line_width(stroke_width);
+                    if stroke_tessellator.tessellate_circle(center, radius, &options, &mut BuffersBuilder::new(&mut buffers, |vertex: StrokeVertex| vertex.position())).is_ok() {
+                        builder.add(buffers, stroke_color.evaluate(context), opacity);
+                    }
+                }
+            }
+        }
+        LayerKind::Background { .. } | LayerKind::Symbol { .. } => {}
+    }
+}
+
+fn stroke(tessellator: &mut StrokeTessellator, path: &LyonPath, options: &StrokeOptions) -> Option<VertexBuffers<Point, u32>> {
+    let mut buffers = VertexBuffers::new();
+    tessellator.tessellate_path(path, options, &mut BuffersBuilder::new(&mut buffers, |vertex: StrokeVertex| vertex.position())).ok()?;
+    Some(buffers)
+}
+
+/// Traces the geometry, scaled from tile units into pixels, the same way `geometry_to_path` does for raqote.
+fn geometry_to_lyon_path(geometry: &geo::Geometry<f32>, scale: f32) -> LyonPath {
+    let mut path = LyonPath::builder();
+    for (points, closed) in geometry_rings(geometry) {
+        let mut points = points.into_iter().map(|coord| point(coord.x * scale, coord.y * scale));
+        let Some(first) = points.next() else {
+            continue;
+        };
+        path.begin(first);
+        for point in points {
+            path.line_to(point);
+        }
+        path.end(closed);
+    }
+    path.build()
+}
+
+/// The lines of a geometry, and whether each one is a closed ring. Polygon rings are wound so the holes go
+/// the other way round to the outside, the same as `trace_polygon`.
+fn geometry_rings(geometry: &geo::Geometry<f32>) -> Vec<(Vec<geo::Coord<f32>>, bool)> {
+    fn polygon_rings(polygon: &geo::Polygon<f32>, rings: &mut Vec<(Vec<geo::Coord<f32>>, bool)>) {
+        let mut add_ring = |ring: &geo::LineString<f32>, reverse: bool| {
+            let mut points = ring.0.clone();
+            // Rings repeat the first point at the end, closing the path draws that edge for us
+            if points.len() > 1 && points.first() == points.last() {
+                points.pop();
+            }
+            if points.len() < 3 {
+                return;
+            }
+            if reverse {
+                points.reverse();
+            }
+            rings.push((points, true));
+        };
+        add_ring(polygon.exterior(), ring_area(polygon.exterior()) < 0.0);
+        for interior in polygon.interiors() {
+            add_ring(interior, ring_area(interior) > 0.0);
+        }
+    }
+
+    let mut rings = Vec::new();
+    match geometry {
+        geo::Geometry::Line(line) => rings.push((vec![line.start, line.end], false)),
+        geo::Geometry::LineString(line_string) => rings.push((line_string.0.clone(), false)),
+        geo::Geometry::MultiLineString(multi_line_string) => rings.extend(multi_line_string.iter().map(|line_string| (line_string.0.clone(), false))),
+        geo::Geometry::Polygon(polygon) => polygon_rings(polygon, &mut rings),
+        geo::Geometry::MultiPolygon(multi_polygon) => multi_polygon.iter().for_each(|polygon| polygon_rings(polygon, &mut rings)),
+        geo::Geometry::Rect(rect) => polygon_rings(&rect.to_polygon(), &mut rings),
+        geo::Geometry::Triangle(triangle) => polygon_rings(&triangle.to_polygon(), &mut rings),
+        geo::Geometry::Point(_) | geo::Geometry::MultiPoint(_) | geo::Geometry::GeometryCollection(_) => {}
+    }
+    rings
+}
+
+/// lyon can't dash strokes, so the lines are cut up into a path with one piece for every dash.
+fn dashed_path(geometry: &geo::Geometry<f32>, scale: f32, dashes: &[f32]) -> LyonPath {
+    let mut path = LyonPath::builder();
+    // An odd number of dashes is repeated, so the gaps swap with the dashes the second time through
+    let dashes = if dashes.len() % 2 == 1 { [dashes, dashes].concat() } else { dashes.to_vec() };
+    let pattern_length: f32 = dashes.iter().sum();
+    if pattern_length <= 0.0 {
+        return geometry_to_lyon_path(geometry, scale);
+    }
+    for (mut points, closed) in geometry_rings(geometry) {
+        if closed {
+            points.push(points[0]);
+        }
+        let points: Vec<Point> = points.iter().map(|coord| point(coord.x * scale, coord.y * scale)).collect();
+        // Where we are in the pattern, even dashes are drawn and odd ones are gaps
+        let mut dash = 0;
+        let mut left_in_dash = dashes[0];
+        let mut drawing = false;
+        for segment in points.windows(2) {
+            let (start, end) = (segment[0], segment[1]);
+            let length = (end - start).length();
+            let mut done = 0.0;
+            while done < length {
+                let step = left_in_dash.min(length - done);
+                let from = start.lerp(end, done / length);
+                let to = start.lerp(end, (done + step) / length);
+                if dash % 2 == 0 {
+                    if !drawing {
+                        path.begin(from);
+                        drawing = true;
+                    }
+                    path.line_to(to);
+                }
+                done += step;
+                left_in_dash -= step;
+                if left_in_dash <= 0.0 {
+                    if drawing {
+                        path.end(false);
+                        drawing = false;
+                    }
+                    dash = (dash + 1) % dashes.len();
+                    left_in_dash = dashes[dash];
+                }
+            }
+        }
+        if drawing {
+            path.end(false);
+        }
+    }
+    path.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use lyon::path::Event;
+
+    use super::*;
+
+    #[test]
+    fn dashes_cut_the_line_up() {
+        let line = geo::Geometry::LineString(geo::LineString::from(vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0)]));
+        let path = dashed_path(&line, 1.0, &[2.0, 3.0]);
+        let dashes: Vec<(Point, Point)> = path.iter().filter_map(|event| match event {
+            Event::Begin { at } => Some((at, at)),
+            _ => None,
+        }).collect();
+        // 20 pixels of line with a dash every 5 pixels, and the dash which starts at 10 goes round the corner
+        assert_eq!(dashes.len(), 4);
+        assert_eq!(dashes[1].0, point(5.0, 0.0));
+        assert_eq!(dashes[3].0, point(10.0, 5.0));
+    }
+
+    #[test]
+    fn holes_are_wound_the_other_way() {
+        let outside = geo::LineString::from(vec![(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0), (0.0, 0.0)]);
+        let hole = geo::LineString::from(vec![(2.0, 2.0), (2.0, 8.0), (8.0, 8.0), (8.0, 2.0), (2.0, 2.0)]);
+        let rings = geometry_rings(&geo::Geometry::Polygon(geo::Polygon::new(outside, vec![hole])));
+        assert_eq!(rings.len(), 2);
+        let area = |points: &Vec<geo::Coord<f32>>| ring_area(&geo::LineString::new(points.clone()));
+        assert!(area(&rings[0].0) > 0.0);
+        assert!(area(&rings[1].0) < 0.0);
+    }
+}
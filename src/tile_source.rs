@@ -0,0 +1,419 @@
+use std::{path::Path, sync::Arc};
+
+use bevy::prelude::*;
+
+use crate::{args::{Args, Flag}, http::{http_config_from_args, CancelFlag, FetchError, HttpClient, HTTP_FLAGS}, mbtiles::MbTilesSource, ofm_api::send_tile_request, pmtiles::PmTilesSource, tile::parse_zoom, tile_cache::{CacheLookup, TileCache, DEFAULT_CACHE_SIZE}};
+
+/// What kind of data a source hands back, raster sources give us finished images
+/// while vector sources give us MVT data which we have to draw ourselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileKind {
+    Raster,
+    Vector,
+}
+
+impl TileKind {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileKind::Raster => "png",
+            TileKind::Vector => "pbf",
+        }
+    }
+}
+
+/// Anything which can give us the bytes of a z/x/y tile.
+pub trait TileSource: Send + Sync {
+    fn name(&self) -> &str;
+    fn kind(&self) -> TileKind;
+    fn max_zoom(&self) -> u32;
+    fn attribution(&self) -> &str;
+    /// Whether the tile can be had without going over the network.
+    fn is_cached(&self, _x: u64, _y: u64, _zoom: u64) -> bool {
+        false
+    }
+    /// The raw tile as it came from the source, so a png/jpeg for raster sources and a pbf for vector sources.
+    /// Empty if the source doesn't have the tile.
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, FetchError>;
+    /// Same as `get_tile`, but sources which can take a while should give up once `cancel` is set.
+    fn get_tile_cancellable(&self, x: u64, y: u64, zoom: u64, _cancel: &CancelFlag) -> Result<Vec<u8>, FetchError> {
+        self.get_tile(x, y, zoom)
+    }
+}
+
+/// The tile source which the map currently loads its tiles from.
+#[derive(Resource, Clone, Deref)]
+pub struct ActiveTileSource(pub Arc<dyn TileSource>);
+
+/// A tile server which serves tiles over http, the url template should contain `{z}`, `{x}` and `{y}`
+/// and can contain `{s}` which gets swapped out for one of the subdomains.
+#[derive(Debug, Clone)]
+pub struct UrlTileSource {
+    pub name: String,
+    pub kind: TileKind,
+    pub url_template: String,
+    pub subdomains: Vec<String>,
+    pub max_zoom: u32,
+    pub attribution: String,
+    pub cache: Arc<TileCache>,
+    pub http: Arc<HttpClient>,
+}
+
+impl UrlTileSource {
+    pub fn new(name: String, kind: TileKind, url_template: String) -> Self {
+        Self {
+            cache: Arc::new(TileCache::for_source(&name, &url_template, DEFAULT_CACHE_SIZE)),
+            http: Arc::new(HttpClient::default()),
+            name,
+            kind,
+            url_template,
+            subdomains: Vec::new(),
+            max_zoom: 19,
+            attribution: String::new(),
+        }
+    }
+
+    /// https://wiki.openstreetmap.org/wiki/Raster_tile_providers
+    pub fn openstreetmap() -> Self {
+        Self {
+            attribution: "© OpenStreetMap contributors".to_string(),
+            ..Self::new("openstreetmap".to_string(), TileKind::Raster, "https://tile.openstreetmap.org/{z}/{x}/{y}.png".to_string())
+        }
+    }
+
+    pub fn openfreemap() -> Self {
+        Self {
+            max_zoom: 14,
+            attribution: "OpenFreeMap © OpenMapTiles Data from OpenStreetMap".to_string(),
+            ..Self::new("openfreemap".to_string(), TileKind::Vector, "https://tiles.openfreemap.org/planet/20250122_001001_pt/{z}/{x}/{y}.pbf".to_string())
+        }
+    }
+
+    pub fn tile_url(&self, x: u64, y: u64, zoom: u64) -> String {
+        let mut url = self.url_template
+            .replace("{z}", &zoom.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string());
+        if !self.subdomains.is_empty() {
+            // Spread the requests over the subdomains, but keep the same subdomain for the same tile
+            let subdomain = &self.subdomains[((x + y) % self.subdomains.len() as u64) as usize];
+            url = url.replace("{s}", subdomain);
+        }
+        url
+    }
+}
+
+impl TileSource for UrlTileSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> TileKind {
+        self.kind
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn is_cached(&self, x: u64, y: u64, zoom: u64) -> bool {
+        matches!(self.cache.get(x, y, zoom, self.kind.extension()), CacheLookup::Fresh(_))
+    }
+
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, FetchError> {
+        self.get_tile_cancellable(x, y, zoom, &CancelFlag::default())
+    }
+
+    fn get_tile_cancellable(&self, x: u64, y: u64, zoom: u64, cancel: &CancelFlag) -> Result<Vec<u8>, FetchError> {
+        send_tile_request(&self.http, self.tile_url(x, y, zoom), &self.cache, x, y, zoom, self.kind.extension(), cancel)
+    }
+}
+
+/// `--source` takes `osm`, `openfreemap`, `raster:<url template>`, `vector:<url template>`, `mbtiles:<path>` or `pmtiles:<path>`,
+/// and `--subdomains a,b,c`, `--max-zoom <zoom>` and `--attribution <text>` can be used to describe a custom server.
+/// `--cache-size <megabytes>` sets how much disk the tiles from a server can take up, and `HTTP_FLAGS` has the
+/// arguments for how we talk to it.
+pub const TILE_SOURCE_FLAGS: &[Flag] = &[
+    Flag::value("--source"),
+    Flag::value("--subdomains"),
+    Flag::value("--max-zoom"),
+    Flag::value("--attribution"),
+    Flag::value("--cache-size"),
+];
+
+/// Works out which tile source to use from the command line arguments.
+pub fn tile_source_from_args(args: &Args) -> Result<Arc<dyn TileSource>, String> {
+    let spec = args.get("--source").unwrap_or("osm");
+    let max_zoom = args.get("--max-zoom").map(|zoom| parse_zoom(zoom, "--max-zoom")).transpose()?;
+    let attribution = args.get("--attribution").map(|attribution| attribution.to_string());
+
+    let source: Arc<dyn TileSource> = match spec.split_once(':') {
+        Some(("mbtiles", path)) => {
+            reject_server_flags(args, "mbtiles")?;
+            Arc::new(MbTilesSource::open(Path::new(path)).map_err(|e| format!("Failed to open {}: {}", path, e))?)
+        }
+        Some(("pmtiles", path)) => {
+            reject_server_flags(args, "pmtiles")?;
+            Arc::new(PmTilesSource::open(Path::new(path)).map_err(|e| format!("Failed to open {}: {}", path, e))?)
+        }
+        _ => Arc::new(url_tile_source_from_args(spec, args)?),
+    };
+
+    if max_zoom.is_none() && attribution.is_none() {
+        return Ok(source);
+    }
+    Ok(Arc::new(OverriddenSource { source, max_zoom, attribution }))
+}
+
+/// Files don't have subdomains or a disk cache and aren't fetched over http, so those flags would do nothing.
+fn reject_server_flags(args: &Args, kind: &str) -> Result<(), String> {
+    let mut server_flags = ["--subdomains", "--cache-size"].into_iter().chain(HTTP_FLAGS.iter().map(|flag| flag.name));
+    match server_flags.find(|flag| args.has(flag)) {
+        Some(flag) => Err(format!("{} only works with a tile server, not {}", flag, kind)),
+        None => Ok(()),
+    }
+}
+
+fn url_tile_source_from_args(spec: &str, args: &Args) -> Result<UrlTileSource, String> {
+    let mut source = match spec.split_once(':') {
+        Some(("raster", template)) => UrlTileSource::new("custom_raster".to_string(), TileKind::Raster, template.to_string()),
+        Some(("vector", template)) => UrlTileSource::new("custom_vector".to_string(), TileKind::Vector, template.to_string()),
+        _ if spec == "osm" => UrlTileSource::openstreetmap(),
+        _ if spec == "openfreemap" => UrlTileSource::openfreemap(),
+        _ => return Err(format!("Unknown tile source: {}", spec)),
+    };
+    if let Some(subdomains) = args.get("--subdomains") {
+        source.subdomains = subdomains.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+    }
+    // Otherwise every request would go to a url with {s} in it
+    if source.url_template.contains("{s}") && source.subdomains.is_empty() {
+        return Err(format!("{} has {{s}} in it, so it needs --subdomains", source.url_template));
+    }
+    source.http = Arc::new(HttpClient::new(http_config_from_args(args)?));
+    if let Some(cache_size) = args.get("--cache-size") {
+        let cache_size = cache_size.parse::<u64>().ok().and_then(|megabytes| megabytes.checked_mul(1024 * 1024))
+            .ok_or("--cache-size needs a number of megabytes")?;
+        source.cache = Arc::new(TileCache::for_source(&source.name, &source.url_template, cache_size));
+    }
+    Ok(source)
+}
+
+/// A source with `--max-zoom` and `--attribution` laid over the top of it, everything else is passed straight through.
+struct OverriddenSource {
+    source: Arc<dyn TileSource>,
+    max_zoom: Option<u32>,
+    attribution: Option<String>,
+}
+
+impl TileSource for OverriddenSource {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn kind(&self) -> TileKind {
+        self.source.kind()
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom.unwrap_or_else(|| self.source.max_zoom())
+    }
+
+    fn attribution(&self) -> &str {
+        self.attribution.as_deref().unwrap_or_else(|| self.source.attribution())
+    }
+
+    fn is_cached(&self, x: u64, y: u64, zoom: u64) -> bool {
+        self.source.is_cached(x, y, zoom)
+    }
+
+    fn get_tile(&self, x: u64, y: u64, zoom: u64) -> Result<Vec<u8>, FetchError> {
+        self.source.get_tile(x, y, zoom)
+    }
+
+    fn get_tile_cancellable(&self, x: u64, y: u64, zoom: u64, cancel: &CancelFlag) -> Result<Vec<u8>, FetchError> {
+        self.source.get_tile_cancellable(x, y, zoom, cancel)
+    }
+}
+
+/// Most tile providers require their attribution to be shown on the map.
+pub fn setup_attribution(mut commands: Commands, tile_source: Res<ActiveTileSource>, asset_server: Res<AssetServer>) {
+    if tile_source.attribution().is_empty() {
+        return;
+    }
+    commands.spawn((
+        Text::new(tile_source.attribution()),
+        TextFont {
+            font: asset_server.load("fonts/BagnardSans.otf"),
+            font_size: 14.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        },
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{http::HttpConfig, mbtiles::MbTilesWriter, test_support::{mock_tile_source, solid_png, test_dir, MockResponse, MockTileServer}};
+
+    #[test]
+    fn fetched_tiles_are_served_from_the_cache() {
+        let server = MockTileServer::start();
+        let png = solid_png(256, [0, 255, 0, 255]);
+        server.route("/3/1/2.png", vec![MockResponse::ok(png.clone()).header("Cache-Control", "max-age=600")]);
+        let source = mock_tile_source(&server, "served-from-cache");
+
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png.clone()));
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png));
+        assert_eq!(server.requests("/3/1/2.png").len(), 1);
+    }
+
+    #[test]
+    fn expired_tiles_are_revalidated_with_their_etag() {
+        let server = MockTileServer::start();
+        let png = solid_png(256, [0, 255, 0, 255]);
+        server.route("/3/1/2.png", vec![
+            MockResponse::ok(png.clone()).header("Cache-Control", "max-age=0").header("ETag", "\"v1\""),
+            MockResponse::status(304).header("Cache-Control", "max-age=600"),
+        ]);
+        let source = mock_tile_source(&server, "revalidated");
+
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png.clone()));
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png.clone()));
+        // Fresh again after the 304
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png));
+        let requests = server.requests("/3/1/2.png");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].headers.get("if-none-match").map(String::as_str), Some("\"v1\""));
+    }
+
+    #[test]
+    fn expired_tile_is_used_when_the_server_is_down() {
+        let server = MockTileServer::start();
+        let png = solid_png(256, [0, 255, 0, 255]);
+        server.route("/3/1/2.png", vec![MockResponse::ok(png.clone()).header("Cache-Control", "max-age=0"), MockResponse::status(503)]);
+        let source = mock_tile_source(&server, "server-down");
+
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png.clone()));
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png));
+    }
+
+    #[test]
+    fn missing_tiles_are_empty() {
+        let server = MockTileServer::start();
+        server.route("/3/1/3.png", vec![MockResponse::status(204)]);
+        let source = mock_tile_source(&server, "missing");
+        assert_eq!(source.get_tile(1, 2, 3), Ok(Vec::new()));
+        assert_eq!(source.get_tile(1, 3, 3), Ok(Vec::new()));
+        // Not worth asking again
+        assert_eq!(server.requests("/3/1/2.png").len(), 1);
+        assert_eq!(server.requests("/3/1/3.png").len(), 1);
+    }
+
+    #[test]
+    fn server_errors_are_still_errors() {
+        let server = MockTileServer::start();
+        server.route("/3/1/2.png", vec![MockResponse::status(403)]);
+        let source = mock_tile_source(&server, "forbidden");
+        assert_eq!(source.get_tile(1, 2, 3), Err(FetchError::Status(403)));
+    }
+
+    #[test]
+    fn busy_server_is_asked_again_after_retry_after() {
+        let server = MockTileServer::start();
+        let png = solid_png(256, [0, 255, 0, 255]);
+        server.route("/3/1/2.png", vec![MockResponse::status(429).header("Retry-After", "0"), MockResponse::ok(png.clone())]);
+        let source = mock_tile_source(&server, "busy");
+
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png));
+        assert_eq!(server.requests("/3/1/2.png").len(), 2);
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        let server = MockTileServer::start();
+        server.route("/3/1/2.png", vec![MockResponse::ok(solid_png(256, [0, 255, 0, 255])).delay(Duration::from_secs(1))]);
+        let source = UrlTileSource {
+            http: Arc::new(HttpClient::new(HttpConfig { timeout: Duration::from_millis(100), max_retries: 0, ..Default::default() })),
+            ..mock_tile_source(&server, "slow")
+        };
+        assert!(matches!(source.get_tile(1, 2, 3), Err(FetchError::Transport(_))));
+    }
+
+    #[test]
+    fn truncated_tile_is_an_error_and_isnt_cached() {
+        let server = MockTileServer::start();
+        let png = solid_png(256, [0, 255, 0, 255]);
+        server.route("/3/1/2.png", vec![MockResponse::ok(png.clone()).truncated(), MockResponse::ok(png.clone())]);
+        let source = mock_tile_source(&server, "truncated");
+
+        assert!(matches!(source.get_tile(1, 2, 3), Err(FetchError::Transport(_))));
+        assert_eq!(source.get_tile(1, 2, 3), Ok(png));
+    }
+
+    #[test]
+    fn requests_say_who_we_are() {
+        let server = MockTileServer::start();
+        let source = mock_tile_source(&server, "user-agent");
+        let _ = source.get_tile(1, 2, 3);
+        let user_agent = server.requests("/3/1/2.png")[0].headers.get("user-agent").cloned().unwrap_or_default();
+        assert!(user_agent.starts_with("bevy-ofm-viewer/"));
+    }
+
+    fn args(args: &str) -> Args {
+        Args::parse(&args.split_whitespace().map(|arg| arg.to_string()).collect::<Vec<_>>(), &[TILE_SOURCE_FLAGS, HTTP_FLAGS]).unwrap()
+    }
+
+    #[test]
+    fn cache_size_that_doesnt_fit_is_an_error() {
+        assert!(tile_source_from_args(&args("--cache-size 18446744073709551615")).is_err());
+        assert!(tile_source_from_args(&args("--cache-size lots")).is_err());
+    }
+
+    #[test]
+    fn overrides_apply_to_files_too() {
+        let path = test_dir("overrides").join("overrides.mbtiles");
+        MbTilesWriter::create(&path, "overrides", TileKind::Raster, "From the file").unwrap().put_tile(1, 2, 3, &solid_png(256, [0, 255, 0, 255])).unwrap();
+        let spec = format!("--source mbtiles:{}", path.display());
+
+        let source = tile_source_from_args(&args(&spec)).unwrap();
+        assert_eq!(source.attribution(), "From the file");
+        let source = tile_source_from_args(&args(&format!("{} --max-zoom 3 --attribution Mine", spec))).unwrap();
+        assert_eq!((source.name(), source.max_zoom(), source.attribution()), ("overrides", 3, "Mine"));
+        assert_eq!(source.get_tile(1, 2, 3), Ok(solid_png(256, [0, 255, 0, 255])));
+    }
+
+    #[test]
+    fn max_zoom_has_to_fit_the_tile_numbers() {
+        assert_eq!(tile_source_from_args(&args("--max-zoom 30")).unwrap().max_zoom(), 30);
+        assert_eq!(tile_source_from_args(&args("--max-zoom 31")).err(), Some("--max-zoom needs a zoom from 0 to 30".to_string()));
+    }
+
+    #[test]
+    fn subdomains_are_needed_for_a_template_with_s() {
+        assert!(tile_source_from_args(&args("--source raster:https://{s}.tiles.example/{z}/{x}/{y}.png")).is_err());
+        assert!(tile_source_from_args(&args("--source raster:https://{s}.tiles.example/{z}/{x}/{y}.png --subdomains ,")).is_err());
+        assert!(tile_source_from_args(&args("--source raster:https://{s}.tiles.example/{z}/{x}/{y}.png --subdomains a,b")).is_ok());
+    }
+
+    #[test]
+    fn server_flags_on_a_file_are_an_error() {
+        let path = test_dir("server-flags").join("server-flags.mbtiles");
+        MbTilesWriter::create(&path, "server-flags", TileKind::Raster, "").unwrap();
+        for flag in ["--subdomains a,b", "--cache-size 10", "--timeout 5"] {
+            let error = tile_source_from_args(&args(&format!("--source mbtiles:{} {}", path.display(), flag))).err();
+            assert_eq!(error, Some(format!("{} only works with a tile server, not mbtiles", flag.split_whitespace().next().unwrap())));
+        }
+        assert!(Args::parse(&["--sauce".to_string(), "osm".to_string()], &[TILE_SOURCE_FLAGS]).is_err());
+    }
+}